@@ -12,22 +12,73 @@
 use crate::prelude::*;
 use afbv4::prelude::*;
 use energy::prelude::*;
+use std::time::Duration;
 use typesv4::prelude::*;
 
 pub struct BindingCfg {
     pub linky_api: &'static str,
-    pub meter_api: &'static str,
     pub energy_mgr: &'static ManagerHandle,
-    pub tic: u32,
+    pub pv_period: u32,
+    pub read_timeout: u32,
+    pub contract_event: &'static AfbEvent,
+    pub state_event: &'static AfbEvent,
+}
+
+// read linky subscribed power and tension, return the change when contract was updated
+fn contract_check(
+    api: &AfbApi,
+    linky_api: &str,
+    energy_mgr: &ManagerHandle,
+) -> Result<Option<ContractChangeSet>, AfbError> {
+    let response = subcall_sync(energy_mgr, api, linky_api, "PCOUP", EnergyAction::READ)?;
+    let max_power = response.get::<JsoncObj>(0)?.index::<i32>(0)?;
+    let response = subcall_sync(energy_mgr, api, linky_api, "URMS", EnergyAction::READ)?;
+    let cur_tension = response.get::<JsoncObj>(0)?.index::<i32>(0)?;
+    energy_mgr.update_contract(max_power, cur_tension)
+}
+
+struct ContractTimerCtx {
+    api: &'static AfbApi,
+    linky_api: &'static str,
+    energy_mgr: &'static ManagerHandle,
+    evt: &'static AfbEvent,
+}
+
+// customer may upgrade contract without binder restart
+fn contract_timer_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<ContractTimerCtx>()?;
+    match contract_check(ctx.api, ctx.linky_api, ctx.energy_mgr) {
+        Ok(Some(change)) => {
+            ctx.evt.push(change);
+        }
+        Ok(None) => {}
+        Err(error) => afb_log_msg!(Warning, None, "{}", error),
+    }
+    Ok(())
 }
 
 struct ApiUserData {
     linky_api: &'static str,
+    linky_injection: bool,
+    tempo: bool,
+    phase: i32,
+    metrics: Option<&'static str>,
+    modbus_server: Option<&'static str>,
     energy_mgr: &'static ManagerHandle,
 }
 impl AfbApiControls for ApiUserData {
     // the API is created and ready. At this level user may subcall api(s) declare as dependencies
     fn start(&mut self, api: &AfbApi) -> Result<(), AfbError> {
+        // detect meter replacement, not every meter exposes its serial number
+        let meter = self.energy_mgr.get_meter()?;
+        match subcall_sync(self.energy_mgr, api, &meter.api, &meter.get_verb("SERIAL-NUMBER"), EnergyAction::READ) {
+            Ok(response) => {
+                let serial = response.get::<JsoncObj>(0)?.to_string();
+                self.energy_mgr.set_meter_serial(serial.trim_matches('"'))?;
+            }
+            Err(error) => afb_log_msg!(Warning, api, "fail to read meter serial number error:{}", error),
+        }
+
         // if linky_api defined subscribe to over current notification
         if self.linky_api != "" {
             afb_log_msg!(
@@ -37,15 +88,48 @@ impl AfbApiControls for ApiUserData {
                 self.linky_api
             );
 
-            let response = AfbSubCall::call_sync(api, self.linky_api, "PCOUP", EnergyAction::READ)?;
-            let max_power = response.get::<JsoncObj>(0)?.index::<i32>(0)?;
-            let response = AfbSubCall::call_sync(api, self.linky_api, "URMS", EnergyAction::READ)?;
-            let cur_tension = response.get::<JsoncObj>(0)?.index::<i32>(0)?;
-            self.energy_mgr
-                .set_power_subscription(max_power * 1000, cur_tension)?;
+            contract_check(api, self.linky_api, self.energy_mgr)?;
 
-            AfbSubCall::call_sync(api, self.linky_api, "ADPS", EnergyAction::SUBSCRIBE)?;
+            subcall_sync(self.energy_mgr, api, self.linky_api, "ADPS", EnergyAction::SUBSCRIBE)?;
+
+            // apparent power feeds available current and phase switching
+            for label in get_sinsts_labels(self.phase) {
+                subcall_sync(self.energy_mgr, api, self.linky_api, label, EnergyAction::SUBSCRIBE)?;
+            }
+
+            // producer installation injection power/energy
+            if self.linky_injection {
+                subcall_sync(self.energy_mgr, api, self.linky_api, "SINSTI", EnergyAction::SUBSCRIBE)?;
+                subcall_sync(self.energy_mgr, api, self.linky_api, "EAIT", EnergyAction::SUBSCRIBE)?;
+            }
+
+            // tempo/ejp day color and current tariff
+            if self.tempo {
+                let response = subcall_sync(self.energy_mgr, api, self.linky_api, "STGE", EnergyAction::READ)?;
+                let stge = get_linky_status(&response.get::<JsoncObj>(0)?)?;
+                let response = subcall_sync(self.energy_mgr, api, self.linky_api, "LTARF", EnergyAction::READ)?;
+                let ltarf = response.get::<JsoncObj>(0)?.index::<String>(0)?;
+                self.energy_mgr.update_tempo(Some(stge), Some(&ltarf))?;
+
+                subcall_sync(self.energy_mgr, api, self.linky_api, "STGE", EnergyAction::SUBSCRIBE)?;
+                subcall_sync(self.energy_mgr, api, self.linky_api, "LTARF", EnergyAction::SUBSCRIBE)?;
+            }
+        }
+
+        // optional OpenMetrics exporter
+        if let Some(uri) = self.metrics {
+            afb_log_msg!(Notice, api, "start metrics exporter uri:{}", uri);
+            metrics_start(uri, self.energy_mgr)?;
         }
+
+        // optional modbus-tcp server for building controllers
+        if let Some(addr) = self.modbus_server {
+            afb_log_msg!(Notice, api, "start modbus server addr:{}", addr);
+            modbus_start(addr, self.energy_mgr)?;
+        }
+
+        // optional mqtt bridge
+        mqtt_start(self.energy_mgr)?;
         Ok(())
     }
 
@@ -63,38 +147,168 @@ pub fn binding_init(rootv4: AfbApiV4, jconf: JsoncObj) -> Result<&'static AfbApi
     // add binding custom converter
     engy_registers()?;
 
-    let uid = jconf.default::<&'static str>("uid", "energy-mgr")?;
-    let api = jconf.default::<&'static str>("api", uid)?;
-    let info = jconf.default::<&'static str>("info", "")?;
+    // parse and check config before anything gets created
+    let jconfig = EnergyBindingConfig::parse(&jconf)?;
+    jconfig.validate()?;
+    for key in jconfig.get_unknown_keys() {
+        afb_log_msg!(Warning, rootv4, "unknown config key:{} ignored", key);
+    }
+
+    let uid = to_static_str(jconfig.uid.clone());
+    let api = match &jconfig.api {
+        Some(value) => to_static_str(value.clone()),
+        None => uid,
+    };
+    let info = to_static_str(jconfig.info.clone());
 
-    let imax = jconf.default::<i32>("imax", 32)?;
-    let pmax = jconf.default::<i32>("pmax", 22)?;
-    let umax = jconf.default::<i32>("umax", 245)?;
-    let phase = jconf.default::<i32>("phase", 3)?;
+    let imax = jconfig.imax;
+    let pmax = jconfig.pmax;
+    let umax = jconfig.umax;
+    let phase = jconfig.phase;
 
-    let linky_api = jconf.default::<&'static str>("linky_api", "")?;
-    let meter_api = jconf.default::<&'static str>("meter_api", "modbus")?;
+    let linky_api = to_static_str(jconfig.linky_api.clone());
+    let linky_injection = jconfig.linky_injection;
+    let contract_period = jconfig.contract_period;
+    let meter_api = to_static_str(jconfig.meter_api.clone());
+    let metrics = jconfig.metrics.clone().map(to_static_str);
+    let modbus_server = jconfig.modbus_server.clone().map(to_static_str);
 
     // Create the energy manager now in order to share session authorization it with verbs/events
     let energy_event = AfbEvent::new("over-limit");
+    let tic = jconfig.tic.unwrap_or_default();
     let energy_mgr = ManagerHandle::new(energy_event, imax, pmax, umax, phase);
-    let tic = jconf.get::<u32>("tic")?;
+    energy_mgr.set_available_margin(jconfig.margin);
+    energy_mgr.set_settings(tic, jconfig.get_meter());
+
+    let contract_event = AfbEvent::new("contract-changed");
+    let state_event = AfbEvent::new("state");
+    energy_mgr.set_state_event(state_event);
+
+    // optional automatic 1/3 phase switching
+    let phase_event = AfbEvent::new("phase-switch");
+    if let Some(jswitch) = &jconfig.phase_switch {
+        let config = PhaseSwitchConfig {
+            min_3ph: jswitch.min_3ph,
+            hysteresis: jswitch.hysteresis,
+            debounce: Duration::from_secs(jswitch.debounce),
+            dwell: Duration::from_secs(jswitch.dwell),
+        };
+        energy_mgr.set_phase_switch(config, phase_event);
+    }
+    // optional mqtt bridge
+    if let Some(jmqtt) = &jconfig.mqtt {
+        let mut topics = Vec::new();
+        for (event, topic) in &jmqtt.topics {
+            if !MQTT_EVENTS.contains(&event.as_str()) {
+                afb_log_msg!(Warning, rootv4, "unknown config key:mqtt.topics.{} ignored", event);
+                continue;
+            }
+            topics.push((event.clone(), topic.clone()));
+        }
+        let config = MqttConfig {
+            uri: jmqtt.uri.clone(),
+            client_id: jmqtt.client_id.clone().unwrap_or(uid.to_string()),
+            username: jmqtt.username.clone(),
+            password: jmqtt.password.clone(),
+            keepalive: jmqtt.keepalive as u16,
+            retain: jmqtt.retain,
+            command: jmqtt.command.clone().unwrap_or_default(),
+            prefix: jmqtt.prefix.clone(),
+            topics,
+        };
+        energy_mgr.set_mqtt_bridge(MqttBridge::new(config));
+    }
+
+    // external demand-response heartbeat and failsafe
+    if let Some(jlimit) = &jconfig.limit {
+        energy_mgr.set_demand_config(DemandConfig {
+            heartbeat: Duration::from_secs(jlimit.heartbeat),
+            failsafe_power: jlimit.failsafe_power,
+            failsafe_current: jlimit.failsafe_current,
+        });
+    }
+
+    // optional daily tariff windows for ISO-15118 schedule
+    if !jconfig.tariffs.is_empty() {
+        let tariffs = jconfig
+            .tariffs
+            .iter()
+            .map(|jtariff| TariffWindow {
+                start: jtariff.start,
+                duration: jtariff.duration,
+                pmax: jtariff.pmax,
+            })
+            .collect();
+        energy_mgr.set_tariffs(tariffs);
+    }
+
+    // optional SunSpec inverter for pv production
+    let mut pv_period = 0;
+    if let Some(jsunspec) = &jconfig.sunspec {
+        pv_period = jsunspec.period;
+        energy_mgr.set_sunspec_reader(SunSpecReader::new(SunSpecConfig {
+            uri: jsunspec.uri.clone(),
+            unit: jsunspec.unit as u8,
+            timeout: Duration::from_millis(jsunspec.timeout),
+        }));
+    }
+
+    // optional tempo/ejp policy, pmax in W during peak hours per day color
+    if let Some(jtempo) = &jconfig.tempo {
+        energy_mgr.set_tempo_policy(TempoPolicy {
+            blue: jtempo.blue,
+            white: jtempo.white,
+            red: jtempo.red,
+            mobile_peak: jtempo.mobile_peak,
+        });
+    }
+
+    // meter energy index wrapping value in kWh
+    energy_mgr.set_energy_rollover(jconfig.energy_rollover);
+
+    // optional energy session persistence
+    if let Some(path) = &jconfig.session_file {
+        energy_mgr.set_session_store(SessionStore::new(path));
+    }
+
+    // change driven state event
+    if let Some(jevent) = &jconfig.state_event {
+        let deltas = StateDeltas {
+            session: jevent.session,
+            current: jevent.current,
+            tension: jevent.tension,
+            power: jevent.power,
+            pv: jevent.pv,
+            injection: jevent.injection,
+        };
+        energy_mgr.set_state_notifier(deltas, Duration::from_secs(jevent.heartbeat));
+    }
+
+    energy_mgr.set_trend_size(jconfig.trend as usize);
 
     // create backend API
     let api = AfbApi::new(api)
         .set_info(info)
         .add_event(energy_event)
         .add_event(energy_event)
+        .add_event(phase_event)
         .set_callback(Box::new(ApiUserData {
             linky_api,
+            linky_injection,
+            tempo: jconfig.tempo.is_some(),
+            phase,
+            metrics,
+            modbus_server,
             energy_mgr,
         }));
 
     let config = BindingCfg {
-        meter_api,
         linky_api,
         energy_mgr,
-        tic,
+        pv_period,
+        read_timeout: jconfig.read_timeout,
+        contract_event,
+        state_event,
     };
 
     // register api dependencies
@@ -102,13 +316,29 @@ pub fn binding_init(rootv4: AfbApiV4, jconf: JsoncObj) -> Result<&'static AfbApi
     if linky_api != "" {
         api.require_api(linky_api);
     }
-    if let Ok(value) = jconf.get::<String>("permission") {
-        api.set_permission(AfbPermission::new(to_static_str(value)));
+    if let Some(value) = &jconfig.permission {
+        api.set_permission(AfbPermission::new(to_static_str(value.clone())));
     };
 
     register_verbs(api, config)?;
+    let api = api.finalize()?;
+
+    // periodic linky contract re-read
+    if linky_api != "" && contract_period > 0 {
+        AfbTimer::new("contract-timer")
+            .set_period(contract_period * 1000)
+            .set_decount(0)
+            .set_callback(contract_timer_cb)
+            .set_context(ContractTimerCtx {
+                api,
+                linky_api,
+                energy_mgr,
+                evt: contract_event,
+            })
+            .start()?;
+    }
 
-    Ok(api.finalize()?)
+    Ok(api)
 }
 
 // register binding within libafb
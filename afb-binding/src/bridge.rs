@@ -0,0 +1,86 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use afbv4::prelude::*;
+use energy::prelude::*;
+
+// events published by the bridge, topic default is prefix/event
+pub(crate) const MQTT_EVENTS: [&str; 12] = [
+    "state",
+    "power",
+    "current",
+    "tension",
+    "energy",
+    "iover",
+    "iavail",
+    "pv",
+    "injection",
+    "over-limit",
+    "config",
+    "contract-changed",
+];
+
+struct MqttEvtCtx {
+    energy_mgr: &'static ManagerHandle,
+}
+
+// incoming broker data (config commands, ping response, ...)
+fn mqtt_read_cb(_evtfd: &AfbEvtFd, _revent: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<MqttEvtCtx>()?;
+    ctx.energy_mgr.mqtt_process()
+}
+
+fn mqtt_connect(energy_mgr: &'static ManagerHandle) -> Result<(), AfbError> {
+    if let Some(fd) = energy_mgr.mqtt_keepalive()? {
+        AfbEvtFd::new("mqtt-bridge")
+            .set_fd(fd)
+            .set_events(AfbEvtFdPoll::IN)
+            .set_autounref(true)
+            .set_callback(mqtt_read_cb)
+            .set_context(MqttEvtCtx { energy_mgr })
+            .start()?;
+    }
+    Ok(())
+}
+
+struct MqttTimerCtx {
+    energy_mgr: &'static ManagerHandle,
+}
+
+// ping broker, (re)connect in background when connection was lost
+fn mqtt_timer_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<MqttTimerCtx>()?;
+    if let Err(error) = mqtt_connect(ctx.energy_mgr) {
+        afb_log_msg!(Warning, None, "{}", error);
+    }
+    Ok(())
+}
+
+pub(crate) fn mqtt_start(energy_mgr: &'static ManagerHandle) -> Result<(), AfbError> {
+    let keepalive = match energy_mgr.get_mqtt_keepalive() {
+        Some(value) => value as u32,
+        None => return Ok(()),
+    };
+
+    // connection runs in a worker thread, next timer tick registers its socket
+    if let Err(error) = mqtt_connect(energy_mgr) {
+        afb_log_msg!(Warning, None, "{}", error);
+    }
+
+    AfbTimer::new("mqtt-keepalive")
+        .set_period(keepalive * 1000 / 2)
+        .set_decount(0)
+        .set_callback(mqtt_timer_cb)
+        .set_context(MqttTimerCtx { energy_mgr })
+        .start()?;
+    Ok(())
+}
@@ -0,0 +1,530 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use afbv4::prelude::*;
+use energy::prelude::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+type UnknownKeys = BTreeMap<String, serde_json::Value>;
+
+// periods in s, also keeps timer period in ms within u32
+const PERIOD_MAX: u64 = 86400;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PhaseSwitchJson {
+    pub min_3ph: i32,
+    pub hysteresis: i32,
+    pub debounce: u64,
+    pub dwell: u64,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
+}
+
+impl Default for PhaseSwitchJson {
+    fn default() -> Self {
+        PhaseSwitchJson {
+            min_3ph: 4140,
+            hysteresis: 200,
+            debounce: 60,
+            dwell: 300,
+            unknown: UnknownKeys::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MqttJson {
+    pub uri: String,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "mqtt_keepalive")]
+    pub keepalive: u32,
+    #[serde(default = "mqtt_retain")]
+    pub retain: bool,
+    #[serde(default = "mqtt_prefix")]
+    pub prefix: String,
+    pub command: Option<String>,
+    #[serde(default)]
+    pub topics: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
+}
+
+fn mqtt_keepalive() -> u32 {
+    30
+}
+fn mqtt_retain() -> bool {
+    true
+}
+fn mqtt_prefix() -> String {
+    "tux-evse/energy".to_string()
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct LimitJson {
+    pub heartbeat: u64,
+    pub failsafe_power: i32,
+    pub failsafe_current: i32,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TariffJson {
+    pub start: u32,
+    pub duration: u32,
+    pub pmax: i32,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SunSpecJson {
+    pub uri: String,
+    #[serde(default = "sunspec_unit")]
+    pub unit: u32,
+    #[serde(default = "sunspec_period")]
+    pub period: u32,
+    #[serde(default = "sunspec_timeout")]
+    pub timeout: u64,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
+}
+
+fn sunspec_unit() -> u32 {
+    1
+}
+fn sunspec_period() -> u32 {
+    10000
+}
+fn sunspec_timeout() -> u64 {
+    1000
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TempoJson {
+    pub blue: Option<i32>,
+    pub white: Option<i32>,
+    pub red: Option<i32>,
+    pub mobile_peak: Option<i32>,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
+}
+
+// state event deltas in state units (0 = any change), heartbeat in s (0 = disable)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StateEventJson {
+    pub heartbeat: u64,
+    pub session: i32,
+    pub current: i32,
+    pub tension: i32,
+    pub power: i32,
+    pub pv: i32,
+    pub injection: i32,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
+}
+
+impl Default for StateEventJson {
+    fn default() -> Self {
+        StateEventJson {
+            heartbeat: 60,
+            session: 0,
+            current: 0,
+            tension: 0,
+            power: 0,
+            pv: 0,
+            injection: 0,
+            unknown: UnknownKeys::new(),
+        }
+    }
+}
+
+// binding json config, every key has a default except 'tic' which is checked by validate
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EnergyBindingConfig {
+    pub uid: String,
+    pub api: Option<String>,
+    pub info: String,
+    pub path: Option<String>,
+    pub permission: Option<String>,
+    pub imax: i32,
+    pub pmax: i32,
+    pub umax: i32,
+    pub phase: i32,
+    pub margin: i32,
+    pub tic: Option<u32>,
+    pub linky_api: String,
+    pub linky_injection: bool,
+    pub contract_period: u32,
+    pub meter_api: String,
+    pub meter_prefix: String,
+    pub meter_labels: BTreeMap<String, String>,
+    pub read_timeout: u32,
+    pub metrics: Option<String>,
+    pub modbus_server: Option<String>,
+    pub phase_switch: Option<PhaseSwitchJson>,
+    pub mqtt: Option<MqttJson>,
+    pub limit: Option<LimitJson>,
+    pub tariffs: Vec<TariffJson>,
+    pub sunspec: Option<SunSpecJson>,
+    pub tempo: Option<TempoJson>,
+    pub energy_rollover: i64,
+    pub session_file: Option<String>,
+    pub state_event: Option<StateEventJson>,
+    pub trend: u32,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
+}
+
+impl Default for EnergyBindingConfig {
+    fn default() -> Self {
+        EnergyBindingConfig {
+            uid: "energy-mgr".to_string(),
+            api: None,
+            info: String::new(),
+            path: None,
+            permission: None,
+            imax: 32,
+            pmax: 22,
+            umax: 245,
+            phase: 3,
+            margin: 20,
+            tic: None,
+            linky_api: String::new(),
+            linky_injection: false,
+            contract_period: 3600,
+            meter_api: "modbus".to_string(),
+            meter_prefix: "SDM72D".to_string(),
+            meter_labels: BTreeMap::new(),
+            read_timeout: 2000,
+            metrics: None,
+            modbus_server: None,
+            phase_switch: None,
+            mqtt: None,
+            limit: None,
+            tariffs: Vec::new(),
+            sunspec: None,
+            tempo: None,
+            energy_rollover: 0,
+            session_file: None,
+            state_event: None,
+            trend: 0,
+            unknown: UnknownKeys::new(),
+        }
+    }
+}
+
+fn check_option(key: &str, value: Option<i32>) -> Result<(), AfbError> {
+    match value {
+        Some(value) => check_range(key, value, &(0..=i32::MAX)),
+        None => Ok(()),
+    }
+}
+
+impl EnergyBindingConfig {
+    // serde errors do not tell which key failed, retry keys one by one to name it
+    pub fn parse(jconf: &JsoncObj) -> Result<Self, AfbError> {
+        let jvalue = match serde_json::from_str::<serde_json::Value>(&jconf.to_string()) {
+            Ok(value) => value,
+            Err(error) => return afb_error!("energy-config-parse", "invalid json config error:{}", error),
+        };
+        let error = match serde_json::from_value::<EnergyBindingConfig>(jvalue.clone()) {
+            Ok(config) => return Ok(config),
+            Err(error) => error,
+        };
+
+        if let serde_json::Value::Object(map) = jvalue {
+            for (key, value) in map {
+                let mut single = serde_json::Map::new();
+                single.insert(key.clone(), value);
+                if let Err(error) = serde_json::from_value::<EnergyBindingConfig>(serde_json::Value::Object(single)) {
+                    return afb_error!("energy-config-parse", "invalid config key:{} error:{}", key, error);
+                }
+            }
+        }
+        afb_error!("energy-config-parse", "invalid config error:{}", error)
+    }
+
+    pub fn get_meter(&self) -> MeterBinding {
+        MeterBinding {
+            api: self.meter_api.clone(),
+            prefix: self.meter_prefix.clone(),
+            labels: self.meter_labels.clone(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), AfbError> {
+        check_range("imax", self.imax, &IMAX_RANGE)?;
+        check_range("pmax", self.pmax, &PMAX_RANGE)?;
+        check_range("umax", self.umax, &UMAX_RANGE)?;
+        check_phase("phase", self.phase)?;
+        check_range("margin", self.margin, &(0..=90))?;
+        match self.tic {
+            Some(tic) => check_range("tic", tic, &TIC_RANGE)?,
+            None => return afb_error!("energy-config-check", "missing config key:tic"),
+        }
+        check_range("read_timeout", self.read_timeout, &(100..=60000))?;
+        self.get_meter().check()?;
+        check_range("trend", self.trend, &(0..=TREND_MAX))?;
+        check_range("energy_rollover", self.energy_rollover, &(0..=i64::MAX / 1000))?;
+        check_range("contract_period", self.contract_period, &(0..=PERIOD_MAX as u32))?;
+
+        if let Some(switch) = &self.phase_switch {
+            // switching to 3 phases is meaningless on a single phase installation
+            if self.phase != 3 {
+                return afb_error!(
+                    "energy-config-check",
+                    "invalid config key:phase_switch requires phase:3 (phase:{})",
+                    self.phase
+                );
+            }
+            check_range("phase_switch.min_3ph", switch.min_3ph, &(1..=i32::MAX))?;
+            check_range("phase_switch.hysteresis", switch.hysteresis, &(0..=switch.min_3ph))?;
+            check_range("phase_switch.debounce", switch.debounce, &(0..=PERIOD_MAX))?;
+            check_range("phase_switch.dwell", switch.dwell, &(0..=PERIOD_MAX))?;
+        }
+        if let Some(event) = &self.state_event {
+            check_range("state_event.heartbeat", event.heartbeat, &(0..=PERIOD_MAX))?;
+            check_range("state_event.session", event.session, &(0..=i32::MAX))?;
+            check_range("state_event.current", event.current, &(0..=i32::MAX))?;
+            check_range("state_event.tension", event.tension, &(0..=i32::MAX))?;
+            check_range("state_event.power", event.power, &(0..=i32::MAX))?;
+            check_range("state_event.pv", event.pv, &(0..=i32::MAX))?;
+            check_range("state_event.injection", event.injection, &(0..=i32::MAX))?;
+        }
+        if let Some(mqtt) = &self.mqtt {
+            check_range("mqtt.keepalive", mqtt.keepalive, &(2..=u16::MAX as u32))?;
+        }
+        if let Some(limit) = &self.limit {
+            check_range("limit.heartbeat", limit.heartbeat, &(0..=PERIOD_MAX))?;
+            check_range("limit.failsafe_power", limit.failsafe_power, &(0..=i32::MAX))?;
+            check_range("limit.failsafe_current", limit.failsafe_current, &(0..=i32::MAX))?;
+            // a silent source should never release its cap
+            if limit.heartbeat > 0 && limit.failsafe_power == 0 && limit.failsafe_current == 0 {
+                return afb_error!(
+                    "energy-config-check",
+                    "invalid config key:limit.heartbeat value:{} requires non zero failsafe_power or failsafe_current",
+                    limit.heartbeat
+                );
+            }
+        }
+        for tariff in &self.tariffs {
+            check_range("tariffs.start", tariff.start, &(0..=86399))?;
+            check_range("tariffs.duration", tariff.duration, &(1..=86400))?;
+            check_range("tariffs.pmax", tariff.pmax, &(0..=i32::MAX))?;
+        }
+        if let Some(sunspec) = &self.sunspec {
+            check_range("sunspec.unit", sunspec.unit, &(0..=255))?;
+            check_range("sunspec.period", sunspec.period, &(1000..=3600000))?;
+            check_range("sunspec.timeout", sunspec.timeout, &(1..=60000))?;
+        }
+        if let Some(tempo) = &self.tempo {
+            check_option("tempo.blue", tempo.blue)?;
+            check_option("tempo.white", tempo.white)?;
+            check_option("tempo.red", tempo.red)?;
+            check_option("tempo.mobile_peak", tempo.mobile_peak)?;
+        }
+        Ok(())
+    }
+
+    // keys present in config but not used by the binding (including nested ones)
+    pub fn get_unknown_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.unknown.keys().cloned().collect();
+        let mut nested = |prefix: &str, unknown: &UnknownKeys| {
+            keys.extend(unknown.keys().map(|key| format!("{}.{}", prefix, key)));
+        };
+        if let Some(value) = &self.phase_switch {
+            nested("phase_switch", &value.unknown);
+        }
+        if let Some(value) = &self.state_event {
+            nested("state_event", &value.unknown);
+        }
+        if let Some(value) = &self.mqtt {
+            nested("mqtt", &value.unknown);
+        }
+        if let Some(value) = &self.limit {
+            nested("limit", &value.unknown);
+        }
+        for value in &self.tariffs {
+            nested("tariffs", &value.unknown);
+        }
+        if let Some(value) = &self.sunspec {
+            nested("sunspec", &value.unknown);
+        }
+        if let Some(value) = &self.tempo {
+            nested("tempo", &value.unknown);
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<EnergyBindingConfig, AfbError> {
+        EnergyBindingConfig::parse(&JsoncObj::parse(json)?)
+    }
+
+    // minimal valid config with one key replaced or added
+    fn with_key(key: &str, value: &str) -> Result<EnergyBindingConfig, AfbError> {
+        match key {
+            "tic" => parse(&format!("{{\"tic\":{}}}", value)),
+            _ => parse(&format!("{{\"tic\":1000, \"{}\":{}}}", key, value)),
+        }
+    }
+
+    #[test]
+    fn parse_default() {
+        let config = parse("{\"tic\":1000}").unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!((config.imax, config.pmax, config.phase), (32, 22, 3));
+        assert_eq!(config.contract_period, 3600);
+        assert!(config.get_unknown_keys().is_empty());
+    }
+
+    #[test]
+    fn parse_sample() {
+        let config = parse(
+            r#"{"tic":1000, "imax":16, "mqtt":{"uri":"localhost:1883"}, "limit":{"heartbeat":60, "failsafe_power":1400},
+            "tariffs":[{"start":0, "duration":3600, "pmax":3000}], "state_event":{"power":500}}"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let mqtt = config.mqtt.unwrap();
+        assert_eq!((mqtt.keepalive, mqtt.retain, mqtt.command), (30, true, None));
+        assert_eq!(config.state_event.unwrap().heartbeat, 60);
+    }
+
+    #[test]
+    fn parse_error_names_key() {
+        for (json, key) in [
+            ("{\"tic\":1000, \"imax\":\"16\"}", "key:imax "),
+            ("{\"tic\":-1}", "key:tic "),
+            ("{\"tic\":1000, \"mqtt\":{\"keepalive\":30}}", "key:mqtt "),
+            ("{\"tic\":1000, \"tariffs\":{\"start\":0}}", "key:tariffs "),
+        ] {
+            let error = parse(json).err().unwrap_or_else(|| panic!("accepted:{}", json));
+            assert!(error.get_info().contains(key), "{} -> {}", json, error.get_info());
+        }
+        assert!(parse("[1,2]").is_err());
+    }
+
+    #[test]
+    fn validate_rules() {
+        let rules = [
+            ("imax", "0"),
+            ("imax", "101"),
+            ("pmax", "0"),
+            ("umax", "99"),
+            ("phase", "2"),
+            ("margin", "91"),
+            ("tic", "99"),
+            ("read_timeout", "99"),
+            ("trend", "86401"),
+            ("energy_rollover", "-1"),
+            ("contract_period", "86401"),
+            ("phase_switch", "{\"min_3ph\":0}"),
+            ("phase_switch", "{\"min_3ph\":1000, \"hysteresis\":1001}"),
+            ("phase_switch", "{\"debounce\":86401}"),
+            ("phase_switch", "{\"dwell\":86401}"),
+            ("state_event", "{\"heartbeat\":86401}"),
+            ("state_event", "{\"session\":-1}"),
+            ("state_event", "{\"current\":-1}"),
+            ("state_event", "{\"tension\":-1}"),
+            ("state_event", "{\"power\":-1}"),
+            ("state_event", "{\"pv\":-1}"),
+            ("state_event", "{\"injection\":-1}"),
+            ("mqtt", "{\"uri\":\"localhost:1883\", \"keepalive\":1}"),
+            ("limit", "{\"heartbeat\":86401, \"failsafe_power\":1400}"),
+            ("limit", "{\"failsafe_power\":-1}"),
+            ("limit", "{\"failsafe_current\":-1}"),
+            ("limit", "{\"heartbeat\":60}"),
+            ("tariffs", "[{\"start\":86400, \"duration\":60, \"pmax\":0}]"),
+            ("tariffs", "[{\"start\":0, \"duration\":0, \"pmax\":0}]"),
+            ("tariffs", "[{\"start\":0, \"duration\":60, \"pmax\":-1}]"),
+            ("sunspec", "{\"uri\":\"localhost:502\", \"unit\":256}"),
+            ("sunspec", "{\"uri\":\"localhost:502\", \"period\":999}"),
+            ("sunspec", "{\"uri\":\"localhost:502\", \"timeout\":0}"),
+            ("tempo", "{\"blue\":-1}"),
+            ("tempo", "{\"white\":-1}"),
+            ("tempo", "{\"red\":-1}"),
+            ("tempo", "{\"mobile_peak\":-1}"),
+        ];
+        for (key, value) in rules {
+            let config = with_key(key, value).unwrap_or_else(|error| panic!("{}:{} parse {}", key, value, error));
+            let error = match config.validate() {
+                Ok(()) => panic!("{}:{} accepted", key, value),
+                Err(error) => error.get_info(),
+            };
+            // error names the faulty key, nested ones with their parent
+            assert!(error.contains(&format!("key:{}", key)), "{}:{} -> {}", key, value, error);
+        }
+    }
+
+    #[test]
+    fn validate_phase_switch() {
+        let config = parse("{\"tic\":1000, \"phase_switch\":{}}").unwrap();
+        assert!(config.validate().is_ok());
+        let config = parse("{\"tic\":1000, \"phase\":1, \"phase_switch\":{}}").unwrap();
+        let error = config.validate().err().unwrap();
+        assert!(error.get_info().contains("key:phase_switch"), "{}", error.get_info());
+    }
+
+    #[test]
+    fn validate_missing_tic() {
+        let error = parse("{}").unwrap().validate().err().unwrap();
+        assert!(error.get_info().contains("missing config key:tic"));
+    }
+
+    #[test]
+    fn validate_bounds() {
+        for (key, value) in [("contract_period", "0"), ("contract_period", "86400"), ("trend", "86400")] {
+            assert!(with_key(key, value).unwrap().validate().is_ok(), "{}:{}", key, value);
+        }
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let config = parse(
+            r#"{"tic":1000, "tick":1, "mqtt":{"uri":"localhost:1883", "qos":1},
+            "limit":{"heartbeat":0, "failsafe":1}, "tariffs":[{"start":0, "duration":60, "pmax":0, "end":60}],
+            "phase_switch":{"min3ph":4000}, "state_event":{"delta":1}, "sunspec":{"uri":"localhost:502", "slave":1},
+            "tempo":{"green":0}}"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let mut keys = config.get_unknown_keys();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                "limit.failsafe",
+                "mqtt.qos",
+                "phase_switch.min3ph",
+                "state_event.delta",
+                "sunspec.slave",
+                "tariffs.end",
+                "tempo.green",
+                "tick",
+            ]
+        );
+    }
+}
@@ -0,0 +1,214 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use afbv4::prelude::*;
+use energy::prelude::*;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Mutex;
+
+const METRICS_CONTENT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const METRICS_REQUEST_MAX: usize = 8192;
+
+enum MetricsListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+enum MetricsStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl MetricsStream {
+    fn read(&self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            MetricsStream::Tcp(stream) => (&*stream).read(buffer),
+            MetricsStream::Unix(stream) => (&*stream).read(buffer),
+        }
+    }
+
+    fn write_all(&self, buffer: &[u8]) -> std::io::Result<()> {
+        match self {
+            MetricsStream::Tcp(stream) => (&*stream).write_all(buffer),
+            MetricsStream::Unix(stream) => (&*stream).write_all(buffer),
+        }
+    }
+
+    fn shutdown(&self) {
+        let _ = match self {
+            MetricsStream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            MetricsStream::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+
+    fn get_fd(&self) -> RawFd {
+        match self {
+            MetricsStream::Tcp(stream) => stream.as_raw_fd(),
+            MetricsStream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+struct MetricsCtx {
+    listener: MetricsListener,
+    energy_mgr: &'static ManagerHandle,
+}
+
+struct MetricsClientCtx {
+    stream: MetricsStream,
+    request: Mutex<Vec<u8>>,
+    energy_mgr: &'static ManagerHandle,
+}
+
+// whatever the request is, scrapper only get our metrics page
+fn metrics_reply(stream: &MetricsStream, energy_mgr: &ManagerHandle) -> Result<(), AfbError> {
+    let response = match energy_mgr.get_metrics() {
+        Ok(body) => format!(
+            "HTTP/1.0 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            METRICS_CONTENT,
+            body.len(),
+            body
+        ),
+        Err(error) => format!(
+            "HTTP/1.0 503 Service Unavailable\r\nContent-Type: text/plain\r\n\r\n{}\n",
+            error
+        ),
+    };
+
+    // page fits in socket buffer, a scrapper not reading it is dropped
+    let status = stream.write_all(response.as_bytes());
+    stream.shutdown();
+    if let Err(error) = status {
+        return afb_error!("energy-metrics-reply", "fail to send metrics error:{}", error);
+    }
+    Ok(())
+}
+
+// socket stays non blocking, request is collected until its header end then answered
+fn metrics_client_cb(_evtfd: &AfbEvtFd, _revent: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<MetricsClientCtx>()?;
+    let mut request = lock_shared(&ctx.request);
+
+    let mut data = [0u8; 1024];
+    loop {
+        match ctx.stream.read(&mut data) {
+            Ok(0) => {
+                // peer closed, hangup will release client context
+                ctx.stream.shutdown();
+                return Ok(());
+            }
+            Ok(count) => request.extend_from_slice(&data[0..count]),
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(error) => {
+                ctx.stream.shutdown();
+                return afb_error!("energy-metrics-read", "fail to read client error:{}", error);
+            }
+        }
+    }
+
+    if request.windows(4).any(|value| value == b"\r\n\r\n") || request.len() > METRICS_REQUEST_MAX {
+        request.clear();
+        metrics_reply(&ctx.stream, ctx.energy_mgr)?;
+    }
+    Ok(())
+}
+
+fn metrics_accept_cb(_evtfd: &AfbEvtFd, _revent: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<MetricsCtx>()?;
+
+    // listener is non blocking, each client gets its own fd handler
+    loop {
+        let status = match &ctx.listener {
+            MetricsListener::Tcp(listener) => listener.accept().map(|(stream, _)| MetricsStream::Tcp(stream)),
+            MetricsListener::Unix(listener) => listener.accept().map(|(stream, _)| MetricsStream::Unix(stream)),
+        };
+        let stream = match status {
+            Ok(value) => value,
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(error) => {
+                return afb_error!("energy-metrics-accept", "fail to accept connection error:{}", error)
+            }
+        };
+        let status = match &stream {
+            MetricsStream::Tcp(value) => value.set_nonblocking(true),
+            MetricsStream::Unix(value) => value.set_nonblocking(true),
+        };
+        if let Err(error) = status {
+            afb_log_msg!(Warning, None, "metrics client fail to set non blocking error:{}", error);
+            continue;
+        }
+
+        AfbEvtFd::new("metrics-client")
+            .set_fd(stream.get_fd())
+            .set_events(AfbEvtFdPoll::IN)
+            .set_autounref(true)
+            .set_callback(metrics_client_cb)
+            .set_context(MetricsClientCtx {
+                stream,
+                request: Mutex::new(Vec::new()),
+                energy_mgr: ctx.energy_mgr,
+            })
+            .start()?;
+    }
+    Ok(())
+}
+
+// listen on 'tcp:host:port' or 'unix:/path' and serve OpenMetrics page
+pub(crate) fn metrics_start(uri: &str, energy_mgr: &'static ManagerHandle) -> Result<(), AfbError> {
+    let listener = if let Some(addr) = uri.strip_prefix("tcp:") {
+        match TcpListener::bind(addr) {
+            Ok(value) => MetricsListener::Tcp(value),
+            Err(error) => {
+                return afb_error!("energy-metrics-start", "fail to bind uri:{} error:{}", uri, error)
+            }
+        }
+    } else if let Some(path) = uri.strip_prefix("unix:") {
+        // only remove a stale socket from previous run, never a regular file
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        match UnixListener::bind(path) {
+            Ok(value) => MetricsListener::Unix(value),
+            Err(error) => {
+                return afb_error!("energy-metrics-start", "fail to bind uri:{} error:{}", uri, error)
+            }
+        }
+    } else {
+        return afb_error!("energy-metrics-start", "invalid uri:{} should be tcp:host:port|unix:/path", uri);
+    };
+
+    let (status, fd) = match &listener {
+        MetricsListener::Tcp(value) => (value.set_nonblocking(true), value.as_raw_fd()),
+        MetricsListener::Unix(value) => (value.set_nonblocking(true), value.as_raw_fd()),
+    };
+    if let Err(error) = status {
+        return afb_error!("energy-metrics-start", "fail to set non blocking error:{}", error);
+    }
+
+    AfbEvtFd::new("metrics-listener")
+        .set_fd(fd)
+        .set_events(AfbEvtFdPoll::IN)
+        .set_callback(metrics_accept_cb)
+        .set_context(MetricsCtx {
+            listener,
+            energy_mgr,
+        })
+        .start()?;
+
+    Ok(())
+}
@@ -25,7 +25,23 @@ mod verbs;
 #[path = "binding.rs"]
 mod binding;
 
+#[path = "config.rs"]
+mod config;
+
+#[path = "bridge.rs"]
+mod bridge;
+
+#[path = "exporter.rs"]
+mod exporter;
+
+#[path = "mbserver.rs"]
+mod mbserver;
+
 pub(crate) mod prelude {
     pub(crate) use crate::verbs::*;
     pub(crate) use crate::binding::*;
+    pub(crate) use crate::config::*;
+    pub(crate) use crate::bridge::*;
+    pub(crate) use crate::exporter::*;
+    pub(crate) use crate::mbserver::*;
 }
\ No newline at end of file
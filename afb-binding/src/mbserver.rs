@@ -0,0 +1,176 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use afbv4::prelude::*;
+use energy::prelude::*;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use typesv4::prelude::*;
+
+// register map and setpoint decoding live in energy lib (mbmap.rs)
+struct ModbusServer {
+    energy_mgr: &'static ManagerHandle,
+    setpoint: Mutex<ModbusSetpoint>,
+}
+
+impl ModbusServer {
+    fn get_registers(&self) -> Result<Vec<u16>, AfbError> {
+        let state = self.energy_mgr.clone_state()?;
+        let limits = self.energy_mgr.get_external_limits()?;
+        let current = self
+            .energy_mgr
+            .get_data_set(&MeterTagSet::Current)?
+            .unwrap_or_default();
+
+        let setpoint = lock_shared(&self.setpoint);
+        Ok(modbus_pack_registers(
+            &state,
+            self.energy_mgr.get_phases(),
+            &current,
+            limits.limits.len(),
+            &setpoint,
+        ))
+    }
+
+    fn read(&self, start: u16, count: u16) -> Result<Vec<u16>, u8> {
+        let (start, stop) = (start as usize, start as usize + count as usize);
+        if stop > MODBUS_MAP_COUNT {
+            return Err(MODBUS_ILLEGAL_ADDRESS);
+        }
+        match self.get_registers() {
+            Ok(registers) => Ok(registers[start..stop].to_vec()),
+            Err(error) => {
+                afb_log_msg!(Warning, None, "{}", error);
+                Err(MODBUS_SERVER_FAILURE)
+            }
+        }
+    }
+
+    fn write(&self, start: u16, values: &[u16]) -> Result<(), u8> {
+        let request = match lock_shared(&self.setpoint).write(start, values)? {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+
+        match self.energy_mgr.set_external_limit(&request) {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                afb_log_msg!(Warning, None, "{}", error);
+                Err(MODBUS_SERVER_FAILURE)
+            }
+        }
+    }
+}
+
+struct ModbusClientCtx {
+    stream: TcpStream,
+    buffer: Mutex<Vec<u8>>,
+    server: &'static ModbusServer,
+}
+
+fn modbus_client_cb(_evtfd: &AfbEvtFd, _revent: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<ModbusClientCtx>()?;
+    let mut buffer = lock_shared(&ctx.buffer);
+
+    let mut data = [0u8; 512];
+    loop {
+        match (&ctx.stream).read(&mut data) {
+            Ok(0) => {
+                // peer closed, hangup will release client context
+                let _ = ctx.stream.shutdown(Shutdown::Both);
+                return Ok(());
+            }
+            Ok(count) => buffer.extend_from_slice(&data[0..count]),
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(error) => {
+                let _ = ctx.stream.shutdown(Shutdown::Both);
+                return afb_error!("energy-modbus-read", "fail to read client error:{}", error);
+            }
+        }
+    }
+
+    while let Some(frame) = modbus_parse_frame(&mut buffer) {
+        let response = modbus_serve(
+            &frame.pdu,
+            |_function, start, count| ctx.server.read(start, count),
+            |start, values| ctx.server.write(start, values),
+        );
+        if let Err(error) = (&ctx.stream).write_all(&modbus_build_frame(frame.transaction, frame.unit, &response)) {
+            let _ = ctx.stream.shutdown(Shutdown::Both);
+            return afb_error!("energy-modbus-write", "fail to reply client error:{}", error);
+        }
+    }
+    Ok(())
+}
+
+struct ModbusListenCtx {
+    listener: TcpListener,
+    server: &'static ModbusServer,
+}
+
+fn modbus_accept_cb(_evtfd: &AfbEvtFd, _revent: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+    let ctx = ctx.get_ref::<ModbusListenCtx>()?;
+
+    loop {
+        let stream = match ctx.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(error) => {
+                return afb_error!("energy-modbus-accept", "fail to accept connection error:{}", error)
+            }
+        };
+        if let Err(error) = stream.set_nonblocking(true) {
+            afb_log_msg!(Warning, None, "modbus client fail to set non blocking error:{}", error);
+            continue;
+        }
+
+        AfbEvtFd::new("modbus-client")
+            .set_fd(stream.as_raw_fd())
+            .set_events(AfbEvtFdPoll::IN)
+            .set_autounref(true)
+            .set_callback(modbus_client_cb)
+            .set_context(ModbusClientCtx {
+                stream,
+                buffer: Mutex::new(Vec::new()),
+                server: ctx.server,
+            })
+            .start()?;
+    }
+    Ok(())
+}
+
+// serve energy state as Modbus-TCP holding registers on 'host:port'
+pub(crate) fn modbus_start(addr: &str, energy_mgr: &'static ManagerHandle) -> Result<(), AfbError> {
+    let listener = match TcpListener::bind(addr) {
+        Ok(value) => value,
+        Err(error) => return afb_error!("energy-modbus-start", "fail to bind addr:{} error:{}", addr, error),
+    };
+    if let Err(error) = listener.set_nonblocking(true) {
+        return afb_error!("energy-modbus-start", "fail to set non blocking error:{}", error);
+    }
+
+    let server = Box::leak(Box::new(ModbusServer {
+        energy_mgr,
+        setpoint: Mutex::new(ModbusSetpoint::new()),
+    }));
+
+    AfbEvtFd::new("modbus-listener")
+        .set_fd(listener.as_raw_fd())
+        .set_events(AfbEvtFdPoll::IN)
+        .set_callback(modbus_accept_cb)
+        .set_context(ModbusListenCtx { listener, server })
+        .start()?;
+
+    Ok(())
+}
@@ -13,42 +13,150 @@
  use crate::prelude::*;
  use afbv4::prelude::*;
  use energy::prelude::*;
- use std::cell::RefCell;
- use std::rc::Rc;
+ use std::sync::{Arc, Mutex, MutexGuard};
+ use std::time::{Instant, SystemTime};
  use typesv4::prelude::*;
  
+ // synchronous subcall keeping track of failures per api
+ pub(crate) fn subcall_sync(
+     mgr: &ManagerHandle,
+     api: &AfbApi,
+     apiname: &str,
+     verbname: &str,
+     action: EnergyAction,
+ ) -> Result<AfbRqtData, AfbError> {
+     match AfbSubCall::call_sync(api, apiname, verbname, action) {
+         Ok(response) => {
+             mgr.count_subcall_success(apiname);
+             Ok(response)
+         }
+         Err(error) => {
+             mgr.count_subcall_error(apiname, &error);
+             Err(error)
+         }
+     }
+ }
+ 
+ // event api name, as in 'linky/SINSTS'
+ fn get_event_source(evt: &AfbEventMsg) -> String {
+     let name = evt.get_name();
+     match name.split_once('/') {
+         Some((api, _)) => api.to_string(),
+         None => name.to_string(),
+     }
+ }
+ 
+ // concurrent data_set access waits for the lock, it is counted per source for health report
+ fn lock_data_set<'a>(
+     mgr: &ManagerHandle,
+     source: &str,
+     data_set: &'a Mutex<MeterDataSet>,
+ ) -> MutexGuard<'a, MeterDataSet> {
+     let (guard, contended) = lock_contended(data_set);
+     if contended {
+         mgr.count_conflict(source);
+     }
+     guard
+ }
+ 
+ // actions of verbs describing their data_set
+ const INFO_ACTIONS: [&str; 4] = ["read", "subscribe", "unsubscribe", "info"];
+ 
+ // reply subscribe with event name, last known data_set and its age, clients do not wait next update
+ fn reply_retained(rqt: &AfbRequest, energy_mgr: &ManagerHandle, event: String, tag: &MeterTagSet) -> Result<(), AfbError> {
+     let retained = energy_mgr.get_retained(tag)?;
+     rqt.reply(MeterSubscribeSet { event, retained }, 0);
+     Ok(())
+ }
+ 
+ // unit and scale of values published for a data_set tag
+ fn get_tag_unit(tag: &MeterTagSet) -> (&'static str, i32) {
+     match tag {
+         MeterTagSet::Tension => ("V", 1000),
+         MeterTagSet::Current | MeterTagSet::OverCurrent | MeterTagSet::AvailCurrent => ("A", 1000),
+         MeterTagSet::Power => ("W", 1000),
+         MeterTagSet::Energy => ("Wh", 1),
+         MeterTagSet::Pv => ("W", 1),
+         MeterTagSet::Injection => ("VA", 1000),
+         MeterTagSet::Unset => ("", 1),
+     }
+ }
+ 
+ fn to_strings(values: &[&str]) -> Vec<String> {
+     values.iter().map(|value| value.to_string()).collect()
+ }
+ 
+ // complete verb info with unit, filter variation and last update time of data_set tag
+ fn get_verb_info(energy_mgr: &ManagerHandle, tag: &MeterTagSet, mut info: VerbInfoSet) -> Result<VerbInfoSet, AfbError> {
+     let (unit, scale) = get_tag_unit(tag);
+     info.unit = unit.to_string();
+     info.scale = scale;
+     info.variation = match tag {
+         MeterTagSet::OverCurrent | MeterTagSet::AvailCurrent | MeterTagSet::Pv | MeterTagSet::Unset => 0,
+         _ => energy_mgr.get_variation()?,
+     };
+     info.updated = match energy_mgr.get_retained(tag)? {
+         Some(retained) => match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+             Ok(value) => (value.as_millis() as u64).saturating_sub(retained.age),
+             Err(_) => 0,
+         },
+         None => 0,
+     };
+     Ok(info)
+ }
+ 
  struct TimerCtx {
      mgr: &'static ManagerHandle,
-     evt: &'static AfbEvent,
  }
  
- // send charging state every tic ms. 
+ // state changes are pushed by updates, every tic refresh profile cap and check heartbeat
  fn timer_callback(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
  
      let ctx = ctx.get_ref::<TimerCtx>()?;
-     let state = ctx.mgr.clone_state()?;
-     ctx.evt.push(state);
-     Ok(())
+     ctx.mgr.update_profile_limit()?;
+     ctx.mgr.push_state_event()
+ }
+ 
+ // state timer period follows tic, settings restart it when tic changes
+ struct TicTimer {
+     mgr: &'static ManagerHandle,
+     timer: Mutex<Option<&'static AfbTimer>>,
+ }
+ 
+ impl TicTimer {
+     fn start(&self, tic: u32) -> Result<(), AfbError> {
+         let mut timer = lock_shared(&self.timer);
+         if let Some(previous) = timer.take() {
+             previous.unref();
+         }
+         let next = AfbTimer::new("tic-timer")
+             .set_period(tic)
+             .set_decount(0)
+             .set_callback(timer_callback)
+             .set_context(TimerCtx { mgr: self.mgr })
+             .start()?;
+         *timer = Some(next);
+         Ok(())
+     }
  }
  
  struct LinkyOverEvtCtx {
      energy_mgr: &'static ManagerHandle,
-     data_set: Rc<RefCell<MeterDataSet>>,
+     data_set: SharedDataSet,
      evt: &'static AfbEvent,
  }
  
  fn evt_iover_cb(
-     _evt: &AfbEventMsg,
+     evt: &AfbEventMsg,
      args: &AfbRqtData,
      ctx: &AfbCtxData,
  ) -> Result<(), AfbError> {
  
      let ctx = ctx.get_ref::<LinkyOverEvtCtx>()?;
+     let source = get_event_source(evt);
+     ctx.energy_mgr.count_event(&source);
  
-     let mut data_set = match ctx.data_set.try_borrow_mut() {
-         Err(_) => return afb_error!("energy-LinkyAdps-update", "fail to access energy state"),
-         Ok(value) => value,
-     };
+     let mut data_set = lock_data_set(ctx.energy_mgr, &source, &ctx.data_set);
      let jargs = args.get::<JsoncObj>(0)?;
      for idx in 0..jargs.count()? {
          let value = jargs.index::<f64>(idx)?;
@@ -56,46 +164,291 @@
      }
      if data_set.updated {
          ctx.energy_mgr.check_over_subscription(&data_set)?;
+         ctx.energy_mgr.record_data_set(&data_set)?;
          ctx.evt.push(data_set.clone());
      }
      Ok(())
  }
  
- struct LinkyAvailEvtCtx {
+ // linky apparent power labels, per phase ones only exist on three phase meters
+ const SINSTS_LABELS: [&str; 4] = ["SINSTS", "SINSTS1", "SINSTS2", "SINSTS3"];
+ pub(crate) fn get_sinsts_labels(phase: i32) -> &'static [&'static str] {
+     match phase {
+         3 => &SINSTS_LABELS,
+         _ => &SINSTS_LABELS[0..1],
+     }
+ }
+ 
+ struct LinkyAvailCtx {
      energy_mgr: &'static ManagerHandle,
-     data_set: Rc<RefCell<MeterDataSet>>,
+     linky_api: &'static str,
      evt: &'static AfbEvent,
  }
  
  fn evt_iavail_cb(
-     _evt: &AfbEventMsg,
+     evt: &AfbEventMsg,
      args: &AfbRqtData,
      ctx:&AfbCtxData,
  ) -> Result<(), AfbError> {
  
-     let ctx = ctx.get_ref::<LinkyAvailEvtCtx>()?;
+     let ctx = ctx.get_ref::<LinkyAvailCtx>()?;
+     ctx.energy_mgr.count_event(&get_event_source(evt));
  
-     let mut data_set = match ctx.data_set.try_borrow_mut() {
-         Err(_) => return afb_error!("energy-LinkyAvail-update", "fail to access energy state"),
-         Ok(value) => value,
+     let name = evt.get_name();
+     let label = match name.rsplit('/').next() {
+         Some(value) => value,
+         None => return afb_error!("energy-LinkyAvail-update", "invalid event:{}", name),
+     };
+     let index = match SINSTS_LABELS.iter().position(|value| *value == label) {
+         Some(value) => value,
+         None => return Ok(()),
      };
+ 
+     let power = args.get::<JsoncObj>(0)?.index::<f64>(0)?;
+     let (avail, imax) = ctx.energy_mgr.update_apparent_power(index, power.round() as i32)?;
+     ctx.energy_mgr.record_data_set(&avail)?;
+     if avail.total < imax {
+         ctx.evt.push(avail.total);
+     }
+     Ok(())
+ }
+ 
+ fn iavail_request_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<LinkyAvailCtx>()?;
+ 
+     match args.get::<&EnergyAction>(0)? {
+         EnergyAction::READ => {
+             rqt.reply(ctx.energy_mgr.get_available_current()?, 0);
+         }
+ 
+         EnergyAction::SUBSCRIBE => {
+             if ctx.linky_api != "" {
+                 let phase = ctx.energy_mgr.get_settings()?.phase;
+                 for label in get_sinsts_labels(phase) {
+                     subcall_sync(ctx.energy_mgr, rqt.get_api(), ctx.linky_api, label, EnergyAction::SUBSCRIBE)?;
+                 }
+             }
+             ctx.evt.subscribe(rqt)?;
+             reply_retained(rqt, ctx.energy_mgr, ctx.evt.get_uid().to_string(), &MeterTagSet::AvailCurrent)?;
+         }
+ 
+         EnergyAction::UNSUBSCRIBE => {
+             ctx.evt.unsubscribe(rqt)?;
+             rqt.reply(AFB_NO_DATA, 0);
+         }
+ 
+         EnergyAction::INFO => {
+             let phase = ctx.energy_mgr.get_settings()?.phase;
+             let info = VerbInfoSet {
+                 verb: ctx.evt.get_uid().to_string(),
+                 api: ctx.linky_api.to_string(),
+                 labels: to_strings(get_sinsts_labels(phase)),
+                 actions: to_strings(&INFO_ACTIONS),
+                 ..VerbInfoSet::default()
+             };
+             rqt.reply(get_verb_info(ctx.energy_mgr, &MeterTagSet::AvailCurrent, info)?, 0);
+         }
+ 
+         _ => {
+             return afb_error!(
+                 "energy-iavail-action",
+                 "unsupported action should be (read|subscribe|unsubscribe|info)"
+             )
+         }
+     }
+     Ok(())
+ }
+ 
+ struct LinkyInjectionCtx {
+     energy_mgr: &'static ManagerHandle,
+     data_set: SharedDataSet,
+     linky_api: &'static str,
+     evt: &'static AfbEvent,
+ }
+ 
+ // SINSTI update injected power data_set, EAIT only the injected energy index
+ fn evt_injection_cb(
+     evt: &AfbEventMsg,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<LinkyInjectionCtx>()?;
+     let source = get_event_source(evt);
+     ctx.energy_mgr.count_event(&source);
+ 
      let jargs = args.get::<JsoncObj>(0)?;
-     for idx in 0..jargs.count()? {
-         let value = jargs.index::<f64>(idx)?;
-         data_set.total = data_set.total + (value * 1000.0).round() as i32;
-         data_set.update(idx, value)?;
+     if evt.get_name().ends_with("/EAIT") {
+         ctx.energy_mgr.set_injection_energy(jargs.index::<i32>(0)?)?;
+         return Ok(());
      }
+ 
+     let mut data_set = lock_data_set(ctx.energy_mgr, &source, &ctx.data_set);
+     data_set.variation = ctx.energy_mgr.get_variation()?;
+     data_set.update(0, jargs.index::<f64>(0)?)?;
      if data_set.updated {
-         let (iavail, imax) = ctx.energy_mgr.check_available_current(&data_set)?;
-         if iavail < imax {
-             ctx.evt.push(iavail);
+         ctx.energy_mgr.check_over_subscription(&data_set)?;
+         ctx.energy_mgr.record_data_set(&data_set)?;
+         ctx.evt.push(data_set.clone());
+     }
+     Ok(())
+ }
+ 
+ fn injection_request_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<LinkyInjectionCtx>()?;
+ 
+     if ctx.linky_api == "" {
+         return afb_error!("energy-injection-action", "no linky meter configured");
+     }
+ 
+     match args.get::<&EnergyAction>(0)? {
+         EnergyAction::READ => {
+             let response = subcall_sync(ctx.energy_mgr, rqt.get_api(), ctx.linky_api, "SINSTI", EnergyAction::READ)?;
+             let sinsti = response.get::<JsoncObj>(0)?.index::<f64>(0)?;
+ 
+             let response = subcall_sync(ctx.energy_mgr, rqt.get_api(), ctx.linky_api, "EAIT", EnergyAction::READ)?;
+             ctx.energy_mgr.set_injection_energy(response.get::<JsoncObj>(0)?.index::<i32>(0)?)?;
+ 
+             let mut data_set = lock_data_set(ctx.energy_mgr, ctx.linky_api, &ctx.data_set);
+             data_set.update(0, sinsti)?;
+             ctx.energy_mgr.check_over_subscription(&data_set)?;
+             rqt.reply(data_set.clone(), 0);
+         }
+ 
+         EnergyAction::SUBSCRIBE => {
+             for label in ["SINSTI", "EAIT"] {
+                 subcall_sync(ctx.energy_mgr, rqt.get_api(), ctx.linky_api, label, EnergyAction::SUBSCRIBE)?;
+             }
+             ctx.evt.subscribe(rqt)?;
+             rqt.reply(AFB_NO_DATA, 0);
+         }
+ 
+         EnergyAction::UNSUBSCRIBE => {
+             ctx.evt.unsubscribe(rqt)?;
+             rqt.reply(AFB_NO_DATA, 0);
+         }
+ 
+         EnergyAction::INFO => {
+             let info = VerbInfoSet {
+                 verb: ctx.evt.get_uid().to_string(),
+                 api: ctx.linky_api.to_string(),
+                 labels: to_strings(&["SINSTI", "EAIT"]),
+                 actions: to_strings(&INFO_ACTIONS),
+                 ..VerbInfoSet::default()
+             };
+             rqt.reply(get_verb_info(ctx.energy_mgr, &MeterTagSet::Injection, info)?, 0);
+         }
+ 
+         _ => {
+             return afb_error!(
+                 "energy-injection-action",
+                 "unsupported action should be (read|subscribe|unsubscribe|info)"
+             )
+         }
+     }
+     Ok(())
+ }
+ 
+ // STGE status word is an hexadecimal string, accept integer as well
+ pub(crate) fn get_linky_status(jargs: &JsoncObj) -> Result<u32, AfbError> {
+     if let Ok(value) = jargs.index::<String>(0) {
+         return match u32::from_str_radix(value.trim(), 16) {
+             Ok(value) => Ok(value),
+             Err(_) => afb_error!("energy-linky-status", "invalid STGE value:{}", value),
+         };
+     }
+     Ok(jargs.index::<i64>(0)? as u32)
+ }
+ 
+ struct TempoCtx {
+     energy_mgr: &'static ManagerHandle,
+     evt: &'static AfbEvent,
+ }
+ 
+ fn evt_tempo_cb(
+     evt: &AfbEventMsg,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<TempoCtx>()?;
+     ctx.energy_mgr.count_event(&get_event_source(evt));
+ 
+     let jargs = args.get::<JsoncObj>(0)?;
+     let changed = if evt.get_name().ends_with("/STGE") {
+         ctx.energy_mgr.update_tempo(Some(get_linky_status(&jargs)?), None)?
+     } else {
+         ctx.energy_mgr.update_tempo(None, Some(&jargs.index::<String>(0)?))?
+     };
+     if changed {
+         ctx.evt.push(ctx.energy_mgr.get_tempo()?);
+     }
+     Ok(())
+ }
+ 
+ fn tempo_request_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<TempoCtx>()?;
+ 
+     match args.get::<&EnergyAction>(0)? {
+         EnergyAction::READ => {
+             rqt.reply(ctx.energy_mgr.get_tempo()?, 0);
+         }
+ 
+         EnergyAction::SUBSCRIBE => {
+             ctx.evt.subscribe(rqt)?;
+             rqt.reply(AFB_NO_DATA, 0);
+         }
+ 
+         EnergyAction::UNSUBSCRIBE => {
+             ctx.evt.unsubscribe(rqt)?;
+             rqt.reply(AFB_NO_DATA, 0);
+         }
+ 
+         _ => {
+             return afb_error!(
+                 "energy-tempo-action",
+                 "unsupported action should be (read|subscribe|unsubscribe)"
+             )
          }
      }
      Ok(())
  }
  
+ fn tempo_override_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<TempoCtx>()?;
+ 
+     let request = args.get::<&TempoOverride>(0)?;
+     afb_log_msg!(Notice, rqt, "tempo policy override active:{}", request.active);
+ 
+     let state = ctx.energy_mgr.set_tempo_override(request.active)?;
+     ctx.evt.push(state.clone());
+     rqt.reply(state, 0);
+     Ok(())
+ }
+ 
  struct LinkyRqtCtx {
-     data_set: Rc<RefCell<MeterDataSet>>,
+     energy_mgr: &'static ManagerHandle,
+     data_set: SharedDataSet,
      linky_api: &'static str,
      linky_verb: &'static str,
      evt: &'static AfbEvent,
@@ -117,18 +470,15 @@
                      "no linky meter configure use 'subscribe'"
                  );
              }
-             let mut data_set = match ctx.data_set.try_borrow_mut() {
-                 Err(_) => return afb_error!("energy-adps-read", "fail to access energy state"),
-                 Ok(value) => value,
-             };
- 
-             let response = AfbSubCall::call_sync(
+             let response = subcall_sync(
+                 ctx.energy_mgr,
                  rqt.get_api(),
                  ctx.linky_api,
                  ctx.linky_verb,
                  EnergyAction::READ,
              )?;
  
+             let mut data_set = lock_data_set(ctx.energy_mgr, ctx.linky_api, &ctx.data_set);
              let jargs = response.get::<JsoncObj>(0)?;
              for idx in 0..jargs.count()? {
                  let value = jargs.index::<i32>(idx)?;
@@ -147,7 +497,8 @@
  
          EnergyAction::SUBSCRIBE => {
              if ctx.linky_api == "" {
-                 AfbSubCall::call_sync(
+                 subcall_sync(
+                     ctx.energy_mgr,
                      rqt.get_api(),
                      ctx.linky_api,
                      ctx.linky_verb,
@@ -155,204 +506,793 @@
                  )?;
              }
              ctx.evt.subscribe(rqt)?;
-             rqt.reply(AFB_NO_DATA, 0);
+             reply_retained(rqt, ctx.energy_mgr, ctx.evt.get_uid().to_string(), &MeterTagSet::OverCurrent)?;
          }
  
          EnergyAction::UNSUBSCRIBE => {
              ctx.evt.unsubscribe(rqt)?;
              rqt.reply(AFB_NO_DATA, 0);
          }
+ 
+         EnergyAction::INFO => {
+             let info = VerbInfoSet {
+                 verb: ctx.evt.get_uid().to_string(),
+                 api: ctx.linky_api.to_string(),
+                 labels: to_strings(&[ctx.linky_verb]),
+                 actions: to_strings(&INFO_ACTIONS),
+                 ..VerbInfoSet::default()
+             };
+             rqt.reply(get_verb_info(ctx.energy_mgr, &MeterTagSet::OverCurrent, info)?, 0);
+         }
          _ => {
              return afb_error!(
                  rqt.get_uid().as_str(),
-                 "action not supported use [read|subscribe|unsubscribe]"
+                 "action not supported use [read|subscribe|unsubscribe|info]"
              )
          }
      }
      Ok(())
  }
  
- struct MeterEvtCtx {
-     data_set: Rc<RefCell<MeterDataSet>>,
-     labels: &'static [&'static str],
-     meter_api: &'static str,
-     evt: &'static AfbEvent,
+ // one meter data_set fed by meter events
+ struct MeterEvtSet {
+     data_set: SharedDataSet,
+     subscribers: Arc<Mutex<MeterSubscribers>>,
+     labels: &'static [&'static str],
+     evt: SharedEvent,
+ }
+ 
+ // one handler per meter api, settings may move the meter to another api at runtime
+ struct MeterEvtCtx {
+     sets: Arc<Vec<MeterEvtSet>>,
+     energy_mgr: &'static ManagerHandle,
+ }
+ 
+ fn evt_meter_cb(evt: &AfbEventMsg, args: &AfbRqtData, ctx:&AfbCtxData) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<MeterEvtCtx>()?;
+     let meter = ctx.energy_mgr.get_meter()?;
+ 
+     // events from a previous meter api still in flight are dropped
+     let name = evt.get_name();
+     let label = match name.split_once('/') {
+         Some((api, label)) if api == meter.api => label,
+         _ => return Ok(()),
+     };
+     ctx.energy_mgr.count_event(&meter.api);
+     let value = args.get::<f64>(0)?;
+ 
+     for set in ctx.sets.iter() {
+         if let Some(idx) = meter.find_label(set.labels, label) {
+             meter_evt_update(ctx.energy_mgr, &meter.api, set, idx, value)?;
+         }
+     }
+     Ok(())
+ }
+ 
+ fn meter_evt_update(
+     energy_mgr: &ManagerHandle,
+     meter_api: &str,
+     set: &MeterEvtSet,
+     idx: usize,
+     value: f64,
+ ) -> Result<(), AfbError> {
+     let mut data_set = lock_data_set(energy_mgr, meter_api, &set.data_set);
+     data_set.variation = energy_mgr.get_variation()?;
+ 
+     // energy index goes through totalizer to survive rollover/replacement
+     if let MeterTagSet::Energy = data_set.tag {
+         let session = energy_mgr.update_energy(value)?;
+         if session != data_set.total {
+             data_set.total = session;
+             data_set.updated = true;
+         }
+     } else {
+         data_set.update(idx, value)?;
+     }
+ 
+     // to limit the number of events data is updated only when total value is received
+     if data_set.updated {
+         push_data_set(energy_mgr, &set.evt, &set.subscribers, &data_set)?;
+     }
+     Ok(())
+ }
+ 
+ // every data_set change goes to energy state, retained/history and subscribers
+ fn push_data_set(
+     energy_mgr: &ManagerHandle,
+     evt: &AfbEvent,
+     subscribers: &Mutex<MeterSubscribers>,
+     data_set: &MeterDataSet,
+ ) -> Result<(), AfbError> {
+     energy_mgr.check_over_subscription(data_set)?;
+     energy_mgr.record_data_set(data_set)?;
+     let _listeners = evt.push(data_set.clone());
+     lock_shared(subscribers).push(data_set, Instant::now());
+     Ok(())
+ }
+ 
+ // meter verbs, only rebuilt when settings change the meter
+ struct MeterVerbs {
+     meter: Option<Arc<MeterBinding>>,
+     verbs: Arc<Vec<String>>,
+ }
+ 
+ impl MeterVerbs {
+     fn new() -> Mutex<Self> {
+         Mutex::new(MeterVerbs {
+             meter: None,
+             verbs: Arc::new(Vec::new()),
+         })
+     }
+ }
+ 
+ fn get_meter_verbs(cache: &Mutex<MeterVerbs>, meter: &Arc<MeterBinding>, labels: &[&str]) -> Arc<Vec<String>> {
+     let mut cache = lock_shared(cache);
+     let current = match &cache.meter {
+         Some(cached) => Arc::ptr_eq(cached, meter),
+         None => false,
+     };
+     if !current {
+         cache.meter = Some(meter.clone());
+         cache.verbs = Arc::new(labels.iter().map(|label| meter.get_verb(label)).collect());
+     }
+     cache.verbs.clone()
+ }
+ 
+ // subscribe meter labels and keep track of them for a later meter change
+ fn subscribe_meter(
+     mgr: &ManagerHandle,
+     api: &AfbApi,
+     meter: &MeterBinding,
+     labels: &[&str],
+     verbs: &[String],
+ ) -> Result<(), AfbError> {
+     for (label, verb) in labels.iter().zip(verbs) {
+         subcall_sync(mgr, api, &meter.api, verb, EnergyAction::SUBSCRIBE)?;
+         mgr.add_meter_label(label);
+     }
+     Ok(())
+ }
+ 
+ // one meter read in progress, shared by label subcalls and timeout timer
+ struct MeterReadJob {
+     rqt: AfbRequest,
+     energy_mgr: &'static ManagerHandle,
+     data_set: SharedDataSet,
+     meter_api: String,
+     labels: &'static [&'static str],
+     read: Mutex<MeterRead>,
+ }
+ 
+ struct MeterReadCtx {
+     job: Arc<MeterReadJob>,
+     idx: usize,
+ }
+ 
+ struct MeterTimeoutCtx {
+     job: Arc<MeterReadJob>,
+ }
+ 
+ // apply received values and reply data_set once, status is the number of missing labels
+ fn meter_read_reply(job: &MeterReadJob) -> Result<(), AfbError> {
+     let values = match lock_shared(&job.read).take() {
+         Some(values) => values,
+         None => return Ok(()),
+     };
+ 
+     let mut data_set = lock_data_set(job.energy_mgr, &job.meter_api, &job.data_set);
+     data_set.variation = job.energy_mgr.get_variation()?;
+     let mut missing = Vec::new();
+     for (idx, data) in values.into_iter().enumerate() {
+         let data = match data {
+             Some(value) => value,
+             None => {
+                 missing.push(job.labels[idx].to_string());
+                 continue;
+             }
+         };
+         data_set.update(idx, data)?;
+ 
+         let value = (data * 1000.0).round() as i32;
+         match idx {
+             0 if data_set.tag == MeterTagSet::Energy => data_set.total = job.energy_mgr.update_energy(data)?,
+             0 => data_set.total = value - data_set.start,
+             1 => data_set.l1 = value,
+             2 => data_set.l2 = value,
+             3 => data_set.l3 = value,
+             _ => return afb_error!("energy-meter-update", "invalid index:{}", idx),
+         }
+     }
+ 
+     // nothing received is an error, a partial read still replies with what was received
+     let status = if missing.len() == job.labels.len() { -1 } else { missing.len() as i32 };
+     if !missing.is_empty() {
+         afb_log_msg!(Warning, &job.rqt, "partial {} read missing:{:?}", job.meter_api, missing);
+     }
+     job.rqt.reply(data_set.clone(), status);
+     Ok(())
+ }
+ 
+ fn meter_read_cb(_api: &AfbApi, args: &AfbRqtData, ctx: &AfbCtxData) -> Result<(), AfbError> {
+     let ctx = ctx.get_ref::<MeterReadCtx>()?;
+     let job = &ctx.job;
+ 
+     let value = match args.get::<f64>(0) {
+         Ok(value) => {
+             job.energy_mgr.count_subcall_success(&job.meter_api);
+             Some(value)
+         }
+         Err(error) => {
+             job.energy_mgr.count_subcall_error(&job.meter_api, &error);
+             None
+         }
+     };
+     if lock_shared(&job.read).set(ctx.idx, value) {
+         meter_read_reply(job)?;
+     }
+     Ok(())
+ }
+ 
+ fn meter_timeout_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+     let ctx = ctx.get_ref::<MeterTimeoutCtx>()?;
+     meter_read_reply(&ctx.job)
+ }
+ 
+ struct MeterRequestCtx {
+     energy_mgr: &'static ManagerHandle,
+     data_set: SharedDataSet,
+     subscribers: Arc<Mutex<MeterSubscribers>>,
+     labels: &'static [&'static str],
+     verbs: Mutex<MeterVerbs>,
+     actions: &'static [&'static str],
+     read_timeout: u32,
+     evt: &'static AfbEvent,
+ }
+
+ fn meter_request_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<MeterRequestCtx>()?;
+     let meter = ctx.energy_mgr.get_meter()?;
+     let verbs = get_meter_verbs(&ctx.verbs, &meter, ctx.labels);
+     let tag = lock_shared(&ctx.data_set).tag.clone();
+ 
+     // data_set is never locked during a subcall, sync call may dispatch meter events on this thread
+     match args.get::<&EnergyAction>(0)? {
+         // every label is read concurrently, reply is sent by last answer or timeout
+         EnergyAction::READ => {
+             let job = Arc::new(MeterReadJob {
+                 rqt: rqt.add_ref(),
+                 energy_mgr: ctx.energy_mgr,
+                 data_set: ctx.data_set.clone(),
+                 meter_api: meter.api.clone(),
+                 labels: ctx.labels,
+                 read: Mutex::new(MeterRead::new(ctx.labels.len())),
+             });
+ 
+             AfbTimer::new("meter-read-timeout")
+                 .set_period(ctx.read_timeout)
+                 .set_decount(1)
+                 .set_callback(meter_timeout_cb)
+                 .set_context(MeterTimeoutCtx { job: job.clone() })
+                 .start()?;
+ 
+             for (idx, verb) in verbs.iter().enumerate() {
+                 let status = AfbSubCall::call_async(
+                     rqt.get_api(),
+                     &meter.api,
+                     verb,
+                     EnergyAction::READ,
+                     meter_read_cb,
+                     MeterReadCtx { job: job.clone(), idx },
+                 );
+                 if let Err(error) = status {
+                     ctx.energy_mgr.count_subcall_error(&meter.api, &error);
+                     if lock_shared(&job.read).set(idx, None) {
+                         meter_read_reply(&job)?;
+                     }
+                 }
+             }
+         }
+ 
+         EnergyAction::SUBSCRIBE => {
+             // optional filter arguments share the subscribe json, binary action means no filter
+             let filter = match args.get::<&MeterFilterRequest>(0) {
+                 Ok(value) => value.clone(),
+                 Err(_) => MeterFilterRequest::default(),
+             };
+             afb_log_msg!(Notice, rqt, "Subscribe {} filter:{:?}", ctx.evt.get_uid(), filter);
+             let event = if MeterFilter::is_default(&filter) {
+                 ctx.evt.subscribe(rqt)?;
+                 ctx.evt.get_uid().to_string()
+             } else {
+                 lock_shared(&ctx.subscribers).subscribe(rqt, ctx.evt.get_uid(), &filter)?
+             };
+             subscribe_meter(ctx.energy_mgr, rqt.get_api(), &meter, ctx.labels, &verbs)?;
+             reply_retained(rqt, ctx.energy_mgr, event, &tag)?;
+         }
+ 
+         EnergyAction::UNSUBSCRIBE => {
+             afb_log_msg!(Notice, rqt, "Unsubscribe {}", ctx.evt.get_uid());
+             ctx.evt.unsubscribe(rqt)?;
+             lock_shared(&ctx.subscribers).unsubscribe(rqt);
+             rqt.reply(AFB_NO_DATA, 0);
+         }
+ 
+         // use l1 to provide session power
+         EnergyAction::RESET => {
+             match tag {
+                 MeterTagSet::Energy => {}
+                 _ => {
+                     return afb_error!(
+                         rqt.get_uid().as_str(),
+                         "action reset not supported for tag:{:?}",
+                         tag
+                     )
+                 }
+             }
+ 
+             // read meeter reset energy counter value
+             let response = subcall_sync(ctx.energy_mgr, rqt.get_api(), &meter.api, &verbs[0], EnergyAction::READ)?;
+ 
+             let data = response.get::<f64>(0)?;
+             ctx.energy_mgr.update_energy(data)?;
+             ctx.energy_mgr.start_session()?;
+ 
+             // session restarts from 0, subscribe replies and state should not keep previous one
+             let mut data_set = lock_data_set(ctx.energy_mgr, &meter.api, &ctx.data_set);
+             data_set.total = 0;
+             push_data_set(ctx.energy_mgr, ctx.evt, &ctx.subscribers, &data_set)?;
+ 
+             data_set.tag = data_set.tag.clone();
+             rqt.reply(data_set.clone(), 0);
+         }
+ 
+         EnergyAction::INFO => {
+             let filters = lock_shared(&ctx.subscribers).get_filters();
+             let info = VerbInfoSet {
+                 verb: ctx.evt.get_uid().to_string(),
+                 api: meter.api.clone(),
+                 prefix: meter.prefix.clone(),
+                 labels: to_strings(ctx.labels),
+                 filters,
+                 actions: to_strings(ctx.actions),
+                 ..VerbInfoSet::default()
+             };
+             rqt.reply(get_verb_info(ctx.energy_mgr, &tag, info)?, 0);
+         }
+     }
+     Ok(())
+ }
+ 
+ struct ConfRequestCtx {
+     energy_mgr: &'static ManagerHandle,
+ }
+ 
+ fn conf_request_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<ConfRequestCtx>()?;
+ 
+     let config = args.get::<&EngyConfSet>(0)?;
+     afb_log_msg!(Debug, rqt, "update energy conf={:?}", config);
+ 
+     //automatically subscribe client to energy manager event
+     ctx.energy_mgr.subscribe_over_power(rqt)?;
+     ctx.energy_mgr.set_imax_cable(config.imax)?;
+     ctx.energy_mgr.set_power_backend(config.pmax)?;
+ 
+     rqt.reply(ctx.energy_mgr.get_config()?, 0);
+     Ok(())
+ }
+ 
+ struct SettingsRequestCtx {
+     energy_mgr: &'static ManagerHandle,
+     linky_api: &'static str,
+     tic_timer: TicTimer,
+     meter_sets: Arc<Vec<MeterEvtSet>>,
+     meter_apis: Mutex<Vec<String>>,
+ }
+ 
+ // meter event pattern is per api, handler is registered once for each api ever used
+ fn add_meter_handler(
+     apiv4: AfbApiV4,
+     ctx: &SettingsRequestCtx,
+     meter_api: &str,
+ ) -> Result<(), AfbError> {
+     let mut apis = lock_shared(&ctx.meter_apis);
+     if apis.iter().any(|api| api == meter_api) {
+         return Ok(());
+     }
+     let handler = new_meter_handler(meter_api, ctx.energy_mgr, ctx.meter_sets.clone())?;
+     handler.register(apiv4)?;
+     apis.push(meter_api.to_string());
+     Ok(())
+ }
+ 
+ fn new_meter_handler(
+     meter_api: &str,
+     energy_mgr: &'static ManagerHandle,
+     sets: Arc<Vec<MeterEvtSet>>,
+ ) -> Result<&'static AfbEvtHandler, AfbError> {
+     AfbEvtHandler::new("meter-events")
+         .set_pattern(to_static_str(format!("{}/*", meter_api)))
+         .set_callback(evt_meter_cb)
+         .set_context(MeterEvtCtx { sets, energy_mgr })
+         .finalize()
+ }
+ 
+ // subscribe new meter before dropping previous one, nothing changes when new meter fails
+ fn move_meter(
+     rqt: &AfbRequest,
+     ctx: &SettingsRequestCtx,
+     previous: &MeterBinding,
+     meter: &MeterBinding,
+ ) -> Result<(), AfbError> {
+     if meter.api != previous.api {
+         add_meter_handler(rqt.get_apiv4(), ctx, &meter.api)?;
+     }
+ 
+     let labels = ctx.energy_mgr.get_meter_labels();
+     for (count, label) in labels.iter().enumerate() {
+         let verb = meter.get_verb(label);
+         if let Err(error) = subcall_sync(ctx.energy_mgr, rqt.get_api(), &meter.api, &verb, EnergyAction::SUBSCRIBE) {
+             for label in &labels[0..count] {
+                 let verb = meter.get_verb(label);
+                 let _ = subcall_sync(ctx.energy_mgr, rqt.get_api(), &meter.api, &verb, EnergyAction::UNSUBSCRIBE);
+             }
+             return Err(error);
+         }
+     }
+ 
+     for label in labels.iter() {
+         let verb = previous.get_verb(label);
+         if let Err(error) = subcall_sync(ctx.energy_mgr, rqt.get_api(), &previous.api, &verb, EnergyAction::UNSUBSCRIBE) {
+             afb_log_msg!(Warning, rqt, "fail to unsubscribe previous meter {}/{} error:{}", previous.api, verb, error);
+         }
+     }
+     Ok(())
+ }
+ 
+ // change runtime settings, empty request only returns effective configuration
+ fn settings_request_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<SettingsRequestCtx>()?;
+ 
+     let request = args.get::<&SettingsRequest>(0)?;
+     afb_log_msg!(Debug, rqt, "update energy settings={:?}", request);
+     EnergySettings::check(request)?;
+ 
+     let previous = ctx.energy_mgr.get_settings()?;
+     let meter = ctx.energy_mgr.get_meter()?;
+     if let Some(update) = meter.update(request) {
+         move_meter(rqt, ctx, &meter, &update)?;
+     }
+     let settings = ctx.energy_mgr.update_settings(request)?;
+     if settings.tic != previous.tic {
+         ctx.tic_timer.start(settings.tic)?;
+     }
+ 
+     // a 3 phase installation also needs linky per phase apparent power
+     if ctx.linky_api != "" && settings.phase != previous.phase {
+         for label in get_sinsts_labels(settings.phase) {
+             subcall_sync(ctx.energy_mgr, rqt.get_api(), ctx.linky_api, label, EnergyAction::SUBSCRIBE)?;
+         }
+     }
+ 
+     rqt.reply(settings, 0);
+     Ok(())
+ }
+ 
+ struct HealthRequestCtx {
+     energy_mgr: &'static ManagerHandle,
+ }
+ 
+ // per source counters, degradation state and limits currently enforced
+ fn health_request_cb(
+     rqt: &AfbRequest,
+     _args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<HealthRequestCtx>()?;
+     rqt.reply(ctx.energy_mgr.get_health()?, 0);
+     Ok(())
+ }
+ 
+ struct PhaseRequestCtx {
+     energy_mgr: &'static ManagerHandle,
+ }
+ 
+ fn phase_request_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<PhaseRequestCtx>()?;
+ 
+     match args.get::<&EnergyAction>(0)? {
+         EnergyAction::READ => {
+             rqt.reply(ctx.energy_mgr.get_phase_switch()?, 0);
+         }
+ 
+         EnergyAction::SUBSCRIBE => {
+             ctx.energy_mgr.subscribe_phase_switch(rqt, true)?;
+             rqt.reply(AFB_NO_DATA, 0);
+         }
+ 
+         EnergyAction::UNSUBSCRIBE => {
+             ctx.energy_mgr.subscribe_phase_switch(rqt, false)?;
+             rqt.reply(AFB_NO_DATA, 0);
+         }
+ 
+         _ => {
+             return afb_error!(
+                 "energy-phase-action",
+                 "unsupported action should be (read|subscribe|unsubscribe)"
+             )
+         }
+     }
+     Ok(())
+ }
+ 
+ struct StatsRequestCtx {
+     energy_mgr: &'static ManagerHandle,
+ }
+ 
+ fn stats_request_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<StatsRequestCtx>()?;
+ 
+     match args.get::<&EnergyAction>(0)? {
+         EnergyAction::READ => {
+             rqt.reply(ctx.energy_mgr.get_stats()?, 0);
+         }
+ 
+         EnergyAction::RESET => {
+             ctx.energy_mgr.reset_stats()?;
+             rqt.reply(AFB_NO_DATA, 0);
+         }
+ 
+         _ => {
+             return afb_error!(
+                 "energy-stats-action",
+                 "unsupported action should be (read|reset)"
+             )
+         }
+     }
+     Ok(())
+ }
+ 
+ struct TrendRequestCtx {
+     energy_mgr: &'static ManagerHandle,
+ }
+ 
+ fn trend_request_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<TrendRequestCtx>()?;
+ 
+     let request = args.get::<&TrendRequest>(0)?;
+     afb_log_msg!(Debug, rqt, "trend request={:?}", request);
+ 
+     rqt.reply(ctx.energy_mgr.get_trend(request)?, 0);
+     Ok(())
+ }
+ 
+ struct ProfileRequestCtx {
+     energy_mgr: &'static ManagerHandle,
+ }
+ 
+ fn profile_install_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<ProfileRequestCtx>()?;
+ 
+     let profile = args.get::<&ChargingProfile>(0)?;
+     afb_log_msg!(Debug, rqt, "install charging profile={:?}", profile);
+ 
+     let profile = ctx.energy_mgr.install_profile(profile.clone())?;
+     rqt.reply(profile, 0);
+     Ok(())
+ }
+ 
+ fn profile_clear_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<ProfileRequestCtx>()?;
+ 
+     let request = args.get::<&ClearProfileRequest>(0)?;
+     let count = ctx.energy_mgr.clear_profile(request)?;
+     afb_log_msg!(Debug, rqt, "clear charging profile={:?} count={}", request, count);
+ 
+     rqt.reply(count as i32, 0);
+     Ok(())
+ }
+ 
+ fn profile_composite_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<ProfileRequestCtx>()?;
+ 
+     let request = args.get::<&CompositeRequest>(0)?;
+     rqt.reply(ctx.energy_mgr.get_composite(request)?, 0);
+     Ok(())
+ }
+ 
+ fn pmax_schedule_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
+ 
+     let ctx = ctx.get_ref::<ProfileRequestCtx>()?;
+ 
+     let request = args.get::<&PMaxScheduleRequest>(0)?;
+     rqt.reply(ctx.energy_mgr.get_pmax_schedule(request)?, 0);
+     Ok(())
+ }
+ 
+ struct LimitRequestCtx {
      energy_mgr: &'static ManagerHandle,
  }
  
- fn evt_meter_cb(evt: &AfbEventMsg, args: &AfbRqtData, ctx:&AfbCtxData) -> Result<(), AfbError> {
-     
-     let ctx = ctx.get_ref::<MeterEvtCtx>()?;
-     let mut data_set = match ctx.data_set.try_borrow_mut() {
-         Err(_) => return afb_error!("energy-metercb-update", "fail to access energy state"),
-         Ok(value) => value,
-     };
+ // check external limits expiry and heartbeat every second
+ fn limit_timer_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+     let ctx = ctx.get_ref::<LimitRequestCtx>()?;
+     ctx.energy_mgr.check_external_limits()
+ }
  
-     let value = args.get::<f64>(0)?;
+ fn limit_request_cb(
+     rqt: &AfbRequest,
+     args: &AfbRqtData,
+     ctx: &AfbCtxData,
+ ) -> Result<(), AfbError> {
  
-     // move to bytes as rust cannot index str :(
-     let full_name = evt.get_name().as_bytes();
-     let short_name = match full_name.get(1 + ctx.meter_api.len()..full_name.len()) {
-         Some(value) => value,
-         None => {
-             return afb_error!(
-                 "energy-metercb-update",
-                 "evt_meter_cb meter argument not a valid float number"
-             )
-         }
-     };
+     let ctx = ctx.get_ref::<LimitRequestCtx>()?;
  
-     for idx in 0..ctx.labels.len() {
-         let label = ctx.labels[idx].as_bytes();
-         if short_name == label {
-             data_set.update(idx, value)?;
-             break;
-         }
-     }
+     let request = args.get::<&LimitRequest>(0)?;
+     afb_log_msg!(Debug, rqt, "external limit request={:?}", request);
  
-     // to limit the number of events data is updated only when total value is received
-     if data_set.updated {
-         ctx.energy_mgr.check_over_subscription(&data_set)?;
-         let _listeners = ctx.evt.push(data_set.clone());
-      }
+     rqt.reply(ctx.energy_mgr.set_external_limit(request)?, 0);
      Ok(())
  }
  
- struct MeterRequestCtx {
-     data_set: Rc<RefCell<MeterDataSet>>,
-     meter_api: &'static str,
-     meter_prefix: &'static str,
-     labels: &'static [&'static str],
+ struct PvCtx {
+     energy_mgr: &'static ManagerHandle,
      evt: &'static AfbEvent,
  }
-
- fn meter_request_cb(
+ 
+ // poll SunSpec inverter, errors are logged as inverter may sleep at night
+ fn pv_timer_cb(_timer: &AfbTimer, _decount: u32, ctx: &AfbCtxData) -> Result<(), AfbError> {
+     let ctx = ctx.get_ref::<PvCtx>()?;
+     match ctx.energy_mgr.read_pv() {
+         Ok(Some(data_set)) => {
+             ctx.evt.push(data_set);
+         }
+         Ok(None) => {}
+         Err(error) => afb_log_msg!(Warning, None, "{}", error),
+     }
+     Ok(())
+ }
+ 
+ fn pv_request_cb(
      rqt: &AfbRequest,
      args: &AfbRqtData,
      ctx: &AfbCtxData,
  ) -> Result<(), AfbError> {
  
-     let ctx = ctx.get_ref::<MeterRequestCtx>()?;
- 
-     let mut data_set = match ctx.data_set.try_borrow_mut() {
-         Err(_) => return afb_error!("energy-meter-update", "fail to access energy state"),
-         Ok(value) => value,
-     };
+     let ctx = ctx.get_ref::<PvCtx>()?;
  
      match args.get::<&EnergyAction>(0)? {
-         EnergyAction::READ => {
-             for idx in 0..ctx.labels.len() {
-                 let label = ctx.labels[idx];
-                 let response = AfbSubCall::call_sync(
-                     rqt.get_api(),
-                     ctx.meter_api,
-                     [ctx.meter_prefix, label].join("/").as_str(),
-                     EnergyAction::READ,
-                 )?;
-                 let data = response.get::<f64>(0)?;
-                 data_set.update(idx, data)?;
- 
-                 let value = (data * 1000.0).round() as i32;
-                 match idx {
-                     0 => data_set.total = value - data_set.start,
-                     1 => data_set.l1 = value,
-                     2 => data_set.l2 = value,
-                     3 => data_set.l3 = value,
-                     _ => return afb_error!("energy-meter-update", "invalid index:{}", idx),
-                 }
-             }
-             data_set.tag = data_set.tag.clone();
-             rqt.reply(data_set.clone(), 0);
-         }
+         EnergyAction::READ => match ctx.energy_mgr.get_data_set(&MeterTagSet::Pv)? {
+             Some(data_set) => rqt.reply(data_set, 0),
+             None => return afb_error!("energy-pv-read", "no pv data_set received yet"),
+         },
  
          EnergyAction::SUBSCRIBE => {
-             afb_log_msg!(Notice, rqt, "Subscribe {}", ctx.evt.get_uid());
              ctx.evt.subscribe(rqt)?;
-             for label in ctx.labels {
-                 AfbSubCall::call_sync(
-                     rqt.get_api(),
-                     ctx.meter_api,
-                     [ctx.meter_prefix, label].join("/").as_str(),
-                     EnergyAction::SUBSCRIBE,
-                 )?;
-             }
              rqt.reply(AFB_NO_DATA, 0);
          }
  
          EnergyAction::UNSUBSCRIBE => {
-             afb_log_msg!(Notice, rqt, "Unsubscribe {}", ctx.evt.get_uid());
              ctx.evt.unsubscribe(rqt)?;
              rqt.reply(AFB_NO_DATA, 0);
          }
  
-         // use l1 to provide session power
-         EnergyAction::RESET => {
-             match data_set.tag {
-                 MeterTagSet::Energy => {}
-                 _ => {
-                     return afb_error!(
-                         rqt.get_uid().as_str(),
-                         "action reset not supported for tag:{:?}",
-                         data_set.tag
-                     )
-                 }
-             }
- 
-             // read meeter reset energy counter value
-             let response = AfbSubCall::call_sync(
-                 rqt.get_api(),
-                 ctx.meter_api,
-                 [ctx.meter_prefix, ctx.labels[0]].join("/").as_str(),
-                 EnergyAction::READ,
-             )?;
- 
-             let data = response.get::<f64>(0)?;
-             data_set.start = (data * 1000.0).round() as i32;
-             data_set.total = 0;
- 
-             data_set.tag = data_set.tag.clone();
-             rqt.reply(data_set.clone(), 0);
+         // labels are the SunSpec inverter points feeding the data_set
+         EnergyAction::INFO => {
+             let info = VerbInfoSet {
+                 verb: ctx.evt.get_uid().to_string(),
+                 api: "sunspec".to_string(),
+                 labels: to_strings(&["W", "AphA", "AphB", "AphC"]),
+                 actions: to_strings(&INFO_ACTIONS),
+                 ..VerbInfoSet::default()
+             };
+             rqt.reply(get_verb_info(ctx.energy_mgr, &MeterTagSet::Pv, info)?, 0);
          }
+ 
          _ => {
              return afb_error!(
-                 rqt.get_uid().as_str(),
-                 "action not supported use [read|subscribe|unsubscribe|reset]"
+                 "energy-pv-action",
+                 "unsupported action should be (read|subscribe|unsubscribe|info)"
              )
          }
      }
      Ok(())
  }
  
- struct ConfRequestCtx {
+ struct ContractRequestCtx {
      energy_mgr: &'static ManagerHandle,
+     evt: &'static AfbEvent,
  }
  
- fn conf_request_cb(
+ fn contract_request_cb(
      rqt: &AfbRequest,
      args: &AfbRqtData,
      ctx: &AfbCtxData,
  ) -> Result<(), AfbError> {
  
-     let ctx = ctx.get_ref::<ConfRequestCtx>()?;
+     let ctx = ctx.get_ref::<ContractRequestCtx>()?;
  
-     let config = args.get::<&EngyConfSet>(0)?;
-     afb_log_msg!(Debug, rqt, "update energy conf={:?}", config);
+     match args.get::<&EnergyAction>(0)? {
+         EnergyAction::READ => {
+             rqt.reply(ctx.energy_mgr.get_contract()?, 0);
+         }
  
-     //automatically subscribe client to energy manager event
-     ctx.energy_mgr.subscribe_over_power(rqt)?;
-     ctx.energy_mgr.set_imax_cable(config.imax)?;
-     ctx.energy_mgr.set_power_backend(config.pmax)?;
+         EnergyAction::SUBSCRIBE => {
+             ctx.evt.subscribe(rqt)?;
+             rqt.reply(AFB_NO_DATA, 0);
+         }
  
-     rqt.reply(ctx.energy_mgr.get_config()?, 0);
+         EnergyAction::UNSUBSCRIBE => {
+             ctx.evt.unsubscribe(rqt)?;
+             rqt.reply(AFB_NO_DATA, 0);
+         }
+ 
+         _ => {
+             return afb_error!(
+                 "energy-contract-action",
+                 "unsupported action should be (read|subscribe|unsubscribe)"
+             )
+         }
+     }
      Ok(())
  }
  
  struct StateRequestCtx {
      mgr: &'static ManagerHandle,
      evt: &'static AfbEvent,
-     meter_api: &'static str,
-     meter_prefix: &'static str,
      labels: &'static [&'static str],
+     verbs: Mutex<MeterVerbs>,
  }
  
  fn state_request_cb(
@@ -362,6 +1302,7 @@
  ) -> Result<(), AfbError> {
  
      let ctx = ctx.get_ref::<StateRequestCtx>()?;
+     let meter = ctx.mgr.get_meter()?;
  
      match args.get::<&EnergyAction>(0)? {
          EnergyAction::READ => {
@@ -373,14 +1314,8 @@
              afb_log_msg!(Notice, rqt, "Subscribe {}", ctx.evt.get_uid());
  
              // let's make sure we listen for emer events.
-             for label in ctx.labels {
-                 AfbSubCall::call_sync(
-                     rqt.get_api(),
-                     ctx.meter_api,
-                     [ctx.meter_prefix, label].join("/").as_str(),
-                     EnergyAction::SUBSCRIBE,
-                 )?;
-             }
+             let verbs = get_meter_verbs(&ctx.verbs, &meter, ctx.labels);
+             subscribe_meter(ctx.mgr, rqt.get_api(), &meter, ctx.labels, &verbs)?;
  
              ctx.evt.subscribe(rqt)?;
              rqt.reply(AFB_NO_DATA, 0);
@@ -392,10 +1327,22 @@
              rqt.reply(AFB_NO_DATA, 0);
          }
  
+         EnergyAction::INFO => {
+             let info = VerbInfoSet {
+                 verb: ctx.evt.get_uid().to_string(),
+                 api: meter.api.clone(),
+                 prefix: meter.prefix.clone(),
+                 labels: to_strings(ctx.labels),
+                 actions: to_strings(&INFO_ACTIONS),
+                 ..VerbInfoSet::default()
+             };
+             rqt.reply(get_verb_info(ctx.mgr, &MeterTagSet::Unset, info)?, 0);
+         }
+ 
          _ => {
              return afb_error!(
                  "energy-state-action",
-                 "unsupported action should be (read|subscribe|unsubscribe)"
+                 "unsupported action should be (read|subscribe|unsubscribe|info)"
              )
          }
      }
@@ -404,7 +1351,9 @@
  
  pub(crate) fn register_verbs(api: &mut AfbApi, config: BindingCfg) -> Result<(), AfbError> {
      const ACTIONS: &str = "['read','subscribe','unsubscribe']";
-     const RESET: &str = "['read','subscribe','unsubscribe','reset']";
+     const INFO: &str = "['read','subscribe','unsubscribe','info']";
+     const RESET: &str = "['read','subscribe','unsubscribe','reset','info']";
+     const RESET_ACTIONS: [&str; 5] = ["read", "subscribe", "unsubscribe", "reset", "info"];
      const VOLTS: [&str; 4] = ["Volt-Avr", "Volt-L1", "Volt-L2", "Volt-L3"];
      const CURRENTS: [&str; 4] = ["Amp-Total", "Amp-L1", "Amp-L2", "Amp-L3"];
      const POWER: [&str; 4] = ["Watt-Total", "Watt-L1", "Watt-L2", "Watt-L3"];
@@ -416,28 +1365,23 @@
          "Volt-Avr",
      ];
  
-     let state_event = AfbEvent::new("state");
-     AfbTimer::new("tic-timer")
-         .set_period(config.tic)
-         .set_decount(0)
-         .set_callback(timer_callback)
-         .set_context(TimerCtx{
-             mgr: config.energy_mgr,
-             evt: state_event,
-         })
-         .start()?;
+     let state_event = config.state_event;
+     let tic_timer = TicTimer {
+         mgr: config.energy_mgr,
+         timer: Mutex::new(None),
+     };
+     tic_timer.start(config.energy_mgr.get_tic()?)?;
  
      let state_verb = AfbVerb::new("charging-state")
          .set_name("state")
          .set_info("current charging state (energy)")
-         .set_actions("['read','subscribe','unsubscribe']")?
+         .set_actions(INFO)?
          .set_callback(state_request_cb)
          .set_context(StateRequestCtx{
              mgr: config.energy_mgr,
              evt: state_event,
-             meter_api: config.meter_api,
-             meter_prefix: "SDM72D",
              labels: &GLO_STATE,
+             verbs: MeterVerbs::new(),
          })
          .finalize()?;
  
@@ -452,39 +1396,176 @@
          })
          .finalize()?;
  
+     let health_verb = AfbVerb::new("health-diagnostics")
+         .set_name("health")
+         .set_info("per source events/failures/conflicts, degradation state and active limits")
+         .set_callback(health_request_cb)
+         .set_context(HealthRequestCtx {
+             energy_mgr: config.energy_mgr,
+         })
+         .finalize()?;
+ 
+     let phase_verb = AfbVerb::new("phase-switch")
+         .set_name("phase")
+         .set_info("vehicle 1/3 phase switch request")
+         .set_actions(ACTIONS)?
+         .set_callback(phase_request_cb)
+         .set_context(PhaseRequestCtx {
+             energy_mgr: config.energy_mgr,
+         })
+         .finalize()?;
+ 
+     let stats_verb = AfbVerb::new("rolling-stats")
+         .set_name("stats")
+         .set_info("min/max/mean/last over 1min, 15min and 1h windows")
+         .set_actions("['read','reset']")?
+         .set_callback(stats_request_cb)
+         .set_context(StatsRequestCtx {
+             energy_mgr: config.energy_mgr,
+         })
+         .finalize()?;
+ 
+     let trend_verb = AfbVerb::new("trend-history")
+         .set_name("trend")
+         .set_info("data_set history [start,stop] in ms downsampled to resolution")
+         .add_sample("{'tag':'Power', 'start':0, 'stop':0, 'resolution':60000}")?
+         .set_callback(trend_request_cb)
+         .set_context(TrendRequestCtx {
+             energy_mgr: config.energy_mgr,
+         })
+         .finalize()?;
+ 
+     // OCPP smart charging profiles
+     let profile_verb = AfbVerb::new("profile-install")
+         .set_name("profile")
+         .set_info("install OCPP charging profile (RFC 3339 timestamps), reply installed profile")
+         .add_sample("{'chargingProfileId':1, 'stackLevel':0, 'chargingProfilePurpose':'TxDefaultProfile', 'chargingProfileKind':'Relative', 'chargingSchedule':{'chargingRateUnit':'A', 'chargingSchedulePeriod':[{'startPeriod':0, 'limit':16}]}}")?
+         .add_sample("{'chargingProfileId':2, 'stackLevel':1, 'chargingProfilePurpose':'ChargePointMaxProfile', 'chargingProfileKind':'Recurring', 'recurrencyKind':'Daily', 'validTo':'2030-01-01T00:00:00Z', 'chargingSchedule':{'startSchedule':'2024-01-01T17:00:00+01:00', 'duration':10800, 'chargingRateUnit':'W', 'chargingSchedulePeriod':[{'startPeriod':0, 'limit':3000}]}}")?
+         .set_callback(profile_install_cb)
+         .set_context(ProfileRequestCtx {
+             energy_mgr: config.energy_mgr,
+         })
+         .finalize()?;
+ 
+     let profile_clear_verb = AfbVerb::new("profile-clear")
+         .set_name("profile-clear")
+         .set_info("clear OCPP charging profiles matching id/purpose/stackLevel")
+         .add_sample("{'id':1}")?
+         .add_sample("{'chargingProfilePurpose':'TxProfile'}")?
+         .set_callback(profile_clear_cb)
+         .set_context(ProfileRequestCtx {
+             energy_mgr: config.energy_mgr,
+         })
+         .finalize()?;
+ 
+     let composite_verb = AfbVerb::new("profile-composite")
+         .set_name("composite")
+         .set_info("get composite schedule for duration in seconds")
+         .add_sample("{'duration':86400, 'chargingRateUnit':'W'}")?
+         .set_callback(profile_composite_cb)
+         .set_context(ProfileRequestCtx {
+             energy_mgr: config.energy_mgr,
+         })
+         .finalize()?;
+ 
+     // ISO-15118-2 SAScheduleTuple and -20 PowerSchedule
+     let schedule_verb = AfbVerb::new("pmax-schedule")
+         .set_name("schedule")
+         .set_info("ISO-15118 PMax schedule for duration in seconds (default 24h)")
+         .add_sample("{'duration':86400}")?
+         .set_callback(pmax_schedule_cb)
+         .set_context(ProfileRequestCtx {
+             energy_mgr: config.energy_mgr,
+         })
+         .finalize()?;
+ 
+     // external demand-response limits
+     AfbTimer::new("limit-timer")
+         .set_period(1000)
+         .set_decount(0)
+         .set_callback(limit_timer_cb)
+         .set_context(LimitRequestCtx {
+             energy_mgr: config.energy_mgr,
+         })
+         .start()?;
+ 
+     let limit_verb = AfbVerb::new("external-limit")
+         .set_name("limit")
+         .set_info("temporary power(W)/current(A) cap for duration(s) from source")
+         .add_sample("{'source':'hems', 'power':3000, 'duration':900}")?
+         .add_sample("{'source':'hems', 'heartbeat':true}")?
+         .add_sample("{'source':'hems'}")?
+         .set_callback(limit_request_cb)
+         .set_context(LimitRequestCtx {
+             energy_mgr: config.energy_mgr,
+         })
+         .finalize()?;
+ 
+     // Pv data_set from SunSpec inverter
+     const VB_PV: &str = "pv";
+     let pv_event = AfbEvent::new(VB_PV);
+     if config.pv_period > 0 {
+         AfbTimer::new("pv-timer")
+             .set_period(config.pv_period)
+             .set_decount(0)
+             .set_callback(pv_timer_cb)
+             .set_context(PvCtx {
+                 energy_mgr: config.energy_mgr,
+                 evt: pv_event,
+             })
+             .start()?;
+     }
+ 
+     let pv_verb = AfbVerb::new("pv-production")
+         .set_name(VB_PV)
+         .set_info("pv production power in W, phase current in mA")
+         .set_actions(INFO)?
+         .set_callback(pv_request_cb)
+         .set_context(PvCtx {
+             energy_mgr: config.energy_mgr,
+             evt: pv_event,
+         })
+         .finalize()?;
+ 
      // Tension data_set from eastron modbus meter
      const VB_TENSION: &str = "tension";
-     let tension_set = Rc::new(RefCell::new(MeterDataSet::default(MeterTagSet::Tension)));
+     let tension_subscribers = Arc::new(Mutex::new(MeterSubscribers::new()));
+     let tension_set = new_shared_data_set(MeterTagSet::Tension);
      let tension_event = AfbEvent::new(VB_TENSION);
      let tension_verb = AfbVerb::new("tension-volts")
          .set_name(VB_TENSION)
          .set_info("tension in volt*100")
-         .set_actions(ACTIONS)?
+         .set_actions(INFO)?
          .set_callback(meter_request_cb)
          .set_context(MeterRequestCtx{
+             energy_mgr: config.energy_mgr,
              data_set: tension_set.clone(),
+             subscribers: tension_subscribers.clone(),
              labels: &VOLTS,
-             meter_api: config.meter_api,
-             meter_prefix: "SDM72D",
-             evt: tension_event,
-         })
-         .finalize()?;
- 
-     let tension_handler = AfbEvtHandler::new(VB_TENSION)
-         .set_pattern(to_static_str(format!("{}/Volt*", config.meter_api)))
-         .set_callback(evt_meter_cb)
-         .set_context(MeterEvtCtx{
-             data_set: tension_set.clone(),
+             actions: &INFO_ACTIONS,
+             verbs: MeterVerbs::new(),
+             read_timeout: config.read_timeout,
              evt: tension_event,
-             labels: &VOLTS,
-             meter_api: config.meter_api,
-             energy_mgr: config.energy_mgr,
          })
          .finalize()?;
  
      // Energy data_set from eastron modbus meter
      const VB_ENERGY: &str = "energy";
-     let energy_set = Rc::new(RefCell::new(MeterDataSet::default(MeterTagSet::Energy)));
+     let energy_subscribers = Arc::new(Mutex::new(MeterSubscribers::new()));
+     let energy_set = new_shared_data_set(MeterTagSet::Energy);
+     // a corrupted or unreadable session file should not prevent binding start
+     match config.energy_mgr.restore_session() {
+         Ok(Some(session)) => afb_log_msg!(
+             Notice,
+             api,
+             "restore energy session start:{}Wh lifetime:{}Wh timestamp:{}",
+             session.start,
+             session.lifetime,
+             session.timestamp
+         ),
+         Ok(None) => {}
+         Err(error) => afb_log_msg!(Warning, api, "ignore saved energy session, starting fresh error:{}", error),
+     }
      let energy_event = AfbEvent::new(VB_ENERGY);
      let energy_verb = AfbVerb::new("Energy-watt")
          .set_name(VB_ENERGY)
@@ -492,99 +1573,120 @@
          .set_actions(RESET)?
          .set_callback(meter_request_cb)
          .set_context(MeterRequestCtx{
+             energy_mgr: config.energy_mgr,
              data_set: energy_set.clone(),
+             subscribers: energy_subscribers.clone(),
              labels: &ENERGY,
-             meter_api: config.meter_api,
-             meter_prefix: "SDM72D",
-             evt: energy_event,
-         })
-         .finalize()?;
- 
-     let energy_handler = AfbEvtHandler::new(VB_ENERGY)
-         .set_pattern(to_static_str(format!("{}/Energy-*", config.meter_api)))
-         .set_callback(evt_meter_cb)
-         .set_context(MeterEvtCtx{
-             data_set: energy_set.clone(),
+             actions: &RESET_ACTIONS,
+             verbs: MeterVerbs::new(),
+             read_timeout: config.read_timeout,
              evt: energy_event,
-             labels: &ENERGY,
-             meter_api: config.meter_api,
-             energy_mgr: config.energy_mgr,
          })
          .finalize()?;
  
      // Current data_set from eastron modbus meter
      const VB_CURRENT: &str = "current";
-     let current_set = Rc::new(RefCell::new(MeterDataSet::default(MeterTagSet::Current)));
+     let current_subscribers = Arc::new(Mutex::new(MeterSubscribers::new()));
+     let current_set = new_shared_data_set(MeterTagSet::Current);
      let current_event = AfbEvent::new(VB_CURRENT);
      let current_verb = AfbVerb::new("current-amps")
          .set_name(VB_CURRENT)
          .set_info("current in amps*100")
-         .set_actions(ACTIONS)?
+         .set_actions(INFO)?
          .set_callback(meter_request_cb)
          .set_context(MeterRequestCtx{
+             energy_mgr: config.energy_mgr,
              data_set: current_set.clone(),
+             subscribers: current_subscribers.clone(),
              labels: &CURRENTS,
-             meter_api: config.meter_api,
-             meter_prefix: "SDM72D",
-             evt: current_event,
-         })
-         .finalize()?;
- 
-     let current_handler = AfbEvtHandler::new(VB_CURRENT)
-         .set_pattern(to_static_str(format!("{}/Amp*", config.meter_api)))
-         .set_callback(evt_meter_cb)
-         .set_context(MeterEvtCtx{
-             data_set: current_set.clone(),
+             actions: &INFO_ACTIONS,
+             verbs: MeterVerbs::new(),
+             read_timeout: config.read_timeout,
              evt: current_event,
-             labels: &CURRENTS,
-             meter_api: config.meter_api,
-             energy_mgr: config.energy_mgr,
          })
          .finalize()?;
  
      // Power data_set from eastron modbus meter
      const VB_POWER: &str = "power";
-     let power_set = Rc::new(RefCell::new(MeterDataSet::default(MeterTagSet::Power)));
+     let power_subscribers = Arc::new(Mutex::new(MeterSubscribers::new()));
+     let power_set = new_shared_data_set(MeterTagSet::Power);
      let power_event = AfbEvent::new(VB_POWER);
      let power_verb = AfbVerb::new("power-Watt")
          .set_name(VB_POWER)
          .set_info("power in Watt*100")
-         .set_actions(ACTIONS)?
+         .set_actions(INFO)?
          .set_callback(meter_request_cb)
          .set_context(MeterRequestCtx{
+             energy_mgr: config.energy_mgr,
              data_set: power_set.clone(),
+             subscribers: power_subscribers.clone(),
              labels: &POWER,
-             meter_api: config.meter_api,
-             meter_prefix: "SDM72D",
+             actions: &INFO_ACTIONS,
+             verbs: MeterVerbs::new(),
+             read_timeout: config.read_timeout,
              evt: power_event,
          })
          .finalize()?;
  
-     let power_handler = AfbEvtHandler::new(VB_POWER)
-         .set_pattern(to_static_str(format!("{}/Watt*", config.meter_api)))
-         .set_callback(evt_meter_cb)
-         .set_context(MeterEvtCtx{
-             data_set: power_set.clone(),
-             evt: power_event,
+     // every meter data_set is fed by one handler on current meter api
+     let meter_api = config.energy_mgr.get_meter()?.api.clone();
+     let meter_sets = Arc::new(vec![
+         MeterEvtSet {
+             data_set: tension_set,
+             subscribers: tension_subscribers,
+             labels: &VOLTS,
+             evt: SharedEvent::new(tension_event),
+         },
+         MeterEvtSet {
+             data_set: energy_set,
+             subscribers: energy_subscribers,
+             labels: &ENERGY,
+             evt: SharedEvent::new(energy_event),
+         },
+         MeterEvtSet {
+             data_set: current_set,
+             subscribers: current_subscribers,
+             labels: &CURRENTS,
+             evt: SharedEvent::new(current_event),
+         },
+         MeterEvtSet {
+             data_set: power_set,
+             subscribers: power_subscribers,
              labels: &POWER,
-             meter_api: config.meter_api,
+             evt: SharedEvent::new(power_event),
+         },
+     ]);
+     let meter_handler = new_meter_handler(&meter_api, config.energy_mgr, meter_sets.clone())?;
+ 
+     let settings_verb = AfbVerb::new("runtime-settings")
+         .set_name("settings")
+         .set_info("change tic/umax/variation/phase/meter live, return effective configuration")
+         .add_sample("{}")?
+         .add_sample("{'tic':10000, 'umax':250, 'variation':2}")?
+         .add_sample("{'phase':1, 'meter_prefix':'SDM630'}")?
+         .add_sample("{'meter_api':'modbus', 'meter_prefix':'SDM630', 'meter_labels':{'Volt-Avr':'Volt-Avg'}}")?
+         .set_callback(settings_request_cb)
+         .set_context(SettingsRequestCtx {
              energy_mgr: config.energy_mgr,
+             linky_api: config.linky_api,
+             tic_timer,
+             meter_sets,
+             meter_apis: Mutex::new(vec![meter_api]),
          })
          .finalize()?;
  
      // Over current data_set from Linky meter
      const OVER_LINKY: &str = "iover";
-     let adps_set = Rc::new(RefCell::new(MeterDataSet::default(
-         MeterTagSet::OverCurrent,
-     )));
+     let adps_set = new_shared_data_set(MeterTagSet::OverCurrent);
  
      let adps_event = AfbEvent::new(OVER_LINKY);
      let adps_verb = AfbVerb::new("over-current")
          .set_name(OVER_LINKY)
          .set_info("current over current(adps) in A")
-         .set_actions(ACTIONS)?
+         .set_actions(INFO)?
          .set_callback(adps_request_cb)
          .set_context(LinkyRqtCtx{
+             energy_mgr: config.energy_mgr,
              data_set: adps_set.clone(),
              linky_api: config.linky_api,
              linky_verb: "ADPS",
@@ -601,35 +1703,133 @@
          })
          .finalize()?;
  
-     // Over current data_set from Linky meter
+     // Available current per phase from Linky apparent power
      const AVAIL_LINKY: &str = "iavail";
-     let avail_set = Rc::new(RefCell::new(MeterDataSet::default(
-         MeterTagSet::AvailCurrent,
-     )));
- 
      let iavail_event = AfbEvent::new(AVAIL_LINKY);
      let iavail_verb = AfbVerb::new("avail-current")
          .set_name(AVAIL_LINKY)
-         .set_info("current available (sinsts) in VA")
-         .set_actions(ACTIONS)?
-         .set_callback(adps_request_cb)
-         .set_context(LinkyRqtCtx {
-             data_set: avail_set.clone(),
+         .set_info("current available per phase (pcoup-margin-sinsts) in mA")
+         .set_actions(INFO)?
+         .set_callback(iavail_request_cb)
+         .set_context(LinkyAvailCtx {
+             energy_mgr: config.energy_mgr,
              linky_api: config.linky_api,
-             linky_verb: "SINSTS",
              evt: iavail_event,
          })
          .finalize()?;
      let iavail_handler = AfbEvtHandler::new(AVAIL_LINKY)
-         .set_pattern(to_static_str(format!("{}/SINSTS", config.linky_api)))
+         .set_pattern(to_static_str(format!("{}/SINSTS*", config.linky_api)))
          .set_callback(evt_iavail_cb)
-         .set_context(LinkyAvailEvtCtx{
-             data_set: avail_set.clone(),
+         .set_context(LinkyAvailCtx {
+             energy_mgr: config.energy_mgr,
+             linky_api: config.linky_api,
              evt: iavail_event,
+         })
+         .finalize()?;
+ 
+     // Injected power/energy from Linky meter on producer installations
+     const INJECTION_LINKY: &str = "injection";
+     let injection_set = new_shared_data_set(MeterTagSet::Injection);
+     let injection_event = AfbEvent::new(INJECTION_LINKY);
+     let injection_verb = AfbVerb::new("injection-power")
+         .set_name(INJECTION_LINKY)
+         .set_info("power injected to grid (sinsti) in VA*1000")
+         .set_actions(INFO)?
+         .set_callback(injection_request_cb)
+         .set_context(LinkyInjectionCtx {
              energy_mgr: config.energy_mgr,
+             data_set: injection_set.clone(),
+             linky_api: config.linky_api,
+             evt: injection_event,
+         })
+         .finalize()?;
+     let sinsti_handler = AfbEvtHandler::new("injection-power")
+         .set_pattern(to_static_str(format!("{}/SINSTI", config.linky_api)))
+         .set_callback(evt_injection_cb)
+         .set_context(LinkyInjectionCtx {
+             energy_mgr: config.energy_mgr,
+             data_set: injection_set.clone(),
+             linky_api: config.linky_api,
+             evt: injection_event,
+         })
+         .finalize()?;
+     let eait_handler = AfbEvtHandler::new("injection-energy")
+         .set_pattern(to_static_str(format!("{}/EAIT", config.linky_api)))
+         .set_callback(evt_injection_cb)
+         .set_context(LinkyInjectionCtx {
+             energy_mgr: config.energy_mgr,
+             data_set: injection_set.clone(),
+             linky_api: config.linky_api,
+             evt: injection_event,
+         })
+         .finalize()?;
+ 
+     // Linky contract (subscribed power/tension), event on PCOUP change
+     let contract_verb = AfbVerb::new("linky-contract")
+         .set_name("contract")
+         .set_info("linky subscribed power (kVA) and tension (V)")
+         .set_actions(ACTIONS)?
+         .set_callback(contract_request_cb)
+         .set_context(ContractRequestCtx {
+             energy_mgr: config.energy_mgr,
+             evt: config.contract_event,
+         })
+         .finalize()?;
+ 
+     api.add_event(config.contract_event);
+     api.add_verb(contract_verb);
+ 
+     // Tempo/EJP day color from Linky meter
+     const TEMPO_LINKY: &str = "tempo";
+     let tempo_event = AfbEvent::new(TEMPO_LINKY);
+     let tempo_verb = AfbVerb::new("tempo-color")
+         .set_name(TEMPO_LINKY)
+         .set_info("tempo/ejp today and tomorrow color with active peak policy")
+         .set_actions(ACTIONS)?
+         .set_callback(tempo_request_cb)
+         .set_context(TempoCtx {
+             energy_mgr: config.energy_mgr,
+             evt: tempo_event,
+         })
+         .finalize()?;
+     let tempo_override_verb = AfbVerb::new("tempo-override")
+         .set_name("tempo-override")
+         .set_info("ignore tempo peak policy until next tempo day (06:00) or day color")
+         .add_sample("{'active':true}")?
+         .set_callback(tempo_override_cb)
+         .set_context(TempoCtx {
+             energy_mgr: config.energy_mgr,
+             evt: tempo_event,
+         })
+         .finalize()?;
+     let stge_handler = AfbEvtHandler::new("tempo-status")
+         .set_pattern(to_static_str(format!("{}/STGE", config.linky_api)))
+         .set_callback(evt_tempo_cb)
+         .set_context(TempoCtx {
+             energy_mgr: config.energy_mgr,
+             evt: tempo_event,
+         })
+         .finalize()?;
+     let ltarf_handler = AfbEvtHandler::new("tempo-tariff")
+         .set_pattern(to_static_str(format!("{}/LTARF", config.linky_api)))
+         .set_callback(evt_tempo_cb)
+         .set_context(TempoCtx {
+             energy_mgr: config.energy_mgr,
+             evt: tempo_event,
          })
          .finalize()?;
  
+     api.add_event(tempo_event);
+     api.add_evt_handler(stge_handler);
+     api.add_evt_handler(ltarf_handler);
+     api.add_verb(tempo_verb);
+     api.add_verb(tempo_override_verb);
+ 
+     api.add_event(injection_event);
+     api.add_evt_handler(sinsti_handler);
+     api.add_evt_handler(eait_handler);
+     api.add_verb(injection_verb);
+ 
      api.add_event(adps_event);
      api.add_evt_handler(adps_handler);
      api.add_verb(adps_verb);
@@ -642,23 +1842,33 @@
      api.add_verb(state_verb);
  
      // register event and verbs
+     api.add_evt_handler(meter_handler);
      api.add_event(tension_event);
-     api.add_evt_handler(tension_handler);
      api.add_verb(tension_verb);
  
      api.add_event(energy_event);
-     api.add_evt_handler(energy_handler);
      api.add_verb(energy_verb);
  
      api.add_event(current_event);
-     api.add_evt_handler(current_handler);
      api.add_verb(current_verb);
  
      api.add_event(power_event);
-     api.add_evt_handler(power_handler);
      api.add_verb(power_verb);
  
+     api.add_event(pv_event);
+     api.add_verb(pv_verb);
+ 
      api.add_verb(config_verb);
+     api.add_verb(settings_verb);
+     api.add_verb(health_verb);
+     api.add_verb(phase_verb);
+     api.add_verb(stats_verb);
+     api.add_verb(trend_verb);
+     api.add_verb(profile_verb);
+     api.add_verb(profile_clear_verb);
+     api.add_verb(composite_verb);
+     api.add_verb(schedule_verb);
+     api.add_verb(limit_verb);
  
      Ok(())
  }
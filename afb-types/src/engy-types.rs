@@ -11,10 +11,11 @@
  */
 use afbv4::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use  std::time::Duration;
 
 AfbDataConverter!(meter_tag_set, MeterTagSet);
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub enum MeterTagSet {
     Current,
     Tension,
@@ -22,6 +23,8 @@ pub enum MeterTagSet {
     OverCurrent,
     AvailCurrent,
     Energy,
+    Pv,
+    Injection,
     #[default]
     Unset,
 }
@@ -107,6 +110,471 @@ impl MeterDataSet {
 }
 }
 
+// source degradation, ordered from best to worst
+AfbDataConverter!(health_state, HealthState);
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthState {
+    Ok,
+    #[default]
+    Unknown,
+    Stale,
+    Failing,
+}
+
+// per source (meter api, linky api, sunspec) counters, timestamps in unix ms (0 = never)
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SourceHealthSet {
+    pub source: String,
+    pub state: HealthState,
+    pub events: u64,
+    pub last_event: u64,
+    pub failures: u64,
+    pub last_error: String,
+    pub conflicts: u64,
+}
+
+// active cap per origin, imax in mA, pmax in W (0 = no cap)
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct LimitCapSet {
+    pub origin: String,
+    pub imax: i32,
+    pub pmax: i32,
+}
+
+// effective imax (mA) and pmax (W) with the origin enforcing them
+AfbDataConverter!(health_set, HealthSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HealthSet {
+    pub state: HealthState,
+    pub sources: Vec<SourceHealthSet>,
+    pub limits: Vec<LimitCapSet>,
+    pub imax: i32,
+    pub imax_origin: String,
+    pub pmax: i32,
+    pub pmax_origin: String,
+}
+
+// last known data_set returned on subscribe, age in ms since it was received
+AfbDataConverter!(retained_data_set, RetainedDataSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RetainedDataSet {
+    pub data_set: MeterDataSet,
+    pub age: u64,
+}
+
+// subscribe reply of meter, iover and iavail verbs, event carrying updates (one per distinct filter) and
+// last known data_set when any
+AfbDataConverter!(meter_subscribe_set, MeterSubscribeSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct MeterSubscribeSet {
+    pub event: String,
+    #[serde(flatten)]
+    pub retained: Option<RetainedDataSet>,
+}
+
+// filtered meter update, phases not selected by the filter are omitted
+AfbDataConverter!(meter_filter_set, MeterFilterSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct MeterFilterSet {
+    pub tag: MeterTagSet,
+    pub total: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub l1: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub l2: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub l3: Option<i32>,
+}
+
+// verb capabilities returned by 'info' action. scale is the factor applied to values in unit
+// (1000 = milli unit), updated is unix time in ms of last update (0 = never)
+AfbDataConverter!(verb_info_set, VerbInfoSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct VerbInfoSet {
+    pub verb: String,
+    pub api: String,
+    pub prefix: String,
+    pub labels: Vec<String>,
+    pub unit: String,
+    pub scale: i32,
+    pub variation: i32,
+    pub filters: Vec<MeterFilterRequest>,
+    pub updated: u64,
+    pub actions: Vec<String>,
+}
+
+// optional subscribe arguments of meter verbs, interval in ms, deadband in data_set unit,
+// phases selects l1..l3 (empty = all). Default values keep plain subscription.
+AfbDataConverter!(meter_filter_request, MeterFilterRequest);
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct MeterFilterRequest {
+    pub interval: u32,
+    pub deadband: i32,
+    pub phases: Vec<u32>,
+}
+
+// 1/3 phase switch request send to charger binding
+AfbDataConverter!(phase_switch_set, PhaseSwitchSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct PhaseSwitchSet {
+    pub phases: u32,
+    pub power: i32,
+}
+
+// rolling statistics, window is in seconds
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct StatsValue {
+    pub min: i32,
+    pub max: i32,
+    pub mean: i32,
+    pub last: i32,
+    pub count: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct StatsWindowSet {
+    pub window: u32,
+    pub total: StatsValue,
+    pub l1: StatsValue,
+    pub l2: StatsValue,
+    pub l3: StatsValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct MeterStatsSet {
+    pub tag: MeterTagSet,
+    pub windows: Vec<StatsWindowSet>,
+}
+
+AfbDataConverter!(energy_stats_set, EnergyStatsSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct EnergyStatsSet {
+    pub meters: Vec<MeterStatsSet>,
+}
+
+// history samples, timestamps in ms since epoch, resolution in ms
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct TrendSample {
+    pub timestamp: u64,
+    pub total: i32,
+    pub l1: i32,
+    pub l2: i32,
+    pub l3: i32,
+}
+
+AfbDataConverter!(trend_request, TrendRequest);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct TrendRequest {
+    pub tag: MeterTagSet,
+    #[serde(default)]
+    pub start: u64,
+    #[serde(default)]
+    pub stop: u64,
+    #[serde(default)]
+    pub resolution: u64,
+}
+
+AfbDataConverter!(trend_set, TrendSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct TrendSet {
+    pub tag: MeterTagSet,
+    pub resolution: u64,
+    pub samples: Vec<TrendSample>,
+}
+
+// OCPP-1.6/2.0.1 smart charging profiles, timestamps are RFC 3339 dateTime (unix seconds internally)
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ChargingProfilePurpose {
+    ChargePointMaxProfile,
+    #[default]
+    TxDefaultProfile,
+    TxProfile,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ChargingProfileKind {
+    #[default]
+    Absolute,
+    Recurring,
+    Relative,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RecurrencyKind {
+    Daily,
+    Weekly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ChargingRateUnit {
+    #[default]
+    W,
+    A,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChargingSchedulePeriod {
+    pub start_period: u32,
+    pub limit: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub number_phases: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChargingSchedule {
+    #[serde(default)]
+    pub duration: Option<u32>,
+    #[serde(default, with = "crate::rfc3339::option", skip_serializing_if = "Option::is_none")]
+    pub start_schedule: Option<u64>,
+    pub charging_rate_unit: ChargingRateUnit,
+    pub charging_schedule_period: Vec<ChargingSchedulePeriod>,
+}
+
+AfbDataConverter!(charging_profile, ChargingProfile);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChargingProfile {
+    pub charging_profile_id: i32,
+    pub stack_level: u32,
+    pub charging_profile_purpose: ChargingProfilePurpose,
+    pub charging_profile_kind: ChargingProfileKind,
+    #[serde(default)]
+    pub recurrency_kind: Option<RecurrencyKind>,
+    #[serde(default, with = "crate::rfc3339::option", skip_serializing_if = "Option::is_none")]
+    pub valid_from: Option<u64>,
+    #[serde(default, with = "crate::rfc3339::option", skip_serializing_if = "Option::is_none")]
+    pub valid_to: Option<u64>,
+    pub charging_schedule: ChargingSchedule,
+}
+
+// clear profiles matching every given criteria (none=all)
+AfbDataConverter!(clear_profile_request, ClearProfileRequest);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ClearProfileRequest {
+    #[serde(default)]
+    pub id: Option<i32>,
+    #[serde(default)]
+    pub charging_profile_purpose: Option<ChargingProfilePurpose>,
+    #[serde(default)]
+    pub stack_level: Option<u32>,
+}
+
+AfbDataConverter!(composite_request, CompositeRequest);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CompositeRequest {
+    pub duration: u32,
+    #[serde(default)]
+    pub charging_rate_unit: ChargingRateUnit,
+}
+
+AfbDataConverter!(composite_schedule, CompositeSchedule);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CompositeSchedule {
+    #[serde(with = "crate::rfc3339")]
+    pub start_schedule: u64,
+    pub duration: u32,
+    pub charging_rate_unit: ChargingRateUnit,
+    pub charging_schedule_period: Vec<ChargingSchedulePeriod>,
+}
+
+// ISO-15118 power schedule, durations in seconds, value=value*10^multiplier
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PhysicalValue {
+    pub multiplier: i8,
+    pub unit: String,
+    pub value: i16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PMaxScheduleEntry {
+    pub start: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u32>,
+    pub pmax: PhysicalValue,
+}
+
+// ISO-15118-2 SAScheduleTuple
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SaScheduleTuple {
+    pub sa_schedule_tuple_id: u8,
+    pub pmax_schedule: Vec<PMaxScheduleEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RationalNumber {
+    pub exponent: i8,
+    pub value: i16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PowerScheduleEntry {
+    pub duration: u32,
+    pub power: RationalNumber,
+}
+
+// ISO-15118-20 PowerSchedule, time anchor in unix seconds
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PowerSchedule {
+    pub time_anchor: u64,
+    pub power_schedule_entries: Vec<PowerScheduleEntry>,
+}
+
+AfbDataConverter!(pmax_schedule_request, PMaxScheduleRequest);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PMaxScheduleRequest {
+    #[serde(default)]
+    pub duration: u32,
+}
+
+AfbDataConverter!(pmax_schedule_set, PMaxScheduleSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PMaxScheduleSet {
+    pub iso2: SaScheduleTuple,
+    pub iso20: PowerSchedule,
+}
+
+// external demand-response cap, power in W, current in A, duration in s (0=until cleared)
+AfbDataConverter!(limit_request, LimitRequest);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct LimitRequest {
+    pub source: String,
+    #[serde(default)]
+    pub power: i32,
+    #[serde(default)]
+    pub current: i32,
+    #[serde(default)]
+    pub duration: u32,
+    #[serde(default)]
+    pub heartbeat: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ExternalLimit {
+    pub source: String,
+    pub power: i32,
+    pub current: i32,
+    pub remaining: u32,
+    pub failsafe: bool,
+}
+
+// effective imax in A and pmax in W with active external limits
+AfbDataConverter!(external_limit_set, ExternalLimitSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ExternalLimitSet {
+    pub imax: i32,
+    pub pmax: i32,
+    pub limits: Vec<ExternalLimit>,
+}
+
+// Tempo day color as announced by linky STGE status word
+AfbDataConverter!(tempo_color, TempoColor);
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TempoColor {
+    #[default]
+    Unknown,
+    Blue,
+    White,
+    Red,
+}
+
+// peak is true during HP hours or EJP mobile peak, pmax in W when a policy applies
+AfbDataConverter!(tempo_set, TempoSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct TempoSet {
+    pub today: TempoColor,
+    pub tomorrow: TempoColor,
+    pub tariff: String,
+    pub peak: bool,
+    pub mobile_peak: bool,
+    pub overridden: bool,
+    pub pmax: Option<i32>,
+}
+
+// ignore tempo policy until next day color change
+AfbDataConverter!(tempo_override, TempoOverride);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct TempoOverride {
+    pub active: bool,
+}
+
+// linky subscribed power (PCOUP) in kVA and tension (URMS) in V
+AfbDataConverter!(contract_set, ContractSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ContractSet {
+    pub pcoup: i32,
+    pub urms: i32,
+}
+
+AfbDataConverter!(contract_change_set, ContractChangeSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ContractChangeSet {
+    pub previous: ContractSet,
+    pub current: ContractSet,
+}
+
+// energy session persisted across restarts, energy in Wh: start is lifetime energy at
+// session reset, index the meter index and serial the meter serial number when saved
+AfbDataConverter!(session_set, SessionSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct SessionSet {
+    pub start: i64,
+    pub timestamp: u64,
+    pub lifetime: i64,
+    pub index: i64,
+    pub saved: u64,
+    pub serial: Option<String>,
+}
+
+// runtime settings update, only provided keys are changed. tic in ms,
+// variation in % for meter notification and meter_prefix the device within meter_api
+AfbDataConverter!(settings_request, SettingsRequest);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct SettingsRequest {
+    pub imax: Option<i32>,
+    pub pmax: Option<i32>,
+    pub umax: Option<i32>,
+    pub phase: Option<i32>,
+    pub tic: Option<u32>,
+    pub variation: Option<i32>,
+    pub meter_api: Option<String>,
+    pub meter_prefix: Option<String>,
+    pub meter_labels: Option<BTreeMap<String, String>>,
+}
+
+// full effective configuration, imax in A, pmax in kW, umax in V
+AfbDataConverter!(settings_set, SettingsSet);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SettingsSet {
+    pub imax: i32,
+    pub pmax: i32,
+    pub umax: i32,
+    pub phase: i32,
+    pub tic: u32,
+    pub variation: i32,
+    pub margin: i32,
+    pub meter_api: String,
+    pub meter_prefix: String,
+    pub meter_labels: BTreeMap<String, String>,
+}
+
 AfbDataConverter!(energy_actions, EnergyAction);
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(rename_all = "lowercase", tag = "action")]
@@ -127,16 +595,24 @@ pub struct EnergyState {
     pub subscription_max: i32,
     #[serde(skip)]
     pub umax: i32,
-    #[serde(skip)]
+    // public data, effective caps imax in mA and pmax in W
     pub imax: i32,
-    #[serde(skip)]
     pub pmax: i32,
-    // public data
     pub timestamp: Duration,
     pub session: i32,
     pub current: i32,
     pub tension: i32,
     pub power: i32,
+    // pv production power in W and lifetime energy in Wh
+    pub pv: i32,
+    pub pv_energy: i32,
+    // linky injection (SINSTI in VA*1000, EAIT in Wh) for producer installations
+    pub injection: i32,
+    pub injection_energy: i32,
+    // monotonic meter energy in Wh whatever rollover/replacement
+    pub lifetime: i64,
+    // incremented on every state event, a gap means missed events
+    pub sequence: u64,
 }
 
 impl EnergyState {
@@ -150,6 +626,12 @@ impl EnergyState {
             current: 0,
             tension: 0,
             power: 0,
+            pv: 0,
+            pv_energy: 0,
+            injection: 0,
+            injection_energy: 0,
+            lifetime: 0,
+            sequence: 0,
             timestamp: Duration::new(0,0),
         }
     }
@@ -161,5 +643,32 @@ pub fn engy_registers() -> Result<(), AfbError> {
     energy_actions::register()?;
     energy_state::register()?;
     meter_tag_set::register()?;
+    phase_switch_set::register()?;
+    energy_stats_set::register()?;
+    trend_request::register()?;
+    trend_set::register()?;
+    charging_profile::register()?;
+    clear_profile_request::register()?;
+    composite_request::register()?;
+    composite_schedule::register()?;
+    pmax_schedule_request::register()?;
+    pmax_schedule_set::register()?;
+    limit_request::register()?;
+    external_limit_set::register()?;
+    tempo_color::register()?;
+    tempo_set::register()?;
+    tempo_override::register()?;
+    contract_set::register()?;
+    contract_change_set::register()?;
+    session_set::register()?;
+    settings_request::register()?;
+    settings_set::register()?;
+    meter_filter_request::register()?;
+    retained_data_set::register()?;
+    meter_subscribe_set::register()?;
+    meter_filter_set::register()?;
+    verb_info_set::register()?;
+    health_state::register()?;
+    health_set::register()?;
     Ok(())
 }
@@ -21,6 +21,10 @@ extern crate afbv4;
 #[path = "engy-types.rs"]
 mod engy;
 
+#[path = "rfc3339.rs"]
+mod rfc3339;
+
 pub mod prelude {
     pub use crate::engy::*;
+    pub use crate::rfc3339::{rfc3339_from_secs, rfc3339_to_secs};
 }
\ No newline at end of file
@@ -0,0 +1,183 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+// OCPP dateTime (RFC 3339) <-> unix seconds, without pulling a calendar crate
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+const DAY_SECS: i64 = 24 * 3600;
+
+// days since 1970-01-01 for a proleptic gregorian date (H.Hinnant algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// always formatted in UTC, ex: 2024-03-01T06:00:00Z
+pub fn rfc3339_from_secs(secs: u64) -> String {
+    let secs = secs as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(DAY_SECS));
+    let time = secs.rem_euclid(DAY_SECS);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+// accept 'Z' or +hh:mm offset, fractional seconds are dropped
+pub fn rfc3339_to_secs(value: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid RFC 3339 date:'{}'", value);
+    let bytes = value.as_bytes();
+    if bytes.len() < 20 || bytes[4] != b'-' || bytes[7] != b'-' || bytes[13] != b':' || bytes[16] != b':' {
+        return Err(invalid());
+    }
+    if !matches!(bytes[10], b'T' | b't' | b' ') {
+        return Err(invalid());
+    }
+    let number = |from: usize, to: usize| -> Result<i64, String> {
+        let field = value.get(from..to).ok_or_else(invalid)?;
+        if !field.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        field.parse::<i64>().map_err(|_| invalid())
+    };
+
+    let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+    let (hour, minute, second) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return Err(invalid());
+    }
+
+    let mut idx = 19;
+    if bytes[idx] == b'.' {
+        idx += 1;
+        while idx < bytes.len() && bytes[idx].is_ascii_digit() {
+            idx += 1;
+        }
+    }
+    let offset = match value.get(idx..) {
+        Some("Z") | Some("z") => 0,
+        Some(zone) if zone.len() == 6 && zone.as_bytes()[3] == b':' => {
+            let sign = match zone.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return Err(invalid()),
+            };
+            sign * (number(idx + 1, idx + 3)? * 3600 + number(idx + 4, idx + 6)? * 60)
+        }
+        _ => return Err(invalid()),
+    };
+
+    let secs = days_from_civil(year, month, day) * DAY_SECS + hour * 3600 + minute * 60 + second - offset;
+    if secs < 0 {
+        return Err(invalid());
+    }
+    Ok(secs as u64)
+}
+
+// serde 'with' helpers for u64 unix seconds fields
+pub fn serialize<S: Serializer>(secs: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&rfc3339_from_secs(*secs))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    rfc3339_to_secs(&value).map_err(D::Error::custom)
+}
+
+// same for optional fields, use with #[serde(default)]
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(secs: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match secs {
+            Some(value) => serializer.serialize_str(&rfc3339_from_secs(*value)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(value) => rfc3339_to_secs(&value).map(Some).map_err(D::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format() {
+        assert_eq!(rfc3339_from_secs(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339_from_secs(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339_from_secs(1709272800), "2024-03-01T06:00:00Z");
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(rfc3339_to_secs("1970-01-01T00:00:00Z"), Ok(0));
+        assert_eq!(rfc3339_to_secs("2024-03-01T06:00:00Z"), Ok(1709272800));
+        assert_eq!(rfc3339_to_secs("2024-03-01T07:00:00.250+01:00"), Ok(1709272800));
+        assert_eq!(rfc3339_to_secs("2024-03-01T01:00:00-05:00"), Ok(1709272800));
+        for value in ["2024-03-01", "2024-13-01T00:00:00Z", "2024-03-01T00:00:00", "1969-12-31T23:59:59Z", "2024-03-01T00:00:00+0100"] {
+            assert!(rfc3339_to_secs(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn profile_json() {
+        let profile: crate::engy::ChargingProfile = serde_json::from_str(
+            r#"{"chargingProfileId":1, "stackLevel":0, "chargingProfilePurpose":"TxProfile",
+            "chargingProfileKind":"Absolute", "validFrom":"2024-03-01T07:00:00+01:00",
+            "chargingSchedule":{"startSchedule":"2024-03-01T06:00:00Z", "chargingRateUnit":"W",
+            "chargingSchedulePeriod":[{"startPeriod":0, "limit":3000}]}}"#,
+        )
+        .unwrap();
+        assert_eq!(profile.valid_from, Some(1709272800));
+        assert_eq!(profile.valid_to, None);
+        assert_eq!(profile.charging_schedule.start_schedule, Some(1709272800));
+
+        let value = serde_json::to_value(&profile).unwrap();
+        assert_eq!(value["validFrom"], "2024-03-01T06:00:00Z");
+        assert!(value.get("validTo").is_none());
+    }
+
+    #[test]
+    fn round_trip() {
+        for secs in [0, 86399, 86400, 1709272800, 4102444800] {
+            assert_eq!(rfc3339_to_secs(&rfc3339_from_secs(secs)), Ok(secs));
+        }
+    }
+}
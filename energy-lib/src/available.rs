@@ -0,0 +1,193 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use typesv4::prelude::*;
+
+// apparent power drawn from linky SINSTS (total) and SINSTS1..3 (per phase) in VA,
+// subscription is linky PCOUP in VA and margin a percentage kept as safety
+pub struct AvailablePower {
+    margin: i32,
+    subscription: i32,
+    total: i32,
+    phases: [Option<i32>; 3],
+    measured: bool,
+}
+
+impl AvailablePower {
+    pub fn new(subscription: i32, margin: i32) -> Self {
+        AvailablePower {
+            margin: margin.clamp(0, 100),
+            subscription,
+            total: 0,
+            phases: [None; 3],
+            measured: false,
+        }
+    }
+
+    pub fn set_subscription(&mut self, subscription: i32) {
+        self.subscription = subscription;
+    }
+
+    pub fn get_margin(&self) -> i32 {
+        self.margin
+    }
+
+    // index 0 is total SINSTS, 1..3 per phase SINSTSn
+    pub fn update(&mut self, index: usize, power: i32) {
+        self.measured = true;
+        match index {
+            0 => self.total = power.max(0),
+            1..=3 => self.phases[index - 1] = Some(power.max(0)),
+            _ => {}
+        }
+    }
+
+    fn get_budget(&self) -> i32 {
+        (self.subscription as i64 * (100 - self.margin) as i64 / 100) as i32
+    }
+
+    // power in VA still available on the whole installation
+    pub fn get_power(&self) -> i32 {
+        self.get_budget() - self.total
+    }
+
+    // power in W the charger may use, what it already draws plus installation headroom.
+    // without linky readings the whole budget is for the charger
+    pub fn get_charge_power(&self, charger: i32) -> i32 {
+        match self.measured {
+            true => (self.get_power() + charger).max(0),
+            false => self.get_budget(),
+        }
+    }
+
+    // available current per phase in mA, subscription is shared evenly between phases.
+    // when per phase power is unknown the total is assumed balanced.
+    pub fn get_data_set(&self, phases: i32, tension: i32) -> MeterDataSet {
+        let phases = phases.clamp(1, 3) as usize;
+        let budget = self.get_budget() / phases as i32;
+
+        let mut currents = [0; 3];
+        for (idx, current) in currents.iter_mut().enumerate().take(phases) {
+            let drawn = self.phases[idx].unwrap_or(self.total / phases as i32);
+            *current = ((budget - drawn).max(0) as i64 * 1000 / tension.max(1) as i64) as i32;
+        }
+
+        let mut data_set = MeterDataSet::default(MeterTagSet::AvailCurrent);
+        data_set.total = currents[0..phases].iter().copied().min().unwrap_or(0);
+        data_set.l1 = currents[0];
+        data_set.l2 = currents[1];
+        data_set.l3 = currents[2];
+        data_set.updated = true;
+        data_set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // linky frame as (label, value), PCOUP in kVA, URMS in V, SINSTS in VA
+    fn replay(frames: &[(&str, i32)], margin: i32) -> (AvailablePower, i32) {
+        let mut available = AvailablePower::new(0, margin);
+        let mut tension = 230;
+        for (label, value) in frames {
+            match *label {
+                "PCOUP" => available.set_subscription(value * 1000),
+                "URMS1" => tension = *value,
+                "SINSTS" => available.update(0, *value),
+                "SINSTS1" => available.update(1, *value),
+                "SINSTS2" => available.update(2, *value),
+                "SINSTS3" => available.update(3, *value),
+                _ => panic!("unexpected label:{}", label),
+            }
+        }
+        (available, tension)
+    }
+
+    #[test]
+    fn single_phase() {
+        let (available, tension) = replay(&[("PCOUP", 6), ("URMS1", 230), ("SINSTS", 1200)], 20);
+        // 6kVA - 20% = 4800VA, 3600VA left
+        assert_eq!(available.get_power(), 3600);
+
+        let data_set = available.get_data_set(1, tension);
+        assert_eq!(data_set.tag, MeterTagSet::AvailCurrent);
+        assert_eq!(data_set.l1, 15652);
+        assert_eq!(data_set.total, 15652);
+        assert_eq!((data_set.l2, data_set.l3), (0, 0));
+    }
+
+    #[test]
+    fn three_phases() {
+        let frames = [
+            ("PCOUP", 12),
+            ("URMS1", 240),
+            ("SINSTS", 8000),
+            ("SINSTS1", 1000),
+            ("SINSTS2", 3000),
+            ("SINSTS3", 4000),
+        ];
+        let (available, tension) = replay(&frames, 10);
+        // 12kVA - 10% = 10800VA, 3600VA per phase, phase 3 is over its share
+        let data_set = available.get_data_set(3, tension);
+        assert_eq!((data_set.l1, data_set.l2, data_set.l3), (10833, 2500, 0));
+        assert_eq!(data_set.total, 0);
+    }
+
+    #[test]
+    fn three_phases_balanced() {
+        // meter without SINSTSn, total is shared evenly
+        let (available, tension) = replay(&[("PCOUP", 12), ("URMS1", 230), ("SINSTS", 3000)], 10);
+        let data_set = available.get_data_set(3, tension);
+        assert_eq!((data_set.l1, data_set.l2, data_set.l3), (11304, 11304, 11304));
+        assert_eq!(data_set.total, 11304);
+    }
+
+    #[test]
+    fn margin() {
+        let (available, _) = replay(&[("PCOUP", 9), ("SINSTS", 0)], 0);
+        assert_eq!(available.get_power(), 9000);
+
+        let (available, _) = replay(&[("PCOUP", 9), ("SINSTS", 0)], 50);
+        assert_eq!(available.get_power(), 4500);
+
+        // margin is clamped to 100%, nothing left for charging
+        let (available, tension) = replay(&[("PCOUP", 9), ("SINSTS", 0)], 150);
+        assert_eq!(available.get_margin(), 100);
+        assert_eq!(available.get_data_set(1, tension).total, 0);
+    }
+
+    #[test]
+    fn contract_change() {
+        let (mut available, tension) = replay(&[("PCOUP", 6), ("URMS1", 230), ("SINSTS", 1200)], 0);
+        assert_eq!(available.get_power(), 4800);
+
+        available.set_subscription(9000);
+        available.update(0, 1200);
+        assert_eq!(available.get_power(), 7800);
+        assert_eq!(available.get_data_set(1, tension).total, 33913);
+    }
+
+    #[test]
+    fn charge_power() {
+        // without linky readings charger gets the whole budget
+        let mut available = AvailablePower::new(6000, 20);
+        assert_eq!(available.get_charge_power(2000), 4800);
+
+        // charger draw is part of SINSTS, it is given back to the charger
+        available.update(0, 4000);
+        assert_eq!(available.get_charge_power(2000), 2800);
+
+        available.update(0, 9000);
+        assert_eq!(available.get_charge_power(2000), 0);
+    }
+}
@@ -0,0 +1,203 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use std::time::{Duration, Instant};
+use typesv4::prelude::*;
+
+// power in W, current in A, heartbeat 0 disables failsafe
+// failsafe only tightens source last cap, it never releases it
+pub struct DemandConfig {
+    pub heartbeat: Duration,
+    pub failsafe_power: i32,
+    pub failsafe_current: i32,
+}
+
+impl Default for DemandConfig {
+    fn default() -> Self {
+        DemandConfig {
+            heartbeat: Duration::from_secs(0),
+            failsafe_power: 0,
+            failsafe_current: 0,
+        }
+    }
+}
+
+// most restrictive of two caps where 0 means no cap
+fn min_cap(cap: i32, failsafe: i32) -> i32 {
+    match (cap, failsafe) {
+        (0, value) | (value, 0) => value,
+        (cap, failsafe) => cap.min(failsafe),
+    }
+}
+
+struct DemandLimit {
+    source: String,
+    power: i32,
+    current: i32,
+    expiry: Option<Instant>,
+    seen: Instant,
+}
+
+// temporary caps imposed by HEMS/aggregators, one per source
+pub struct DemandResponse {
+    config: DemandConfig,
+    limits: Vec<DemandLimit>,
+}
+
+impl DemandResponse {
+    pub fn new(config: DemandConfig) -> Self {
+        DemandResponse {
+            config,
+            limits: Vec::new(),
+        }
+    }
+
+    pub fn set(&mut self, request: &LimitRequest, now: Instant) {
+        let position = self.limits.iter().position(|limit| limit.source == request.source);
+
+        // heartbeat only refresh source activity
+        if request.heartbeat {
+            if let Some(idx) = position {
+                self.limits[idx].seen = now;
+            }
+            return;
+        }
+
+        if request.power <= 0 && request.current <= 0 {
+            if let Some(idx) = position {
+                self.limits.remove(idx);
+            }
+            return;
+        }
+
+        let limit = DemandLimit {
+            source: request.source.clone(),
+            power: request.power.max(0),
+            current: request.current.max(0),
+            expiry: match request.duration {
+                0 => None,
+                duration => Some(now + Duration::from_secs(duration as u64)),
+            },
+            seen: now,
+        };
+        match position {
+            Some(idx) => self.limits[idx] = limit,
+            None => self.limits.push(limit),
+        }
+    }
+
+    fn is_failsafe(&self, limit: &DemandLimit, now: Instant) -> bool {
+        !self.config.heartbeat.is_zero() && now.duration_since(limit.seen) > self.config.heartbeat
+    }
+
+    // drop expired limits and return active caps (origin, current A, power W)
+    pub fn check(&mut self, now: Instant) -> Vec<(String, i32, i32)> {
+        self.limits.retain(|limit| match limit.expiry {
+            Some(expiry) => now < expiry,
+            None => true,
+        });
+
+        let mut caps = Vec::new();
+        for limit in &self.limits {
+            if self.is_failsafe(limit, now) {
+                caps.push((
+                    format!("failsafe/{}", limit.source),
+                    min_cap(limit.current, self.config.failsafe_current),
+                    min_cap(limit.power, self.config.failsafe_power),
+                ));
+            } else {
+                caps.push((limit.source.clone(), limit.current, limit.power));
+            }
+        }
+        caps
+    }
+
+    pub fn get_limits(&self, now: Instant) -> Vec<ExternalLimit> {
+        self.limits
+            .iter()
+            .map(|limit| ExternalLimit {
+                source: limit.source.clone(),
+                power: limit.power,
+                current: limit.current,
+                remaining: limit
+                    .expiry
+                    .map_or(0, |expiry| expiry.saturating_duration_since(now).as_secs() as u32),
+                failsafe: self.is_failsafe(limit, now),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(power: i32, current: i32) -> LimitRequest {
+        LimitRequest {
+            source: "hems".to_string(),
+            power,
+            current,
+            ..LimitRequest::default()
+        }
+    }
+
+    fn demand(failsafe_power: i32, failsafe_current: i32) -> DemandResponse {
+        DemandResponse::new(DemandConfig {
+            heartbeat: Duration::from_secs(60),
+            failsafe_power,
+            failsafe_current,
+        })
+    }
+
+    #[test]
+    fn failsafe_tightens_cap() {
+        let now = Instant::now();
+        let mut demand = demand(1400, 6);
+        demand.set(&request(3000, 0), now);
+        assert_eq!(demand.check(now), vec![("hems".to_string(), 0, 3000)]);
+
+        let later = now + Duration::from_secs(61);
+        assert_eq!(demand.check(later), vec![("failsafe/hems".to_string(), 6, 1400)]);
+    }
+
+    #[test]
+    fn failsafe_keeps_lower_cap() {
+        let now = Instant::now();
+        let mut demand = demand(1400, 0);
+        demand.set(&request(1000, 10), now);
+
+        let later = now + Duration::from_secs(61);
+        assert_eq!(demand.check(later), vec![("failsafe/hems".to_string(), 10, 1000)]);
+    }
+
+    #[test]
+    fn failsafe_never_releases() {
+        let now = Instant::now();
+        let mut demand = demand(0, 0);
+        demand.set(&request(3000, 0), now);
+
+        let later = now + Duration::from_secs(61);
+        assert_eq!(demand.check(later), vec![("failsafe/hems".to_string(), 0, 3000)]);
+    }
+
+    #[test]
+    fn heartbeat_and_expiry() {
+        let now = Instant::now();
+        let mut demand = demand(1400, 0);
+        demand.set(&LimitRequest { duration: 100, ..request(3000, 0) }, now);
+
+        let beat = now + Duration::from_secs(50);
+        demand.set(&LimitRequest { heartbeat: true, ..request(0, 0) }, beat);
+        assert_eq!(demand.check(now + Duration::from_secs(90)), vec![("hems".to_string(), 0, 3000)]);
+        assert!(demand.check(now + Duration::from_secs(100)).is_empty());
+    }
+}
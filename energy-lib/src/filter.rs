@@ -0,0 +1,243 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use std::time::{Duration, Instant};
+use typesv4::prelude::*;
+
+// rate limit and deadband for one group of subscribers sharing the same filter
+pub struct MeterFilter {
+    request: MeterFilterRequest,
+    last: Option<(MeterFilterSet, Instant)>,
+}
+
+impl MeterFilter {
+    pub fn new(request: &MeterFilterRequest) -> Self {
+        MeterFilter {
+            request: request.clone(),
+            last: None,
+        }
+    }
+
+    // no argument means every update as with plain subscribe
+    pub fn is_default(request: &MeterFilterRequest) -> bool {
+        *request == MeterFilterRequest::default()
+    }
+
+    // same phases in another order or repeated is the same filter
+    pub fn check_request(request: &MeterFilterRequest) -> Result<MeterFilterRequest, AfbError> {
+        let mut request = request.clone();
+        if let Some(phase) = request.phases.iter().find(|phase| !(1..=3).contains(*phase)) {
+            return afb_error!("meter-filter-phases", "invalid phase:{} should be 1..3", phase);
+        }
+        request.phases.sort_unstable();
+        request.phases.dedup();
+        Ok(request)
+    }
+
+    fn is_selected(&self, phase: u32) -> bool {
+        self.request.phases.is_empty() || self.request.phases.contains(&phase)
+    }
+
+    // unselected phases are omitted
+    fn select(&self, data: &MeterDataSet) -> MeterFilterSet {
+        let phase = |phase: u32, value: i32| if self.is_selected(phase) { Some(value) } else { None };
+        MeterFilterSet {
+            tag: data.tag.clone(),
+            total: data.total,
+            l1: phase(1, data.l1),
+            l2: phase(2, data.l2),
+            l3: phase(3, data.l3),
+        }
+    }
+
+    fn moved(&self, last: &MeterFilterSet, data: &MeterFilterSet) -> bool {
+        let deadband = self.request.deadband.max(0) as i64;
+        let over = |last: i32, value: i32| (value as i64 - last as i64).abs() > deadband;
+        let over_phase = |last: Option<i32>, value: Option<i32>| match (last, value) {
+            (Some(last), Some(value)) => over(last, value),
+            _ => false,
+        };
+        over(last.total, data.total)
+            || over_phase(last.l1, data.l1)
+            || over_phase(last.l2, data.l2)
+            || over_phase(last.l3, data.l3)
+    }
+
+    // return data_set to push when interval elapsed and value moved out of deadband
+    pub fn check(&mut self, data: &MeterDataSet, now: Instant) -> Option<MeterFilterSet> {
+        let data_set = self.select(data);
+        if let Some((last, time)) = &self.last {
+            if now.duration_since(*time) < Duration::from_millis(self.request.interval as u64) {
+                return None;
+            }
+            if !self.moved(last, &data_set) {
+                return None;
+            }
+        }
+        self.last = Some((data_set.clone(), now));
+        Some(data_set)
+    }
+}
+
+// filtered subscriptions of one meter verb, each distinct filter has its own event.
+// events of dropped filters are kept idle and reused, as afb events cannot be deleted
+pub struct MeterSubscribers {
+    count: usize,
+    filters: Vec<(MeterFilter, SharedEvent)>,
+    idle: Vec<SharedEvent>,
+}
+
+impl MeterSubscribers {
+    pub fn new() -> Self {
+        MeterSubscribers {
+            count: 0,
+            filters: Vec::new(),
+            idle: Vec::new(),
+        }
+    }
+
+    // reuse event of an identical filter, then an idle one, return the event name to listen to
+    pub fn subscribe(&mut self, rqt: &AfbRequest, prefix: &str, request: &MeterFilterRequest) -> Result<String, AfbError> {
+        let request = MeterFilter::check_request(request)?;
+        let event = match self.filters.iter().find(|(filter, _)| filter.request == request) {
+            Some((_, event)) => *event,
+            None => {
+                let event = match self.idle.pop() {
+                    Some(event) => event,
+                    None => {
+                        self.count += 1;
+                        let event = AfbEvent::new(to_static_str(format!("{}/{}", prefix, self.count)));
+                        event.register(rqt.get_apiv4())?;
+                        SharedEvent::new(event)
+                    }
+                };
+                self.filters.push((MeterFilter::new(&request), event));
+                event
+            }
+        };
+        event.subscribe(rqt)?;
+        Ok(event.get_uid().to_string())
+    }
+
+    pub fn get_filters(&self) -> Vec<MeterFilterRequest> {
+        self.filters.iter().map(|(filter, _)| filter.request.clone()).collect()
+    }
+
+    // client may not be subscribed to every filtered event
+    pub fn unsubscribe(&self, rqt: &AfbRequest) {
+        for (_, event) in &self.filters {
+            let _ = event.unsubscribe(rqt);
+        }
+    }
+
+    // filters without listener anymore are dropped, their event becomes idle
+    pub fn push(&mut self, data: &MeterDataSet, now: Instant) {
+        let idle = &mut self.idle;
+        self.filters.retain_mut(|(filter, event)| {
+            let listened = match filter.check(data, now) {
+                Some(data_set) => event.push(data_set) > 0,
+                None => true,
+            };
+            if !listened {
+                idle.push(*event);
+            }
+            listened
+        });
+    }
+}
+
+impl Default for MeterSubscribers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn power(total: i32, l1: i32, l2: i32, l3: i32) -> MeterDataSet {
+        MeterDataSet {
+            total,
+            l1,
+            l2,
+            l3,
+            ..MeterDataSet::default(MeterTagSet::Power)
+        }
+    }
+
+    #[test]
+    fn check_request() {
+        let request = MeterFilterRequest {
+            phases: vec![3, 1, 3],
+            ..MeterFilterRequest::default()
+        };
+        assert_eq!(MeterFilter::check_request(&request).unwrap().phases, vec![1, 3]);
+        for phases in [vec![0], vec![1, 4]] {
+            let request = MeterFilterRequest {
+                phases,
+                ..MeterFilterRequest::default()
+            };
+            assert!(MeterFilter::check_request(&request).is_err());
+        }
+    }
+
+    #[test]
+    fn select_phases() {
+        let mut filter = MeterFilter::new(&MeterFilterRequest {
+            phases: vec![1],
+            ..MeterFilterRequest::default()
+        });
+        let data_set = filter.check(&power(3000, 1000, 1000, 1000), Instant::now()).unwrap();
+        assert_eq!((data_set.total, data_set.l1, data_set.l2, data_set.l3), (3000, Some(1000), None, None));
+
+        let value = serde_json::to_value(&data_set).unwrap();
+        assert_eq!(value["l1"], 1000);
+        assert!(value.get("l2").is_none() && value.get("l3").is_none());
+
+        let mut all = MeterFilter::new(&MeterFilterRequest::default());
+        let data_set = all.check(&power(3000, 1000, 0, 2000), Instant::now()).unwrap();
+        assert_eq!((data_set.l1, data_set.l2, data_set.l3), (Some(1000), Some(0), Some(2000)));
+    }
+
+    #[test]
+    fn deadband() {
+        let mut filter = MeterFilter::new(&MeterFilterRequest {
+            deadband: 500,
+            phases: vec![1],
+            ..MeterFilterRequest::default()
+        });
+        let now = Instant::now();
+        assert!(filter.check(&power(3000, 1000, 1000, 1000), now).is_some());
+        assert!(filter.check(&power(3400, 1400, 1000, 1000), now).is_none());
+        // unselected phases never trigger an update
+        assert!(filter.check(&power(3000, 1000, 9000, 9000), now).is_none());
+        assert!(filter.check(&power(3000, 1600, 1000, 1000), now).is_some());
+        assert!(filter.check(&power(3600, 1600, 1000, 1000), now).is_some());
+    }
+
+    #[test]
+    fn interval() {
+        let mut filter = MeterFilter::new(&MeterFilterRequest {
+            interval: 10000,
+            ..MeterFilterRequest::default()
+        });
+        let now = Instant::now();
+        assert!(filter.check(&power(1000, 0, 0, 0), now).is_some());
+        assert!(filter.check(&power(2000, 0, 0, 0), now + Duration::from_millis(9999)).is_none());
+        assert!(filter.check(&power(2000, 0, 0, 0), now + Duration::from_millis(10000)).is_some());
+        // interval elapsed but nothing moved
+        assert!(filter.check(&power(2000, 0, 0, 0), now + Duration::from_millis(30000)).is_none());
+    }
+}
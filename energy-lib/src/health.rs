@@ -0,0 +1,163 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use typesv4::prelude::*;
+
+// all timestamps in unix ms, 0 means never
+#[derive(Default)]
+struct SourceHealth {
+    events: u64,
+    last_event: u64,
+    last_success: u64,
+    failures: u64,
+    last_failure: u64,
+    last_error: String,
+    conflicts: u64,
+}
+
+impl SourceHealth {
+    fn get_state(&self, now: u64, stale: u64) -> HealthState {
+        let last_seen = self.last_event.max(self.last_success);
+        if self.last_failure > last_seen {
+            HealthState::Failing
+        } else if last_seen == 0 {
+            HealthState::Unknown
+        } else if now.saturating_sub(last_seen) > stale {
+            HealthState::Stale
+        } else {
+            HealthState::Ok
+        }
+    }
+}
+
+// what the binding sees from each source, a source is failing when its last subcall
+// failed and stale when nothing was received for 'stale' ms
+pub struct EnergyHealth {
+    stale: u64,
+    sources: Vec<(String, SourceHealth)>,
+}
+
+impl EnergyHealth {
+    pub fn new(stale: u64) -> Self {
+        EnergyHealth {
+            stale,
+            sources: Vec::new(),
+        }
+    }
+
+    fn get_source(&mut self, source: &str) -> &mut SourceHealth {
+        let idx = match self.sources.iter().position(|(name, _)| name == source) {
+            Some(value) => value,
+            None => {
+                self.sources.push((source.to_string(), SourceHealth::default()));
+                self.sources.len() - 1
+            }
+        };
+        &mut self.sources[idx].1
+    }
+
+    pub fn count_event(&mut self, source: &str, now: u64) {
+        let health = self.get_source(source);
+        health.events += 1;
+        health.last_event = now;
+    }
+
+    pub fn count_success(&mut self, source: &str, now: u64) {
+        self.get_source(source).last_success = now;
+    }
+
+    pub fn count_failure(&mut self, source: &str, error: &str, now: u64) {
+        let health = self.get_source(source);
+        health.failures += 1;
+        health.last_failure = now;
+        health.last_error = error.to_string();
+    }
+
+    pub fn count_conflict(&mut self, source: &str) {
+        self.get_source(source).conflicts += 1;
+    }
+
+    pub fn get_sources(&self, now: u64) -> Vec<SourceHealthSet> {
+        self.sources
+            .iter()
+            .map(|(source, health)| SourceHealthSet {
+                source: source.clone(),
+                state: health.get_state(now, self.stale),
+                events: health.events,
+                last_event: health.last_event,
+                failures: health.failures,
+                last_error: health.last_error.clone(),
+                conflicts: health.conflicts,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STALE: u64 = 300_000;
+    const NOW: u64 = 1_709_272_800_000;
+
+    fn state(health: &EnergyHealth, source: &str, now: u64) -> HealthState {
+        match health.get_sources(now).iter().find(|value| value.source == source) {
+            Some(value) => value.state,
+            None => panic!("no source:{}", source),
+        }
+    }
+
+    #[test]
+    fn unknown() {
+        let mut health = EnergyHealth::new(STALE);
+        assert!(health.get_sources(NOW).is_empty());
+        // conflicts only tell the source exists, it was never seen
+        health.count_conflict("modbus");
+        assert_eq!(state(&health, "modbus", NOW), HealthState::Unknown);
+        health.count_event("modbus", NOW);
+        assert_eq!(state(&health, "modbus", NOW), HealthState::Ok);
+    }
+
+    #[test]
+    fn failure_then_success() {
+        let mut health = EnergyHealth::new(STALE);
+        health.count_failure("linky", "timeout", NOW);
+        assert_eq!(state(&health, "linky", NOW), HealthState::Failing);
+
+        health.count_success("linky", NOW + 1000);
+        assert_eq!(state(&health, "linky", NOW + 1000), HealthState::Ok);
+        health.count_failure("linky", "no meter", NOW + 2000);
+        assert_eq!(state(&health, "linky", NOW + 2000), HealthState::Failing);
+        health.count_event("linky", NOW + 3000);
+        assert_eq!(state(&health, "linky", NOW + 3000), HealthState::Ok);
+
+        let sources = health.get_sources(NOW + 3000);
+        assert_eq!((sources[0].failures, sources[0].events), (2, 1));
+        assert_eq!(sources[0].last_error, "no meter");
+    }
+
+    #[test]
+    fn stale() {
+        let mut health = EnergyHealth::new(STALE);
+        health.count_event("modbus", NOW);
+        health.count_success("sunspec", NOW + 1000);
+        assert_eq!(state(&health, "modbus", NOW + STALE), HealthState::Ok);
+        assert_eq!(state(&health, "modbus", NOW + STALE + 1), HealthState::Stale);
+        assert_eq!(state(&health, "sunspec", NOW + STALE + 1), HealthState::Ok);
+
+        // a failure wins over staleness, next event makes the source ok again
+        health.count_failure("modbus", "timeout", NOW + 2 * STALE);
+        assert_eq!(state(&health, "modbus", NOW + 2 * STALE), HealthState::Failing);
+        health.count_event("modbus", NOW + 2 * STALE + 1);
+        assert_eq!(state(&health, "modbus", NOW + 2 * STALE + 1), HealthState::Ok);
+    }
+}
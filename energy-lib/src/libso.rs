@@ -18,9 +18,97 @@
 #[cfg(not(afbv4))]
 extern crate afbv4;
 
+#[path = "available.rs"]
+mod available;
+
+#[path = "demand.rs"]
+mod demand;
+
+#[path = "filter.rs"]
+mod filter;
+
+#[path = "health.rs"]
+mod health;
+
+#[path = "limits.rs"]
+mod limits;
+
 #[path = "manager.rs"]
 mod manager;
 
+#[path = "metrics.rs"]
+mod metrics;
+
+#[path = "mbmap.rs"]
+mod mbmap;
+
+#[path = "modbus.rs"]
+mod modbus;
+
+#[path = "mqtt.rs"]
+mod mqtt;
+
+#[path = "notify.rs"]
+mod notify;
+
+#[path = "phase.rs"]
+mod phase;
+
+#[path = "profile.rs"]
+mod profile;
+
+#[path = "reader.rs"]
+mod reader;
+
+#[path = "schedule.rs"]
+mod schedule;
+
+#[path = "session.rs"]
+mod session;
+
+#[path = "settings.rs"]
+mod settings;
+
+#[path = "shared.rs"]
+mod shared;
+
+#[path = "stats.rs"]
+mod stats;
+
+#[path = "sunspec.rs"]
+mod sunspec;
+
+#[path = "tempo.rs"]
+mod tempo;
+
+#[path = "totalizer.rs"]
+mod totalizer;
+
+#[path = "trend.rs"]
+mod trend;
+
 pub mod prelude {
+    pub use crate::available::*;
+    pub use crate::demand::*;
+    pub use crate::filter::*;
+    pub use crate::health::*;
+    pub use crate::limits::*;
     pub use crate::manager::*;
+    pub use crate::metrics::*;
+    pub use crate::mbmap::*;
+    pub use crate::modbus::*;
+    pub use crate::mqtt::*;
+    pub use crate::notify::*;
+    pub use crate::phase::*;
+    pub use crate::profile::*;
+    pub use crate::reader::*;
+    pub use crate::schedule::*;
+    pub use crate::session::*;
+    pub use crate::settings::*;
+    pub use crate::shared::*;
+    pub use crate::stats::*;
+    pub use crate::sunspec::*;
+    pub use crate::tempo::*;
+    pub use crate::totalizer::*;
+    pub use crate::trend::*;
 }
\ No newline at end of file
@@ -0,0 +1,91 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+// imax in mA per phase, pmax in W, 0 means no cap
+#[derive(Clone, Debug)]
+pub struct LimitCap {
+    pub origin: String,
+    pub imax: i32,
+    pub pmax: i32,
+}
+
+// every source (cable, backend, profile, ...) registers its own cap,
+// the effective limit is the most restrictive one.
+pub struct EnergyLimits {
+    caps: Vec<LimitCap>,
+}
+
+impl EnergyLimits {
+    pub fn new() -> Self {
+        EnergyLimits { caps: Vec::new() }
+    }
+
+    pub fn set(&mut self, origin: &str, imax: i32, pmax: i32) {
+        if imax <= 0 && pmax <= 0 {
+            self.clear(origin);
+            return;
+        }
+        let cap = LimitCap {
+            origin: origin.to_string(),
+            imax: imax.max(0),
+            pmax: pmax.max(0),
+        };
+        match self.caps.iter_mut().find(|cap| cap.origin == origin) {
+            Some(value) => *value = cap,
+            None => self.caps.push(cap),
+        }
+    }
+
+    pub fn clear(&mut self, origin: &str) {
+        self.caps.retain(|cap| cap.origin != origin);
+    }
+
+    pub fn clear_prefix(&mut self, prefix: &str) {
+        self.caps.retain(|cap| !cap.origin.starts_with(prefix));
+    }
+
+    pub fn get_caps(&self) -> &[LimitCap] {
+        &self.caps
+    }
+
+    // return effective imax and the origin enforcing it
+    pub fn get_imax(&self, imax: i32) -> (i32, &str) {
+        self.caps
+            .iter()
+            .filter(|cap| cap.imax > 0 && cap.imax < imax)
+            .min_by_key(|cap| cap.imax)
+            .map_or((imax, "config"), |cap| (cap.imax, cap.origin.as_str()))
+    }
+
+    // return effective pmax and the origin enforcing it
+    pub fn get_pmax(&self, pmax: i32) -> (i32, &str) {
+        self.caps
+            .iter()
+            .filter(|cap| cap.pmax > 0 && cap.pmax < pmax)
+            .min_by_key(|cap| cap.pmax)
+            .map_or((pmax, "config"), |cap| (cap.pmax, cap.origin.as_str()))
+    }
+
+    // effective pmax ignoring one origin
+    pub fn get_pmax_except(&self, pmax: i32, origin: &str) -> i32 {
+        self.caps
+            .iter()
+            .filter(|cap| cap.origin != origin && cap.pmax > 0)
+            .fold(pmax, |pmax, cap| pmax.min(cap.pmax))
+    }
+}
+
+impl Default for EnergyLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -10,17 +10,50 @@
  *
  */
 
+use crate::prelude::*;
 use afbv4::prelude::*;
-use std::sync::{Mutex, MutexGuard};
-use std::time::SystemTime;
+use std::collections::BTreeSet;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
 use typesv4::prelude::*;
 
+// source without event or successful subcall for 5mn is reported stale
+const HEALTH_STALE: u64 = 300_000;
+
+fn get_unix_time() -> Result<Duration, AfbError> {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(value) => Ok(value),
+        Err(_) => afb_error!("energy-unix-time", "system time before UNIX EPOCH!"),
+    }
+}
+
 pub struct ManagerHandle {
     data_set: Mutex<EnergyState>,
-    event: &'static AfbEvent,
+    event: SharedEvent,
+    phase_switch: Mutex<Option<PhaseSwitch>>,
+    phase_event: Option<SharedEvent>,
+    state_event: Option<SharedEvent>,
+    stats: Mutex<EnergyStats>,
+    trend: Mutex<TrendBuffer>,
+    counters: Mutex<EnergyCounters>,
+    mqtt: Mutex<Option<MqttBridge>>,
+    limits: Mutex<EnergyLimits>,
+    profiles: Mutex<ProfileStore>,
+    tariffs: Vec<TariffWindow>,
+    demand: Mutex<DemandResponse>,
+    sunspec: Mutex<Option<SunSpecReader>>,
+    tempo: Mutex<Option<TempoTracker>>,
+    contract: Mutex<ContractSet>,
+    available: Mutex<AvailablePower>,
+    session: Option<SessionStore>,
+    totalizer: Mutex<EnergyTotalizer>,
+    settings: Mutex<EnergySettings>,
+    meter_labels: Mutex<BTreeSet<String>>,
+    notifier: Mutex<StateNotifier>,
+    health: Mutex<EnergyHealth>,
     imax: i32,
     pmax: i32,
-    phase: i32,
 }
 
 impl ManagerHandle {
@@ -30,20 +63,126 @@ impl ManagerHandle {
         let umax = umax * 1000;
         let handle = ManagerHandle {
             data_set: Mutex::new(EnergyState::default(imax, pmax, umax)),
-            event,
+            event: SharedEvent::new(event),
+            phase_switch: Mutex::new(None),
+            phase_event: None,
+            state_event: None,
+            stats: Mutex::new(EnergyStats::new()),
+            trend: Mutex::new(TrendBuffer::new(0)),
+            counters: Mutex::new(EnergyCounters::new()),
+            mqtt: Mutex::new(None),
+            limits: Mutex::new(EnergyLimits::new()),
+            profiles: Mutex::new(ProfileStore::new(phase as u32)),
+            tariffs: Vec::new(),
+            demand: Mutex::new(DemandResponse::new(DemandConfig::default())),
+            sunspec: Mutex::new(None),
+            tempo: Mutex::new(None),
+            contract: Mutex::new(ContractSet::default()),
+            // without linky, subscription is the configured pmax
+            available: Mutex::new(AvailablePower::new(pmax, 20)),
+            session: None,
+            totalizer: Mutex::new(EnergyTotalizer::new(0, pmax as i64)),
+            settings: Mutex::new(EnergySettings::new(phase)),
+            meter_labels: Mutex::new(BTreeSet::new()),
+            notifier: Mutex::new(StateNotifier::new(StateDeltas::default(), Duration::from_secs(60))),
+            health: Mutex::new(EnergyHealth::new(HEALTH_STALE)),
             imax: imax,
             pmax: pmax,
-            phase,
         };
 
         // return a static handle to prevent Rust from complaining when moving/sharing it
         Box::leak(Box::new(handle))
     }
 
+    // enable automatic 1/3 phase switching, requests are pushed on 'event'
+    pub fn set_phase_switch(&mut self, config: PhaseSwitchConfig, event: &'static AfbEvent) -> &mut Self {
+        let phase = match self.settings.get_mut() {
+            Ok(settings) => settings.phase,
+            Err(_) => 3,
+        };
+        self.phase_switch = Mutex::new(Some(PhaseSwitch::new(config, phase as u32)));
+        self.phase_event = Some(SharedEvent::new(event));
+        self
+    }
+
+    // number of samples kept per data_set for history export (0=disable)
+    pub fn set_trend_size(&mut self, size: usize) -> &mut Self {
+        self.trend = Mutex::new(TrendBuffer::new(size));
+        self
+    }
+
+    // publish events to mqtt broker and accept config from command topic
+    pub fn set_mqtt_bridge(&mut self, bridge: MqttBridge) -> &mut Self {
+        self.mqtt = Mutex::new(Some(bridge));
+        self
+    }
+
+    // daily tariff windows used to build ISO-15118 PMax schedule
+    pub fn set_tariffs(&mut self, tariffs: Vec<TariffWindow>) -> &mut Self {
+        self.tariffs = tariffs;
+        self
+    }
+
+    // heartbeat timeout and failsafe limit for external demand-response
+    pub fn set_demand_config(&mut self, config: DemandConfig) -> &mut Self {
+        self.demand = Mutex::new(DemandResponse::new(config));
+        self
+    }
+
+    // read pv production from a SunSpec inverter
+    pub fn set_sunspec_reader(&mut self, reader: SunSpecReader) -> &mut Self {
+        self.sunspec = Mutex::new(Some(reader));
+        self
+    }
+
+    // percentage of subscribed power never used for charging
+    pub fn set_available_margin(&mut self, margin: i32) -> &mut Self {
+        self.available = Mutex::new(AvailablePower::new(self.pmax, margin));
+        self
+    }
+
+    // persist energy session offset across binder restarts
+    pub fn set_session_store(&mut self, store: SessionStore) -> &mut Self {
+        self.session = Some(store);
+        self
+    }
+
+    // meter energy index wrapping value in kWh (0=never wraps)
+    pub fn set_energy_rollover(&mut self, rollover: i64) -> &mut Self {
+        self.totalizer = Mutex::new(EnergyTotalizer::new(rollover * 1000, self.pmax as i64));
+        self
+    }
+
+    // per day color power policy for Tempo/EJP contracts
+    pub fn set_tempo_policy(&mut self, policy: TempoPolicy) -> &mut Self {
+        self.tempo = Mutex::new(Some(TempoTracker::new(policy)));
+        self
+    }
+
+    // state event deltas and heartbeat (0 = only on change)
+    // state is pushed on 'event' by every update that moved it enough
+    pub fn set_state_event(&mut self, event: &'static AfbEvent) -> &mut Self {
+        self.state_event = Some(SharedEvent::new(event));
+        self
+    }
+
+    pub fn set_state_notifier(&mut self, deltas: StateDeltas, heartbeat: Duration) -> &mut Self {
+        self.notifier = Mutex::new(StateNotifier::new(deltas, heartbeat));
+        self
+    }
+
+    // initial tic in ms and meter binding, other settings keep their defaults
+    pub fn set_settings(&mut self, tic: u32, meter: MeterBinding) -> &mut Self {
+        if let Ok(settings) = self.settings.get_mut() {
+            settings.tic = tic;
+            settings.meter = Arc::new(meter);
+        }
+        self
+    }
+
     #[track_caller]
     pub fn get_state(&self) -> Result<MutexGuard<'_, EnergyState>, AfbError> {
-        let guard = self.data_set.lock().unwrap();
-        Ok(guard)
+        Ok(lock_shared(&self.data_set))
     }
 
     #[track_caller]
@@ -58,6 +197,24 @@ impl ManagerHandle {
         Ok(data_set.clone())
     }
 
+    // push state when it moved enough since last event, tic timer only calls it for heartbeat.
+    // notifier stays locked until pushed so concurrent updates keep events in sequence order
+    pub fn push_state_event(&self) -> Result<(), AfbError> {
+        let mut notifier = lock_shared(&self.notifier);
+        let mut state = self.clone_state()?;
+        let sequence = match notifier.check(&state, Instant::now()) {
+            Some(sequence) => sequence,
+            None => return Ok(()),
+        };
+        self.get_state()?.sequence = sequence;
+        state.sequence = sequence;
+        self.publish_state(&state);
+        if let Some(event) = self.state_event {
+            event.push(state);
+        }
+        Ok(())
+    }
+
     #[track_caller]
     pub fn get_config(&self) -> Result<EngyConfSet, AfbError> {
         let data_set = self.get_state()?;
@@ -67,57 +224,692 @@ impl ManagerHandle {
         })
     }
 
-    pub fn set_imax_cable(&self, amp_max: i32) -> Result<&Self, AfbError> {
-        let mut data_set = self.get_state()?;
-        let amp_max = amp_max * 1000;
-        if amp_max != 0 && amp_max < self.imax {
-            data_set.imax = amp_max;
-        } else {
-            data_set.imax = self.imax;
+    fn lock_settings(&self) -> Result<MutexGuard<'_, EnergySettings>, AfbError> {
+        Ok(lock_shared(&self.settings))
+    }
+
+    fn get_phase(&self) -> Result<i32, AfbError> {
+        Ok(self.lock_settings()?.phase)
+    }
+
+    pub fn get_tic(&self) -> Result<u32, AfbError> {
+        Ok(self.lock_settings()?.tic)
+    }
+
+    pub fn get_variation(&self) -> Result<i32, AfbError> {
+        Ok(self.lock_settings()?.variation)
+    }
+
+    pub fn get_meter(&self) -> Result<Arc<MeterBinding>, AfbError> {
+        Ok(self.lock_settings()?.meter.clone())
+    }
+
+    // meter labels subscribed by clients, moved to the new meter when settings change it
+    pub fn add_meter_label(&self, label: &str) {
+        lock_shared(&self.meter_labels).insert(label.to_string());
+    }
+
+    pub fn get_meter_labels(&self) -> Vec<String> {
+        lock_shared(&self.meter_labels).iter().cloned().collect()
+    }
+
+    pub fn get_settings(&self) -> Result<SettingsSet, AfbError> {
+        let settings = self.lock_settings()?.clone();
+        let margin = lock_shared(&self.available).get_margin();
+        let config = self.get_config()?;
+        let umax = self.get_state()?.umax / 1000;
+        Ok(SettingsSet {
+            imax: config.imax,
+            pmax: config.pmax,
+            umax,
+            phase: settings.phase,
+            tic: settings.tic,
+            variation: settings.variation,
+            margin,
+            meter_api: settings.meter.api.clone(),
+            meter_prefix: settings.meter.prefix.clone(),
+            meter_labels: settings.meter.labels.clone(),
+        })
+    }
+
+    // request is fully checked before anything changes, settings lock serializes concurrent updates
+    pub fn update_settings(&self, request: &SettingsRequest) -> Result<SettingsSet, AfbError> {
+        EnergySettings::check(request)?;
+        {
+            let mut settings = self.lock_settings()?;
+            if request.imax.is_some() || request.pmax.is_some() {
+                self.update_limits(|limits| {
+                    if let Some(imax) = request.imax {
+                        limits.set("cable", imax * 1000, 0);
+                    }
+                    if let Some(pmax) = request.pmax {
+                        limits.set("backend", 0, pmax * 1000);
+                    }
+                })?;
+            }
+            if let Some(umax) = request.umax {
+                self.get_state()?.umax = umax * 1000;
+            }
+            if let Some(phase) = request.phase {
+                lock_shared(&self.profiles).set_phases(phase as u32);
+                if let Some(switch) = lock_shared(&self.phase_switch).as_mut() {
+                    switch.set_phases(phase as u32);
+                }
+            }
+            settings.apply(request);
         }
-        Ok(self)
+        self.get_settings()
     }
 
-    pub fn set_power_backend(&self, kwh_max: i32) -> Result<&Self, AfbError> {
-        let mut data_set = self.get_state()?;
-        let kwh_max = kwh_max * 1000;
+    // update one limit source and apply the most restrictive cap to energy state
+    fn update_limits<F>(&self, update: F) -> Result<(), AfbError>
+    where
+        F: FnOnce(&mut EnergyLimits),
+    {
+        let (imax, pmax) = {
+            let mut limits = lock_shared(&self.limits);
+            update(&mut limits);
+            (limits.get_imax(self.imax).0, limits.get_pmax(self.pmax).0)
+        };
 
-        if kwh_max != 0 && kwh_max < self.pmax {
-            data_set.pmax = kwh_max;
-        } else {
-            data_set.pmax = self.pmax;
+        {
+            let mut data_set = self.get_state()?;
+            data_set.imax = imax;
+            data_set.pmax = pmax;
         }
+        self.push_state_event()
+    }
+
+    pub fn set_imax_cable(&self, amp_max: i32) -> Result<&Self, AfbError> {
+        self.update_limits(|limits| limits.set("cable", amp_max * 1000, 0))?;
+        Ok(self)
+    }
+
+    pub fn set_power_backend(&self, kwh_max: i32) -> Result<&Self, AfbError> {
+        self.update_limits(|limits| limits.set("backend", 0, kwh_max * 1000))?;
         Ok(self)
     }
 
+    // subscription in W (VA), tension in V
     pub fn set_power_subscription(&self, watt_max: i32, volts: i32) -> Result<&Self, AfbError> {
         let mut data_set = self.get_state()?;
 
-        data_set.subscription_max = watt_max * 1000;
-        data_set.tension= volts;
+        data_set.subscription_max = watt_max;
+        data_set.tension = volts * 1000;
+        lock_shared(&self.available).set_subscription(watt_max);
         Ok(self)
     }
 
+    // apply linky PCOUP(kVA)/URMS(V), return the change when subscribed power differs from last read
+    pub fn update_contract(&self, pcoup: i32, urms: i32) -> Result<Option<ContractChangeSet>, AfbError> {
+        let current = ContractSet { pcoup, urms };
+        let previous = std::mem::replace(&mut *lock_shared(&self.contract), current.clone());
+        self.set_power_subscription(pcoup * 1000, urms)?;
+
+        if previous.pcoup == 0 || previous.pcoup == pcoup {
+            return Ok(None);
+        }
+        afb_log_msg!(
+            Notice,
+            self.event.get(),
+            "Linky contract changed pcoup:{}->{} kVA",
+            previous.pcoup,
+            pcoup
+        );
+        let change = ContractChangeSet { previous, current };
+        self.mqtt_publish("contract-changed", serde_json::to_string(&change));
+        Ok(Some(change))
+    }
+
+    pub fn get_contract(&self) -> Result<ContractSet, AfbError> {
+        Ok(lock_shared(&self.contract).clone())
+    }
+
     pub fn notify_over_power(&self, tag: MeterTagSet, over_power: i32) -> Result<(), AfbError> {
         afb_log_msg!(
             Notice,
-            self.event,
+            self.event.get(),
             "Request to stop vehicle power tag:{:?} over-power:{}",
             tag,
             over_power
         );
+        lock_shared(&self.counters).count_alarm(&tag);
+        self.mqtt_publish("over-limit", serde_json::to_string(&tag));
         self.event.push(tag);
         Ok(())
     }
 
-    // TBD fulup: make available current per phase smarter
-    pub fn check_available_current(&self, data: &MeterDataSet) -> Result<(i32, i32), AfbError> {
+    // linky SINSTS (index 0) or SINSTSn (index n) apparent power in VA,
+    // return available current data_set in mA per phase and current imax
+    pub fn update_apparent_power(&self, index: usize, power: i32) -> Result<(MeterDataSet, i32), AfbError> {
+        let (tension, imax) = {
+            let data_set = self.get_state()?;
+            match data_set.tension / 1000 {
+                0 => (PROFILE_VOLTS, data_set.imax),
+                value => (value, data_set.imax),
+            }
+        };
+
+        let phase = self.get_phase()?;
+        let avail = {
+            let mut available = lock_shared(&self.available);
+            available.update(index, power);
+            available.get_data_set(phase, tension)
+        };
+        self.update_phase_switch()?;
+        Ok((avail, imax))
+    }
+
+    pub fn get_available_current(&self) -> Result<MeterDataSet, AfbError> {
+        let tension = match self.get_state()?.tension / 1000 {
+            0 => PROFILE_VOLTS,
+            value => value,
+        };
+        let phase = self.get_phase()?;
+        Ok(lock_shared(&self.available).get_data_set(phase, tension))
+    }
+
+    // power in W the charger could use, capped by current pmax limit
+    fn get_charge_power(&self) -> Result<i32, AfbError> {
+        let (charger, pmax) = {
+            let state = self.get_state()?;
+            (state.power / 1000, state.pmax)
+        };
+        // linky SINSTS stays at 0 while exporting, exported power comes on top of headroom
+        let surplus = self.get_surplus()?;
+        let power = lock_shared(&self.available).get_charge_power(charger.max(0)) + surplus;
+        Ok(match pmax {
+            0 => power,
+            pmax => power.min(pmax),
+        })
+    }
+
+    // called on linky SINSTS and meter power updates, works with or without linky
+    fn update_phase_switch(&self) -> Result<(), AfbError> {
+        if self.phase_event.is_none() {
+            return Ok(());
+        }
+        let power = self.get_charge_power()?;
+        self.check_phase_switch(power)
+    }
+
+    pub fn check_phase_switch(&self, power: i32) -> Result<(), AfbError> {
+        let event = match self.phase_event {
+            Some(event) => event.get(),
+            None => return Ok(()),
+        };
+        let mut phase_switch = lock_shared(&self.phase_switch);
+        let phase_switch = match phase_switch.as_mut() {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+
+        if let Some(request) = phase_switch.check(power, Instant::now()) {
+            afb_log_msg!(
+                Notice,
+                event,
+                "Request vehicle phase switch phases:{} power:{}",
+                request.phases,
+                request.power
+            );
+            event.push(request);
+        }
+        Ok(())
+    }
+
+    pub fn get_phase_switch(&self) -> Result<PhaseSwitchSet, AfbError> {
+        let phase_switch = lock_shared(&self.phase_switch);
+        match phase_switch.as_ref() {
+            Some(value) => Ok(value.get_state()),
+            None => afb_error!("energy-phase-switch", "phase switching not configured"),
+        }
+    }
+
+    // current number of phases, as requested by phase switching when enabled
+    pub fn get_phases(&self) -> u32 {
+        let phase = self.get_phase().unwrap_or(3) as u32;
+        lock_shared(&self.phase_switch).as_ref().map_or(phase, |switch| switch.get_state().phases)
+    }
+
+    pub fn subscribe_phase_switch(&self, rqt: &AfbRequest, subscribe: bool) -> Result<(), AfbError> {
+        match self.phase_event {
+            Some(event) if subscribe => {
+                event.subscribe(rqt)?;
+            }
+            Some(event) => {
+                event.unsubscribe(rqt)?;
+            }
+            None => return afb_error!("energy-phase-switch", "phase switching not configured"),
+        }
+        Ok(())
+    }
+
+    pub fn install_profile(&self, profile: ChargingProfile) -> Result<ChargingProfile, AfbError> {
+        let now = get_unix_time()?.as_secs();
+        let profile = lock_shared(&self.profiles).install(profile, now)?;
+        self.update_profile_limit()?;
+        Ok(profile)
+    }
+
+    pub fn clear_profile(&self, request: &ClearProfileRequest) -> Result<usize, AfbError> {
+        let count = lock_shared(&self.profiles).clear(request);
+        self.update_profile_limit()?;
+        Ok(count)
+    }
+
+    pub fn get_composite(&self, request: &CompositeRequest) -> Result<CompositeSchedule, AfbError> {
+        let now = get_unix_time()?.as_secs();
+        Ok(lock_shared(&self.profiles).get_composite(request, now, self.pmax))
+    }
+
+    // ISO-15118 PMax schedule from current limits, profiles, tariffs and subscription (default 24h)
+    pub fn get_pmax_schedule(&self, request: &PMaxScheduleRequest) -> Result<PMaxScheduleSet, AfbError> {
+        let now = get_unix_time()?.as_secs();
+
+        // profile cap is time dependent and already part of composite schedule
+        let mut pmax = lock_shared(&self.limits).get_pmax_except(self.pmax, "profile");
+        let subscription = self.get_state()?.subscription_max;
+        if subscription > 0 {
+            pmax = pmax.min(subscription);
+        }
+
+        let request = CompositeRequest {
+            duration: if request.duration == 0 { 24 * 3600 } else { request.duration },
+            charging_rate_unit: ChargingRateUnit::W,
+        };
+        let composite = lock_shared(&self.profiles).get_composite(&request, now, pmax);
+        Ok(get_pmax_schedule(&composite, &self.tariffs))
+    }
+
+    // relative profiles start with charging session
+    pub fn set_session_start(&self, start: Option<u64>) -> Result<(), AfbError> {
+        lock_shared(&self.profiles).set_session(start);
+        self.update_profile_limit()
+    }
+
+    fn save_session(&self, totalizer: &EnergyTotalizer) -> Result<SessionSet, AfbError> {
+        let now = get_unix_time()?.as_secs();
+        let timestamp = lock_shared(&self.profiles).get_session().unwrap_or(now);
+        let session = totalizer.save(timestamp, now);
+        if let Some(store) = &self.session {
+            store.save(&session)?;
+        }
+        Ok(session)
+    }
+
+    // new session from current lifetime energy, persisted when a session store is configured
+    pub fn start_session(&self) -> Result<SessionSet, AfbError> {
+        self.set_session_start(Some(get_unix_time()?.as_secs()))?;
+        let mut totalizer = lock_shared(&self.totalizer);
+        totalizer.reset_session();
+        self.save_session(&totalizer)
+    }
+
+    // session saved before binder restart if any
+    pub fn restore_session(&self) -> Result<Option<SessionSet>, AfbError> {
+        let session = match &self.session {
+            Some(store) => store.load()?,
+            None => None,
+        };
+        if let Some(value) = &session {
+            lock_shared(&self.totalizer).restore(value);
+            self.set_session_start(Some(value.timestamp))?;
+        }
+        Ok(session)
+    }
+
+    // every discontinuity is logged and re-baselines the persisted session
+    fn check_discontinuity(
+        &self,
+        totalizer: &EnergyTotalizer,
+        discontinuity: Option<EnergyDiscontinuity>,
+    ) -> Result<(), AfbError> {
+        if let Some(discontinuity) = discontinuity {
+            afb_log_msg!(
+                Warning,
+                self.event.get(),
+                "energy counter discontinuity:{:?} discarded:{}Wh lifetime:{}Wh",
+                discontinuity,
+                discontinuity.get_discarded(),
+                totalizer.get_lifetime()
+            );
+            self.save_session(totalizer)?;
+        }
+        Ok(())
+    }
+
+    // meter energy index in kWh, return session energy in Wh (kWh*1000 as data_sets)
+    pub fn update_energy(&self, index: f64) -> Result<i32, AfbError> {
+        let now = get_unix_time()?.as_secs();
+        let (session, lifetime) = {
+            let mut totalizer = lock_shared(&self.totalizer);
+            let discontinuity = totalizer.update((index * 1000.0).round() as i64, now);
+            self.check_discontinuity(&totalizer, discontinuity)?;
+            (totalizer.get_session(), totalizer.get_lifetime())
+        };
+
+        self.get_state()?.lifetime = lifetime;
+        Ok(session.min(i32::MAX as i64) as i32)
+    }
+
+    // meter SERIAL-NUMBER, a change means the meter was replaced
+    pub fn set_meter_serial(&self, serial: &str) -> Result<(), AfbError> {
+        let mut totalizer = lock_shared(&self.totalizer);
+        let discontinuity = totalizer.set_serial(serial);
+        self.check_discontinuity(&totalizer, discontinuity)
+    }
+
+    // profiles are time dependent, should be called periodically
+    pub fn update_profile_limit(&self) -> Result<(), AfbError> {
+        let now = get_unix_time()?.as_secs();
+        let watts = lock_shared(&self.profiles).get_limit(now);
+
+        match watts {
+            Some(watts) => {
+                let imax = (watts as i64 * 1000 / (PROFILE_VOLTS * self.get_phase()?) as i64) as i32;
+                // a 0W profile pauses charging, keep a minimal cap as 0 means no cap
+                self.update_limits(|limits| limits.set("profile", imax.max(1), watts.max(1)))
+            }
+            None => self.update_limits(|limits| limits.clear("profile")),
+        }
+    }
+
+    pub fn set_external_limit(&self, request: &LimitRequest) -> Result<ExternalLimitSet, AfbError> {
+        lock_shared(&self.demand).set(request, Instant::now());
+        self.check_external_limits()?;
+        self.get_external_limits()
+    }
+
+    // expire external limits and apply failsafe, should be called periodically
+    pub fn check_external_limits(&self) -> Result<(), AfbError> {
+        let caps = lock_shared(&self.demand).check(Instant::now());
+
+        self.update_limits(|limits| {
+            limits.clear_prefix("limit/");
+            for (origin, current, power) in caps {
+                limits.set(&format!("limit/{}", origin), current * 1000, power);
+            }
+        })
+    }
+
+    pub fn get_external_limits(&self) -> Result<ExternalLimitSet, AfbError> {
+        let limits = lock_shared(&self.demand).get_limits(Instant::now());
         let data_set = self.get_state()?;
+        Ok(ExternalLimitSet {
+            imax: data_set.imax / 1000,
+            pmax: data_set.pmax,
+            limits,
+        })
+    }
+
+    // keep track of every meter data_set update
+    pub fn record_data_set(&self, data: &MeterDataSet) -> Result<(), AfbError> {
+        lock_shared(&self.stats).push(data);
+
+        let timestamp = get_unix_time()?.as_millis() as u64;
+        lock_shared(&self.trend).push(data, timestamp);
+
+        lock_shared(&self.counters).update_meter(data, Instant::now());
+
+        let event = match data.tag {
+            MeterTagSet::Current => "current",
+            MeterTagSet::Tension => "tension",
+            MeterTagSet::Power => "power",
+            MeterTagSet::Energy => "energy",
+            MeterTagSet::OverCurrent => "iover",
+            MeterTagSet::AvailCurrent => "iavail",
+            MeterTagSet::Pv => "pv",
+            MeterTagSet::Injection => "injection",
+            MeterTagSet::Unset => return Ok(()),
+        };
+        self.mqtt_publish(event, serde_json::to_string(data));
+        Ok(())
+    }
+
+    pub fn publish_state(&self, state: &EnergyState) {
+        self.mqtt_publish("state", serde_json::to_string(state));
+    }
+
+    // broker failures should never block energy management, they are only logged
+    fn mqtt_publish(&self, event: &str, payload: Result<String, serde_json::Error>) {
+        let mut mqtt = lock_shared(&self.mqtt);
+        let bridge = match mqtt.as_mut() {
+            Some(value) if value.is_connected() => value,
+            _ => return,
+        };
+
+        let status = match payload {
+            Ok(payload) => bridge.publish(event, payload.as_bytes()),
+            Err(error) => afb_error!("mqtt-bridge-publish", "fail to serialize event:{} error:{}", event, error),
+        };
+        if let Err(error) = status {
+            afb_log_msg!(Warning, self.event.get(), "{}", error);
+        }
+    }
+
+    // ping broker or reconnect in background, return new socket fd when (re)connected
+    pub fn mqtt_keepalive(&self) -> Result<Option<RawFd>, AfbError> {
+        let mut mqtt = lock_shared(&self.mqtt);
+        let bridge = match mqtt.as_mut() {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        if bridge.is_connected() {
+            bridge.ping()?;
+            Ok(None)
+        } else {
+            bridge.connect()
+        }
+    }
+
+    pub fn get_mqtt_keepalive(&self) -> Option<u16> {
+        lock_shared(&self.mqtt).as_ref().map(|bridge| bridge.get_keepalive())
+    }
 
-        // never use more than 80% of available subscription power
-        let remaining = (self.pmax * 80) / 100 - data.total;
-        let iavail = remaining / data_set.tension / self.phase;
-        Ok((iavail, data_set.imax)) //move to A/phase
+    // process incoming broker packets, config commands update imax/pmax
+    pub fn mqtt_process(&self) -> Result<(), AfbError> {
+        let mut commands = Vec::new();
+        {
+            let mut mqtt = lock_shared(&self.mqtt);
+            let bridge = match mqtt.as_mut() {
+                Some(value) => value,
+                None => return Ok(()),
+            };
+            for packet in bridge.read()? {
+                if let MqttPacket::Publish(topic, payload) = packet {
+                    if topic == bridge.get_command() {
+                        commands.push(payload);
+                    }
+                }
+            }
+        }
+
+        for payload in commands {
+            let config = match serde_json::from_slice::<EngyConfSet>(&payload) {
+                Ok(value) => value,
+                Err(error) => {
+                    afb_log_msg!(Warning, self.event.get(), "mqtt invalid config command error:{}", error);
+                    continue;
+                }
+            };
+            afb_log_msg!(Notice, self.event.get(), "mqtt update energy conf={:?}", config);
+            self.set_imax_cable(config.imax)?;
+            self.set_power_backend(config.pmax)?;
+            self.mqtt_publish("config", serde_json::to_string(&self.get_config()?));
+        }
+        Ok(())
+    }
+
+    pub fn count_subcall_error(&self, api: &str, error: &AfbError) {
+        lock_shared(&self.counters).count_subcall_error(api);
+        let now = get_unix_time().map_or(0, |value| value.as_millis() as u64);
+        lock_shared(&self.health).count_failure(api, &error.to_string(), now);
+    }
+
+    pub fn count_subcall_success(&self, api: &str) {
+        let now = get_unix_time().map_or(0, |value| value.as_millis() as u64);
+        lock_shared(&self.health).count_success(api, now);
+    }
+
+    // event received from a source api (meter, linky, ...)
+    pub fn count_event(&self, source: &str) {
+        let now = get_unix_time().map_or(0, |value| value.as_millis() as u64);
+        lock_shared(&self.health).count_event(source, now);
+    }
+
+    pub fn count_conflict(&self, source: &str) {
+        lock_shared(&self.health).count_conflict(source);
+    }
+
+    // overall state is the worst source state
+    pub fn get_health(&self) -> Result<HealthSet, AfbError> {
+        let now = get_unix_time()?.as_millis() as u64;
+        let sources = lock_shared(&self.health).get_sources(now);
+        let state = sources.iter().map(|source| source.state).max().unwrap_or_default();
+
+        let limits = lock_shared(&self.limits);
+        let (imax, imax_origin) = limits.get_imax(self.imax);
+        let (pmax, pmax_origin) = limits.get_pmax(self.pmax);
+        Ok(HealthSet {
+            state,
+            sources,
+            limits: limits
+                .get_caps()
+                .iter()
+                .map(|cap| LimitCapSet {
+                    origin: cap.origin.clone(),
+                    imax: cap.imax,
+                    pmax: cap.pmax,
+                })
+                .collect(),
+            imax,
+            imax_origin: imax_origin.to_string(),
+            pmax,
+            pmax_origin: pmax_origin.to_string(),
+        })
+    }
+
+    // render energy state and counters in OpenMetrics text format
+    pub fn get_metrics(&self) -> Result<String, AfbError> {
+        let state = self.get_state()?;
+        Ok(lock_shared(&self.counters).render(&state))
+    }
+
+    // poll SunSpec inverter and update pv data_set, None when no inverter is configured
+    pub fn read_pv(&self) -> Result<Option<MeterDataSet>, AfbError> {
+        let reading = match lock_shared(&self.sunspec).as_mut() {
+            Some(reader) => reader.read(),
+            None => return Ok(None),
+        };
+        let reading = match reading {
+            Ok(value) => value,
+            Err(error) => {
+                self.count_subcall_error("sunspec", &error);
+                return Err(error);
+            }
+        };
+        self.count_subcall_success("sunspec");
+
+        let data_set = MeterDataSet {
+            total: reading.power,
+            l1: reading.current[0],
+            l2: reading.current[1],
+            l3: reading.current[2],
+            updated: true,
+            ..MeterDataSet::default(MeterTagSet::Pv)
+        };
+        {
+            let mut state = self.get_state()?;
+            state.pv = reading.power;
+            state.pv_energy = reading.energy.min(i32::MAX as u64) as i32;
+        }
+        self.push_state_event()?;
+        self.record_data_set(&data_set)?;
+        Ok(Some(data_set))
+    }
+
+    // update tempo from linky STGE/LTARF and apply its cap, return true when state changed
+    pub fn update_tempo(&self, stge: Option<u32>, ltarf: Option<&str>) -> Result<bool, AfbError> {
+        let now = get_unix_time()?.as_secs();
+        let (changed, watts) = match lock_shared(&self.tempo).as_mut() {
+            Some(tracker) => {
+                let rollover = tracker.check_day(now);
+                let status = stge.is_some_and(|value| tracker.update_status(value));
+                let tariff = ltarf.is_some_and(|value| tracker.update_tariff(value));
+                (rollover || status || tariff, tracker.get_limit())
+            }
+            None => return afb_error!("energy-tempo-update", "tempo policy not configured"),
+        };
+        self.set_tempo_limit(watts)?;
+        Ok(changed)
+    }
+
+    pub fn set_tempo_override(&self, active: bool) -> Result<TempoSet, AfbError> {
+        let now = get_unix_time()?.as_secs();
+        let (state, watts) = match lock_shared(&self.tempo).as_mut() {
+            Some(tracker) => {
+                tracker.set_override(active, now);
+                (tracker.get_state(), tracker.get_limit())
+            }
+            None => return afb_error!("energy-tempo-override", "tempo policy not configured"),
+        };
+        self.set_tempo_limit(watts)?;
+        Ok(state)
+    }
+
+    pub fn get_tempo(&self) -> Result<TempoSet, AfbError> {
+        match lock_shared(&self.tempo).as_ref() {
+            Some(tracker) => Ok(tracker.get_state()),
+            None => afb_error!("energy-tempo-get", "tempo policy not configured"),
+        }
+    }
+
+    fn set_tempo_limit(&self, watts: Option<i32>) -> Result<(), AfbError> {
+        match watts {
+            Some(watts) => {
+                let imax = (watts as i64 * 1000 / (PROFILE_VOLTS * self.get_phase()?) as i64) as i32;
+                // 0W policy pauses charging, keep a minimal cap as 0 means no cap
+                self.update_limits(|limits| limits.set("tempo", imax.max(1), watts.max(1)))
+            }
+            None => self.update_limits(|limits| limits.clear("tempo")),
+        }
+    }
+
+    // linky EAIT injected energy index in Wh
+    pub fn set_injection_energy(&self, energy: i32) -> Result<(), AfbError> {
+        let mut data_set = self.get_state()?;
+        data_set.injection_energy = energy;
+        Ok(())
+    }
+
+    // power in W currently exported to the grid, usable for surplus charging
+    pub fn get_surplus(&self) -> Result<i32, AfbError> {
+        let data_set = self.get_state()?;
+        Ok(data_set.injection.max(0) / 1000)
+    }
+
+    // last recorded data_set for a tag and its age, None until first update
+    pub fn get_retained(&self, tag: &MeterTagSet) -> Result<Option<RetainedDataSet>, AfbError> {
+        Ok(lock_shared(&self.counters).get_retained(tag, Instant::now()))
+    }
+
+    // last recorded data_set for a tag
+    pub fn get_data_set(&self, tag: &MeterTagSet) -> Result<Option<MeterDataSet>, AfbError> {
+        Ok(lock_shared(&self.counters).get_meter(tag))
+    }
+
+    pub fn get_trend(&self, request: &TrendRequest) -> Result<TrendSet, AfbError> {
+        Ok(lock_shared(&self.trend).get(request))
+    }
+
+    pub fn get_stats(&self) -> Result<EnergyStatsSet, AfbError> {
+        Ok(lock_shared(&self.stats).get())
+    }
+
+    pub fn reset_stats(&self) -> Result<(), AfbError> {
+        lock_shared(&self.stats).reset();
+        Ok(())
     }
 
     pub fn subscribe_over_power(&self, rqt: &AfbRequest) -> Result<(), AfbError> {
@@ -125,8 +917,11 @@ impl ManagerHandle {
         Ok(())
     }
 
+    // state lock is released before notifying, mqtt publish and event push may take a while
     pub fn check_over_subscription(&self, data_new: &MeterDataSet) -> Result<(), AfbError> {
         let mut data_set = self.get_state()?;
+        let mut power_update = false;
+        let mut over_power = None;
 
         match data_new.tag {
             MeterTagSet::Current => {
@@ -135,7 +930,7 @@ impl ManagerHandle {
                     || data_new.l2 > data_set.imax
                     || data_new.l3 > data_set.imax
                 {
-                    self.notify_over_power(data_new.tag.clone(), data_set.imax)?;
+                    over_power = Some(data_set.imax);
                 }
             }
             MeterTagSet::Tension => {
@@ -144,14 +939,15 @@ impl ManagerHandle {
                     || data_new.l2 > data_set.umax
                     || data_new.l3 > data_set.umax
                 {
-                    self.notify_over_power(data_new.tag.clone(), data_set.umax)?;
+                    over_power = Some(data_set.umax);
                 }
             }
             MeterTagSet::Power => {
                 data_set.power = data_new.total;
-                if data_new.total > data_set.subscription_max*1000 // Power is in wath subscription in kW
+                power_update = true;
+                if data_new.total > data_set.subscription_max*1000 // Power is in W*1000 subscription in W
                 {
-                    self.notify_over_power(data_new.tag.clone(), data_set.subscription_max)?;
+                    over_power = Some(data_set.subscription_max);
                 }
             }
 
@@ -159,12 +955,98 @@ impl ManagerHandle {
                 data_set.session = data_new.total;
             }
 
+            MeterTagSet::Injection => {
+                data_set.injection = data_new.total;
+                power_update = true;
+            }
+
             MeterTagSet::OverCurrent => {
-                self.notify_over_power(data_new.tag.clone(), data_set.subscription_max)?;
+                over_power = Some(data_set.subscription_max);
             }
             _ => {}
         }
 
-        Ok(())
+        drop(data_set);
+        if let Some(over_power) = over_power {
+            self.notify_over_power(data_new.tag.clone(), over_power)?;
+        }
+        if power_update {
+            self.update_phase_switch()?;
+        }
+        self.push_state_event()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+
+    // values stay under limits, alarms log and push events that need a running binder
+    fn current(total: i32) -> MeterDataSet {
+        MeterDataSet {
+            total,
+            l1: total / 3,
+            l2: total / 3,
+            l3: total / 3,
+            ..MeterDataSet::default(MeterTagSet::Current)
+        }
+    }
+
+    // binder callbacks share one static handle from any thread, spawn checks it is Sync
+    fn new_handle() -> &'static ManagerHandle {
+        ManagerHandle::new(AfbEvent::new("over-limit"), 32, 22, 245, 3)
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        let mgr = new_handle();
+        let workers: Vec<_> = (0..8)
+            .map(|worker| {
+                thread::spawn(move || {
+                    for count in 0..500 {
+                        mgr.check_over_subscription(&current(worker * 1000 + count)).unwrap();
+                        mgr.set_imax_cable(16 + worker % 4).unwrap();
+                        if count % 50 == 0 {
+                            let request = SettingsRequest {
+                                variation: Some(worker),
+                                ..SettingsRequest::default()
+                            };
+                            mgr.update_settings(&request).unwrap();
+                        }
+                        mgr.get_retained(&MeterTagSet::Current).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let state = mgr.clone_state().unwrap();
+        // last pushed sequence is the one kept in state whatever the interleaving
+        assert_eq!(state.sequence, lock_shared(&mgr.notifier).get_sequence());
+        assert!((16..=19).contains(&mgr.get_config().unwrap().imax));
+        assert!((0..8).contains(&mgr.get_variation().unwrap()));
+    }
+
+    #[test]
+    fn poison_recovery() {
+        let mgr = new_handle();
+        let status = thread::spawn(move || {
+            let mut state = mgr.get_state().unwrap();
+            state.current = 1234;
+            panic!("callback panic while holding energy state");
+        })
+        .join();
+        assert!(status.is_err());
+
+        // state is left as the panicking callback wrote it and keeps working
+        assert_eq!(mgr.clone_state().unwrap().current, 1234);
+        mgr.check_over_subscription(&current(3000)).unwrap();
+        mgr.set_imax_cable(10).unwrap();
+        assert_eq!(mgr.clone_state().unwrap().current, 3000);
+        assert_eq!(mgr.get_config().unwrap().imax, 10);
     }
 }
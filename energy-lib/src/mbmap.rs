@@ -0,0 +1,158 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use crate::prelude::*;
+use typesv4::prelude::*;
+
+// holding register table (see README), 32bit values are big-endian signed on two registers
+const REG_VERSION: usize = 0;
+const REG_PHASES: usize = 1;
+const REG_SESSION: usize = 2;
+const REG_CURRENT: usize = 4;
+const REG_TENSION: usize = 6;
+const REG_POWER: usize = 8;
+const REG_CURRENT_L1: usize = 10;
+const REG_CURRENT_L2: usize = 12;
+const REG_CURRENT_L3: usize = 14;
+const REG_IMAX: usize = 16;
+const REG_PMAX: usize = 18;
+const REG_SUBSCRIPTION: usize = 20;
+const REG_LIMITS: usize = 22;
+const REG_SETPOINT: usize = 32;
+const REG_SETPOINT_POWER: usize = 34;
+pub const MODBUS_MAP_COUNT: usize = 36;
+
+const MAP_VERSION: u16 = 1;
+const SETPOINT_SOURCE: &str = "modbus";
+const SETPOINT_COUNT: usize = MODBUS_MAP_COUNT - REG_SETPOINT;
+
+fn set_i32(registers: &mut [u16], addr: usize, value: i32) {
+    registers[addr] = (value >> 16) as u16;
+    registers[addr + 1] = value as u16;
+}
+
+// setpoint registers: duration(s), current(A), power(W) on 2 registers
+#[derive(Default)]
+pub struct ModbusSetpoint {
+    registers: [u16; SETPOINT_COUNT],
+}
+
+impl ModbusSetpoint {
+    pub fn new() -> Self {
+        ModbusSetpoint::default()
+    }
+
+    pub fn get_registers(&self) -> &[u16] {
+        &self.registers
+    }
+
+    // store written registers and return the limit to apply, any applied write also acts as heartbeat.
+    // A write ending on power high word waits for the low word, two FC06 never apply half a power.
+    pub fn write(&mut self, start: u16, values: &[u16]) -> Result<Option<LimitRequest>, u8> {
+        let start = start as usize;
+        let stop = start + values.len();
+        if values.is_empty() || start < REG_SETPOINT || stop > MODBUS_MAP_COUNT {
+            return Err(MODBUS_ILLEGAL_ADDRESS);
+        }
+        self.registers[start - REG_SETPOINT..stop - REG_SETPOINT].copy_from_slice(values);
+        if stop == REG_SETPOINT_POWER + 1 {
+            return Ok(None);
+        }
+
+        Ok(Some(LimitRequest {
+            source: SETPOINT_SOURCE.to_string(),
+            duration: self.registers[0] as u32,
+            current: self.registers[1] as i32,
+            power: ((self.registers[2] as u32) << 16 | self.registers[3] as u32) as i32,
+            heartbeat: false,
+        }))
+    }
+}
+
+// full register table from energy state, phase currents and active external limits count
+pub fn modbus_pack_registers(
+    state: &EnergyState,
+    phases: u32,
+    current: &MeterDataSet,
+    limits: usize,
+    setpoint: &ModbusSetpoint,
+) -> Vec<u16> {
+    let mut registers = vec![0u16; MODBUS_MAP_COUNT];
+    registers[REG_VERSION] = MAP_VERSION;
+    registers[REG_PHASES] = phases as u16;
+    set_i32(&mut registers, REG_SESSION, state.session);
+    set_i32(&mut registers, REG_CURRENT, state.current);
+    set_i32(&mut registers, REG_TENSION, state.tension);
+    set_i32(&mut registers, REG_POWER, state.power);
+    set_i32(&mut registers, REG_CURRENT_L1, current.l1);
+    set_i32(&mut registers, REG_CURRENT_L2, current.l2);
+    set_i32(&mut registers, REG_CURRENT_L3, current.l3);
+    set_i32(&mut registers, REG_IMAX, state.imax);
+    set_i32(&mut registers, REG_PMAX, state.pmax);
+    set_i32(&mut registers, REG_SUBSCRIPTION, state.subscription_max);
+    registers[REG_LIMITS] = limits as u16;
+    registers[REG_SETPOINT..].copy_from_slice(setpoint.get_registers());
+    registers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack() {
+        let mut state = EnergyState::default(16000, 11000, 250000);
+        state.session = 1_234_567;
+        state.power = -2_500_000;
+        state.tension = 231_000;
+        let current = MeterDataSet {
+            l1: 8030,
+            l2: 70_000,
+            l3: 0,
+            ..MeterDataSet::default(MeterTagSet::Current)
+        };
+        let mut setpoint = ModbusSetpoint::new();
+        setpoint.write(32, &[60, 16]).unwrap();
+
+        let registers = modbus_pack_registers(&state, 3, &current, 2, &setpoint);
+        assert_eq!(registers.len(), MODBUS_MAP_COUNT);
+        assert_eq!(registers[0..4], [1, 3, 0x0012, 0xD687]);
+        assert_eq!(registers[6..10], [0x0003, 0x8658, 0xFFD9, 0xDA60]);
+        assert_eq!(registers[10..16], [0, 8030, 0x0001, 0x1170, 0, 0]);
+        assert_eq!(registers[16..22], [0, 16000, 0, 11000, 0, 11000]);
+        assert_eq!(registers[22], 2);
+        assert_eq!(registers[32..36], [60, 16, 0, 0]);
+    }
+
+    #[test]
+    fn setpoint_write() {
+        let mut setpoint = ModbusSetpoint::new();
+        let request = setpoint.write(33, &[16]).unwrap().unwrap();
+        assert_eq!((request.source.as_str(), request.current, request.power, request.duration), ("modbus", 16, 0, 0));
+
+        // FC16 on both power registers applies at once
+        let request = setpoint.write(32, &[0, 0, 0x0001, 0x86A0]).unwrap().unwrap();
+        assert_eq!((request.current, request.power), (0, 100000));
+
+        // FC06 on power high word is only applied with low word
+        assert!(setpoint.write(34, &[0x0002]).unwrap().is_none());
+        assert!(setpoint.write(32, &[300, 0, 0x0002]).unwrap().is_none());
+        let request = setpoint.write(35, &[0x0000]).unwrap().unwrap();
+        assert_eq!((request.duration, request.power), (300, 0x20000));
+        assert_eq!(setpoint.get_registers(), [300, 0, 2, 0]);
+
+        for (start, values) in [(31, &[0u16][..]), (35, &[0, 0]), (32, &[]), (0, &[1])] {
+            assert_eq!(setpoint.write(start, values).err(), Some(MODBUS_ILLEGAL_ADDRESS));
+        }
+        assert_eq!(setpoint.get_registers(), [300, 0, 2, 0]);
+    }
+}
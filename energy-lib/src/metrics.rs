@@ -0,0 +1,152 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use std::fmt::Write;
+use std::time::Instant;
+use typesv4::prelude::*;
+
+// last data_sets with their reception time, alarms and subcall failures as exported to OpenMetrics
+pub struct EnergyCounters {
+    meters: Vec<(MeterDataSet, Instant)>,
+    alarms: Vec<(MeterTagSet, u64)>,
+    subcall_errors: Vec<(String, u64)>,
+}
+
+impl EnergyCounters {
+    pub fn new() -> Self {
+        EnergyCounters {
+            meters: Vec::new(),
+            alarms: Vec::new(),
+            subcall_errors: Vec::new(),
+        }
+    }
+
+    pub fn update_meter(&mut self, data: &MeterDataSet, now: Instant) {
+        match self.meters.iter_mut().find(|(meter, _)| meter.tag == data.tag) {
+            Some(meter) => *meter = (data.clone(), now),
+            None => self.meters.push((data.clone(), now)),
+        }
+    }
+
+    pub fn get_meter(&self, tag: &MeterTagSet) -> Option<MeterDataSet> {
+        self.meters.iter().find(|(meter, _)| meter.tag == *tag).map(|(meter, _)| meter.clone())
+    }
+
+    // age in ms since data_set was received
+    pub fn get_retained(&self, tag: &MeterTagSet, now: Instant) -> Option<RetainedDataSet> {
+        self.meters.iter().find(|(meter, _)| meter.tag == *tag).map(|(meter, time)| RetainedDataSet {
+            data_set: meter.clone(),
+            age: now.saturating_duration_since(*time).as_millis() as u64,
+        })
+    }
+
+    pub fn count_alarm(&mut self, tag: &MeterTagSet) {
+        match self.alarms.iter_mut().find(|(alarm, _)| alarm == tag) {
+            Some((_, count)) => *count += 1,
+            None => self.alarms.push((tag.clone(), 1)),
+        }
+    }
+
+    pub fn count_subcall_error(&mut self, api: &str) {
+        match self.subcall_errors.iter_mut().find(|(name, _)| name == api) {
+            Some((_, count)) => *count += 1,
+            None => self.subcall_errors.push((api.to_string(), 1)),
+        }
+    }
+
+    // OpenMetrics text format, meter values are x1000 as in MeterDataSet
+    pub fn render(&self, state: &EnergyState) -> String {
+        let mut text = String::new();
+
+        let gauges = [
+            ("energy_state_session", "session energy", state.session),
+            ("energy_state_current", "total current", state.current),
+            ("energy_state_tension", "average tension", state.tension),
+            ("energy_state_power", "total power", state.power),
+            ("energy_state_imax", "max current per phase", state.imax),
+            ("energy_state_pmax", "max power", state.pmax),
+            ("energy_state_subscription", "subscription max power", state.subscription_max),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(text, "# TYPE {} gauge", name);
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "{} {}", name, value);
+        }
+
+        let _ = writeln!(text, "# TYPE energy_meter gauge");
+        let _ = writeln!(text, "# HELP energy_meter last meter data_set value (x1000)");
+        for (meter, _) in &self.meters {
+            let values = [
+                ("total", meter.total),
+                ("l1", meter.l1),
+                ("l2", meter.l2),
+                ("l3", meter.l3),
+            ];
+            for (phase, value) in values {
+                let _ = writeln!(
+                    text,
+                    "energy_meter{{tag=\"{:?}\",phase=\"{}\"}} {}",
+                    meter.tag, phase, value
+                );
+            }
+        }
+
+        let _ = writeln!(text, "# TYPE energy_alarms counter");
+        let _ = writeln!(text, "# HELP energy_alarms over-limit notifications");
+        for (tag, count) in &self.alarms {
+            let _ = writeln!(text, "energy_alarms_total{{tag=\"{:?}\"}} {}", tag, count);
+        }
+
+        let _ = writeln!(text, "# TYPE energy_subcall_errors counter");
+        let _ = writeln!(text, "# HELP energy_subcall_errors failed subcalls per api");
+        for (api, count) in &self.subcall_errors {
+            let _ = writeln!(text, "energy_subcall_errors_total{{api=\"{}\"}} {}", api, count);
+        }
+
+        text.push_str("# EOF\n");
+        text
+    }
+}
+
+impl Default for EnergyCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn retained() {
+        let mut counters = EnergyCounters::new();
+        let now = Instant::now();
+        assert!(counters.get_retained(&MeterTagSet::Energy, now).is_none());
+
+        let mut energy = MeterDataSet::default(MeterTagSet::Energy);
+        energy.total = 1500;
+        counters.update_meter(&energy, now);
+        let retained = counters.get_retained(&MeterTagSet::Energy, now + Duration::from_millis(250)).unwrap();
+        assert_eq!((retained.data_set.total, retained.age), (1500, 250));
+
+        // reset replaces value and age, other tags are left alone
+        energy.total = 0;
+        counters.update_meter(&energy, now + Duration::from_secs(1));
+        counters.update_meter(&MeterDataSet::default(MeterTagSet::Power), now);
+        let retained = counters.get_retained(&MeterTagSet::Energy, now + Duration::from_secs(1)).unwrap();
+        assert_eq!((retained.data_set.total, retained.age), (0, 0));
+        assert_eq!(counters.get_meter(&MeterTagSet::Energy).unwrap().total, 0);
+        assert!(counters.render(&EnergyState::default(0, 0, 0)).contains("energy_meter{tag=\"Energy\",phase=\"total\"} 0"));
+    }
+}
@@ -0,0 +1,266 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+// minimal Modbus-TCP frame codec (holding/input registers only)
+pub const MODBUS_READ_HOLDING: u8 = 0x03;
+pub const MODBUS_READ_INPUT: u8 = 0x04;
+pub const MODBUS_WRITE_SINGLE: u8 = 0x06;
+pub const MODBUS_WRITE_MULTIPLE: u8 = 0x10;
+
+pub const MODBUS_ILLEGAL_FUNCTION: u8 = 0x01;
+pub const MODBUS_ILLEGAL_ADDRESS: u8 = 0x02;
+pub const MODBUS_ILLEGAL_VALUE: u8 = 0x03;
+pub const MODBUS_SERVER_FAILURE: u8 = 0x04;
+
+// max registers per read request
+pub const MODBUS_MAX_READ: u16 = 125;
+
+pub struct ModbusFrame {
+    pub transaction: u16,
+    pub unit: u8,
+    pub pdu: Vec<u8>,
+}
+
+// extract first complete MBAP frame from buffer if any
+pub fn modbus_parse_frame(buffer: &mut Vec<u8>) -> Option<ModbusFrame> {
+    if buffer.len() < 7 {
+        return None;
+    }
+    let protocol = u16::from_be_bytes([buffer[2], buffer[3]]);
+    let len = u16::from_be_bytes([buffer[4], buffer[5]]) as usize;
+    if protocol != 0 || !(2..=254).contains(&len) {
+        // not a modbus-tcp stream, drop everything
+        buffer.clear();
+        return None;
+    }
+    if buffer.len() < 6 + len {
+        return None;
+    }
+
+    let frame: Vec<u8> = buffer.drain(0..6 + len).collect();
+    Some(ModbusFrame {
+        transaction: u16::from_be_bytes([frame[0], frame[1]]),
+        unit: frame[6],
+        pdu: frame[7..].to_vec(),
+    })
+}
+
+pub fn modbus_build_frame(transaction: u16, unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(7 + pdu.len());
+    frame.extend_from_slice(&transaction.to_be_bytes());
+    frame.extend_from_slice(&0u16.to_be_bytes());
+    frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+    frame.push(unit);
+    frame.extend_from_slice(pdu);
+    frame
+}
+
+pub fn modbus_exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
+
+fn get_u16(pdu: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*pdu.get(offset)?, *pdu.get(offset + 1)?]))
+}
+
+// process one request pdu against register read/write handlers, return response pdu
+pub fn modbus_serve<R, W>(pdu: &[u8], read: R, write: W) -> Vec<u8>
+where
+    R: Fn(u8, u16, u16) -> Result<Vec<u16>, u8>,
+    W: FnOnce(u16, &[u16]) -> Result<(), u8>,
+{
+    let function = match pdu.first() {
+        Some(value) => *value,
+        None => return modbus_exception(0, MODBUS_ILLEGAL_FUNCTION),
+    };
+
+    match function {
+        MODBUS_READ_HOLDING | MODBUS_READ_INPUT => {
+            let (start, count) = match (get_u16(pdu, 1), get_u16(pdu, 3)) {
+                (Some(start), Some(count)) if (1..=MODBUS_MAX_READ).contains(&count) => (start, count),
+                _ => return modbus_exception(function, MODBUS_ILLEGAL_VALUE),
+            };
+            match read(function, start, count) {
+                Ok(registers) => {
+                    let mut response = vec![function, (registers.len() * 2) as u8];
+                    for register in registers {
+                        response.extend_from_slice(&register.to_be_bytes());
+                    }
+                    response
+                }
+                Err(code) => modbus_exception(function, code),
+            }
+        }
+        MODBUS_WRITE_SINGLE => {
+            let (addr, value) = match (get_u16(pdu, 1), get_u16(pdu, 3)) {
+                (Some(addr), Some(value)) => (addr, value),
+                _ => return modbus_exception(function, MODBUS_ILLEGAL_VALUE),
+            };
+            match write(addr, &[value]) {
+                Ok(()) => pdu[0..5].to_vec(),
+                Err(code) => modbus_exception(function, code),
+            }
+        }
+        MODBUS_WRITE_MULTIPLE => {
+            let (start, count) = match (get_u16(pdu, 1), get_u16(pdu, 3), pdu.get(5)) {
+                (Some(start), Some(count), Some(bytes))
+                    if count > 0 && *bytes as usize == count as usize * 2 && pdu.len() >= 6 + *bytes as usize =>
+                {
+                    (start, count)
+                }
+                _ => return modbus_exception(function, MODBUS_ILLEGAL_VALUE),
+            };
+            let values: Vec<u16> = (0..count as usize)
+                .filter_map(|idx| get_u16(pdu, 6 + idx * 2))
+                .collect();
+            match write(start, &values) {
+                Ok(()) => pdu[0..5].to_vec(),
+                Err(code) => modbus_exception(function, code),
+            }
+        }
+        _ => modbus_exception(function, MODBUS_ILLEGAL_FUNCTION),
+    }
+}
+
+// client side read request pdu
+pub fn modbus_read_request(function: u8, start: u16, count: u16) -> Vec<u8> {
+    let mut pdu = vec![function];
+    pdu.extend_from_slice(&start.to_be_bytes());
+    pdu.extend_from_slice(&count.to_be_bytes());
+    pdu
+}
+
+// decode read response pdu, return exception code on failure
+pub fn modbus_read_response(function: u8, pdu: &[u8]) -> Result<Vec<u16>, u8> {
+    match pdu.first() {
+        Some(value) if *value == function => {}
+        Some(value) if *value == function | 0x80 => return Err(pdu.get(1).copied().unwrap_or(0)),
+        _ => return Err(MODBUS_ILLEGAL_FUNCTION),
+    }
+    let bytes = pdu.get(1).copied().unwrap_or(0) as usize;
+    if pdu.len() < 2 + bytes || bytes % 2 != 0 {
+        return Err(MODBUS_ILLEGAL_VALUE);
+    }
+    Ok((0..bytes / 2).filter_map(|idx| get_u16(pdu, 2 + idx * 2)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_map(_function: u8, start: u16, count: u16) -> Result<Vec<u16>, u8> {
+        match start.checked_add(count) {
+            Some(end) if end <= 8 => Ok((start..end).collect()),
+            _ => Err(MODBUS_ILLEGAL_ADDRESS),
+        }
+    }
+
+    fn no_write(_addr: u16, _values: &[u16]) -> Result<(), u8> {
+        panic!("unexpected write")
+    }
+
+    #[test]
+    fn parse_frame() {
+        let request = modbus_build_frame(0x1234, 1, &modbus_read_request(MODBUS_READ_HOLDING, 40000, 2));
+        assert_eq!(request, [0x12, 0x34, 0, 0, 0, 6, 1, 3, 0x9C, 0x40, 0, 2]);
+
+        // short header then partial pdu are kept until complete
+        let mut buffer = request[0..6].to_vec();
+        assert!(modbus_parse_frame(&mut buffer).is_none());
+        buffer.extend_from_slice(&request[6..10]);
+        assert!(modbus_parse_frame(&mut buffer).is_none());
+        assert_eq!(buffer.len(), 10);
+
+        // trailing bytes of next frame remain in buffer
+        buffer.extend_from_slice(&request[10..]);
+        buffer.extend_from_slice(&request[0..3]);
+        let frame = modbus_parse_frame(&mut buffer).unwrap();
+        assert_eq!((frame.transaction, frame.unit), (0x1234, 1));
+        assert_eq!(frame.pdu, [3, 0x9C, 0x40, 0, 2]);
+        assert_eq!(buffer, request[0..3]);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let mut frame = modbus_build_frame(1, 1, &[3, 0, 0, 0, 1]);
+        frame[3] = 1;
+        let mut buffer = frame.clone();
+        assert!(modbus_parse_frame(&mut buffer).is_none());
+        assert!(buffer.is_empty());
+
+        // mbap length covers unit id + function at least, and at most 254 bytes
+        for len in [0u16, 1, 255] {
+            let mut buffer = modbus_build_frame(1, 1, &[3, 0, 0, 0, 1]);
+            buffer[4..6].copy_from_slice(&len.to_be_bytes());
+            assert!(modbus_parse_frame(&mut buffer).is_none());
+            assert!(buffer.is_empty(), "len:{}", len);
+        }
+    }
+
+    #[test]
+    fn serve_read() {
+        let response = modbus_serve(&[MODBUS_READ_INPUT, 0, 2, 0, 3], read_map, no_write);
+        assert_eq!(response, [MODBUS_READ_INPUT, 6, 0, 2, 0, 3, 0, 4]);
+        assert_eq!(modbus_read_response(MODBUS_READ_INPUT, &response), Ok(vec![2, 3, 4]));
+
+        let response = modbus_serve(&[MODBUS_READ_HOLDING, 0, 6, 0, 3], read_map, no_write);
+        assert_eq!(response, [0x83, MODBUS_ILLEGAL_ADDRESS]);
+        assert_eq!(modbus_read_response(MODBUS_READ_HOLDING, &response), Err(MODBUS_ILLEGAL_ADDRESS));
+
+        for pdu in [&[MODBUS_READ_HOLDING, 0, 0, 0, 0][..], &[MODBUS_READ_HOLDING, 0, 0, 0, 126], &[3, 0, 0, 0]] {
+            assert_eq!(modbus_serve(pdu, read_map, no_write), [0x83, MODBUS_ILLEGAL_VALUE]);
+        }
+        assert_eq!(modbus_serve(&[0x2B, 0x0E], read_map, no_write), [0xAB, MODBUS_ILLEGAL_FUNCTION]);
+        assert_eq!(modbus_serve(&[], read_map, no_write), [0x80, MODBUS_ILLEGAL_FUNCTION]);
+    }
+
+    #[test]
+    fn serve_write() {
+        let pdu = [MODBUS_WRITE_SINGLE, 0, 33, 0, 16];
+        let response = modbus_serve(&pdu, read_map, |addr, values| {
+            assert_eq!((addr, values), (33, &[16][..]));
+            Ok(())
+        });
+        assert_eq!(response, pdu);
+
+        let pdu = [MODBUS_WRITE_MULTIPLE, 0, 34, 0, 2, 4, 0, 1, 0x86, 0xA0];
+        let response = modbus_serve(&pdu, read_map, |addr, values| {
+            assert_eq!((addr, values), (34, &[1, 0x86A0][..]));
+            Ok(())
+        });
+        assert_eq!(response, pdu[0..5]);
+
+        let response = modbus_serve(&pdu, read_map, |_, _| Err(MODBUS_ILLEGAL_ADDRESS));
+        assert_eq!(response, [0x90, MODBUS_ILLEGAL_ADDRESS]);
+
+        // byte count should match register count and available data
+        for pdu in [
+            &[MODBUS_WRITE_MULTIPLE, 0, 34, 0, 2, 2, 0, 1][..],
+            &[MODBUS_WRITE_MULTIPLE, 0, 34, 0, 2, 4, 0, 1],
+            &[MODBUS_WRITE_MULTIPLE, 0, 34, 0, 0, 0],
+            &[MODBUS_WRITE_SINGLE, 0, 34, 0],
+        ] {
+            let response = modbus_serve(pdu, read_map, no_write);
+            assert_eq!(response, [pdu[0] | 0x80, MODBUS_ILLEGAL_VALUE]);
+        }
+    }
+
+    #[test]
+    fn read_response() {
+        assert_eq!(modbus_read_response(MODBUS_READ_HOLDING, &[3, 4, 0, 1, 0xFF, 0xFF]), Ok(vec![1, 0xFFFF]));
+        assert_eq!(modbus_read_response(MODBUS_READ_HOLDING, &[0x83, 4]), Err(MODBUS_SERVER_FAILURE));
+        assert_eq!(modbus_read_response(MODBUS_READ_HOLDING, &[4, 2, 0, 1]), Err(MODBUS_ILLEGAL_FUNCTION));
+        assert_eq!(modbus_read_response(MODBUS_READ_HOLDING, &[]), Err(MODBUS_ILLEGAL_FUNCTION));
+        assert_eq!(modbus_read_response(MODBUS_READ_HOLDING, &[3, 3, 0, 1, 0]), Err(MODBUS_ILLEGAL_VALUE));
+        assert_eq!(modbus_read_response(MODBUS_READ_HOLDING, &[3, 4, 0, 1]), Err(MODBUS_ILLEGAL_VALUE));
+    }
+}
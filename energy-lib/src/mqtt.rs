@@ -0,0 +1,470 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+// minimal MQTT-3.1.1 client (qos=0 only) used to bridge energy events
+use afbv4::prelude::*;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const MQTT_TIMEOUT: Duration = Duration::from_secs(2);
+const MQTT_CONNECT: u8 = 0x10;
+const MQTT_CONNACK: u8 = 0x20;
+const MQTT_PUBLISH: u8 = 0x30;
+const MQTT_SUBSCRIBE: u8 = 0x82;
+const MQTT_PINGREQ: u8 = 0xC0;
+
+// alarms are transient, a retained one would stay on broker after it is cleared
+const MQTT_NOT_RETAINED: [&str; 1] = ["over-limit"];
+
+pub struct MqttConfig {
+    pub uri: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keepalive: u16,
+    pub retain: bool,
+    pub prefix: String,
+    // config command topic, empty = disabled (it bypasses api permission)
+    pub command: String,
+    // event name -> topic, default is prefix/event
+    pub topics: Vec<(String, String)>,
+}
+
+pub enum MqttPacket {
+    ConnAck(u8),
+    Publish(String, Vec<u8>),
+    Other(u8),
+}
+
+fn mqtt_push_len(buffer: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buffer.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn mqtt_push_str(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+fn mqtt_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    mqtt_push_len(&mut packet, body.len());
+    packet.extend_from_slice(body);
+    packet
+}
+
+pub fn mqtt_connect_packet(config: &MqttConfig) -> Vec<u8> {
+    let mut flags = 0x02; // clean session
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+
+    let mut body = Vec::new();
+    mqtt_push_str(&mut body, "MQTT");
+    body.push(4); // protocol level 3.1.1
+    body.push(flags);
+    body.extend_from_slice(&config.keepalive.to_be_bytes());
+    mqtt_push_str(&mut body, &config.client_id);
+    if let Some(username) = &config.username {
+        mqtt_push_str(&mut body, username);
+    }
+    if let Some(password) = &config.password {
+        mqtt_push_str(&mut body, password);
+    }
+    mqtt_packet(MQTT_CONNECT, &body)
+}
+
+pub fn mqtt_publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    mqtt_push_str(&mut body, topic);
+    body.extend_from_slice(payload);
+    mqtt_packet(MQTT_PUBLISH | retain as u8, &body)
+}
+
+pub fn mqtt_subscribe_packet(packet_id: u16, topic: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    mqtt_push_str(&mut body, topic);
+    body.push(0); // qos=0
+    mqtt_packet(MQTT_SUBSCRIBE, &body)
+}
+
+// extract first complete packet from buffer if any
+pub fn mqtt_parse_packet(buffer: &mut Vec<u8>) -> Option<MqttPacket> {
+    let mut len: usize = 0;
+    let mut shift = 0;
+    let mut idx = 1;
+    loop {
+        let byte = *buffer.get(idx)?;
+        len += ((byte & 0x7F) as usize) << shift;
+        idx += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 21 {
+            // invalid remaining length, drop everything
+            buffer.clear();
+            return None;
+        }
+    }
+    if buffer.len() < idx + len {
+        return None;
+    }
+
+    let header = buffer[0];
+    let body: Vec<u8> = buffer.drain(0..idx + len).skip(idx).collect();
+    let packet = match header & 0xF0 {
+        MQTT_CONNACK if body.len() >= 2 => MqttPacket::ConnAck(body[1]),
+        MQTT_PUBLISH if body.len() >= 2 => {
+            let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let qos = (header >> 1) & 0x03;
+            // skip packet-id when qos>0
+            let offset = 2 + topic_len + if qos > 0 { 2 } else { 0 };
+            if body.len() < offset {
+                return Some(MqttPacket::Other(header));
+            }
+            let topic = String::from_utf8_lossy(&body[2..2 + topic_len]).to_string();
+            MqttPacket::Publish(topic, body[offset..].to_vec())
+        }
+        _ => MqttPacket::Other(header),
+    };
+    Some(packet)
+}
+
+// dns, tcp connect and connack run in a worker thread, never on a binder one
+fn mqtt_handshake(uri: &str, connect: &[u8], command: &str) -> Result<(TcpStream, Vec<u8>), String> {
+    let addr = match uri.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(value)) => value,
+        _ => return Err(format!("invalid broker uri:{}", uri)),
+    };
+
+    let mut stream = match TcpStream::connect_timeout(&addr, MQTT_TIMEOUT) {
+        Ok(value) => value,
+        Err(error) => return Err(format!("fail to connect uri:{} error:{}", uri, error)),
+    };
+
+    let status = stream
+        .set_read_timeout(Some(MQTT_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(MQTT_TIMEOUT)))
+        .and_then(|_| stream.write_all(connect));
+    if let Err(error) = status {
+        return Err(format!("fail to send connect error:{}", error));
+    }
+
+    // wait for broker connack
+    let mut pending = Vec::new();
+    let mut buffer = [0u8; 64];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => return Err("connection closed by broker".to_string()),
+            Ok(count) => pending.extend_from_slice(&buffer[0..count]),
+            Err(error) => return Err(format!("fail to read connack error:{}", error)),
+        }
+        match mqtt_parse_packet(&mut pending) {
+            Some(MqttPacket::ConnAck(0)) => break,
+            Some(MqttPacket::ConnAck(code)) => return Err(format!("connection refused code:{}", code)),
+            Some(_) => return Err("unexpected packet waiting connack".to_string()),
+            None => continue,
+        }
+    }
+
+    if !command.is_empty() {
+        if let Err(error) = stream.write_all(&mqtt_subscribe_packet(1, command)) {
+            return Err(format!("fail to subscribe error:{}", error));
+        }
+    }
+
+    if let Err(error) = stream.set_nonblocking(true) {
+        return Err(format!("fail to set non blocking error:{}", error));
+    }
+    Ok((stream, pending))
+}
+
+type MqttHandshake = JoinHandle<Result<(TcpStream, Vec<u8>), String>>;
+
+pub struct MqttBridge {
+    config: MqttConfig,
+    stream: Option<TcpStream>,
+    pending: Option<MqttHandshake>,
+    buffer: Vec<u8>,
+}
+
+impl MqttBridge {
+    pub fn new(config: MqttConfig) -> Self {
+        MqttBridge {
+            config,
+            stream: None,
+            pending: None,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    pub fn get_keepalive(&self) -> u16 {
+        self.config.keepalive
+    }
+
+    pub fn get_command(&self) -> &str {
+        &self.config.command
+    }
+
+    pub fn get_topic(&self, event: &str) -> String {
+        match self.config.topics.iter().find(|(name, _)| name == event) {
+            Some((_, topic)) => topic.clone(),
+            None => format!("{}/{}", self.config.prefix, event),
+        }
+    }
+
+    // start a connection in background or collect it, return socket fd to be polled
+    // for incoming commands once broker accepted the connection
+    pub fn connect(&mut self) -> Result<Option<RawFd>, AfbError> {
+        let handle = match self.pending.take() {
+            Some(handle) if handle.is_finished() => handle,
+            Some(handle) => {
+                self.pending = Some(handle);
+                return Ok(None);
+            }
+            None => {
+                self.stream = None;
+                let uri = self.config.uri.clone();
+                let connect = mqtt_connect_packet(&self.config);
+                let command = self.config.command.clone();
+                self.pending = Some(std::thread::spawn(move || mqtt_handshake(&uri, &connect, &command)));
+                return Ok(None);
+            }
+        };
+
+        match handle.join() {
+            Ok(Ok((stream, buffer))) => {
+                let fd = stream.as_raw_fd();
+                self.stream = Some(stream);
+                self.buffer = buffer;
+                Ok(Some(fd))
+            }
+            Ok(Err(error)) => afb_error!("mqtt-bridge-connect", "{}", error),
+            Err(_) => afb_error!("mqtt-bridge-connect", "connection thread panicked"),
+        }
+    }
+
+    fn send(&mut self, packet: &[u8]) -> Result<(), AfbError> {
+        let stream = match self.stream.as_mut() {
+            Some(value) => value,
+            None => return afb_error!("mqtt-bridge-send", "broker not connected"),
+        };
+
+        // socket is non blocking, a full send buffer means broker is gone
+        if let Err(error) = stream.write_all(packet) {
+            self.stream = None;
+            return afb_error!("mqtt-bridge-send", "fail to send packet error:{}", error);
+        }
+        Ok(())
+    }
+
+    pub fn publish(&mut self, event: &str, payload: &[u8]) -> Result<(), AfbError> {
+        let topic = self.get_topic(event);
+        let retain = self.config.retain && !MQTT_NOT_RETAINED.contains(&event);
+        let packet = mqtt_publish_packet(&topic, payload, retain);
+        self.send(&packet)
+    }
+
+    pub fn ping(&mut self) -> Result<(), AfbError> {
+        self.send(&[MQTT_PINGREQ, 0])
+    }
+
+    // read every pending packet, connection is dropped on hangup
+    pub fn read(&mut self) -> Result<Vec<MqttPacket>, AfbError> {
+        let mut packets = Vec::new();
+        let stream = match self.stream.as_mut() {
+            Some(value) => value,
+            None => return Ok(packets),
+        };
+
+        let mut buffer = [0u8; 512];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.stream = None;
+                    return afb_error!("mqtt-bridge-read", "connection closed by broker");
+                }
+                Ok(count) => self.buffer.extend_from_slice(&buffer[0..count]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    self.stream = None;
+                    return afb_error!("mqtt-bridge-read", "fail to read error:{}", error);
+                }
+            }
+        }
+
+        while let Some(packet) = mqtt_parse_packet(&mut self.buffer) {
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            uri: "localhost:1883".to_string(),
+            client_id: "engy".to_string(),
+            username: Some("user".to_string()),
+            password: None,
+            keepalive: 30,
+            retain: true,
+            prefix: "tux-evse/energy".to_string(),
+            command: String::new(),
+            topics: vec![("over-limit".to_string(), "tux-evse/alarm".to_string())],
+        }
+    }
+
+    #[test]
+    fn push_len() {
+        for (len, expected) in [
+            (0, vec![0x00]),
+            (127, vec![0x7F]),
+            (128, vec![0x80, 0x01]),
+            (16383, vec![0xFF, 0x7F]),
+            (16384, vec![0x80, 0x80, 0x01]),
+        ] {
+            let mut buffer = Vec::new();
+            mqtt_push_len(&mut buffer, len);
+            assert_eq!(buffer, expected, "len:{}", len);
+        }
+    }
+
+    #[test]
+    fn connect_packet() {
+        let packet = mqtt_connect_packet(&config());
+        let mut expected = vec![MQTT_CONNECT, 22, 0, 4];
+        expected.extend_from_slice(b"MQTT");
+        expected.extend_from_slice(&[4, 0x82, 0, 30, 0, 4]);
+        expected.extend_from_slice(b"engy");
+        expected.extend_from_slice(&[0, 4]);
+        expected.extend_from_slice(b"user");
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn publish_packet() {
+        let packet = mqtt_publish_packet("a/b", b"{}", true);
+        assert_eq!(packet, vec![MQTT_PUBLISH | 1, 7, 0, 3, b'a', b'/', b'b', b'{', b'}']);
+        let packet = mqtt_publish_packet("a/b", b"{}", false);
+        assert_eq!(packet[0], MQTT_PUBLISH);
+    }
+
+    #[test]
+    fn subscribe_packet() {
+        let packet = mqtt_subscribe_packet(1, "a/b");
+        assert_eq!(packet, vec![MQTT_SUBSCRIBE, 8, 0, 1, 0, 3, b'a', b'/', b'b', 0]);
+    }
+
+    #[test]
+    fn parse_connack() {
+        let mut buffer = vec![MQTT_CONNACK, 2, 0, 5, 0xD0];
+        assert!(matches!(mqtt_parse_packet(&mut buffer), Some(MqttPacket::ConnAck(5))));
+        // trailing bytes stay for next parse
+        assert_eq!(buffer, vec![0xD0]);
+    }
+
+    #[test]
+    fn parse_publish() {
+        let mut buffer = mqtt_publish_packet("a/b", b"{}", false);
+        match mqtt_parse_packet(&mut buffer) {
+            Some(MqttPacket::Publish(topic, payload)) => {
+                assert_eq!(topic, "a/b");
+                assert_eq!(payload, b"{}");
+            }
+            _ => panic!("expected publish"),
+        }
+        assert!(buffer.is_empty());
+
+        // qos=1 carries a packet-id before payload
+        let mut buffer = vec![MQTT_PUBLISH | 0x02, 9, 0, 3, b'a', b'/', b'b', 0, 7, b'{', b'}'];
+        match mqtt_parse_packet(&mut buffer) {
+            Some(MqttPacket::Publish(topic, payload)) => {
+                assert_eq!(topic, "a/b");
+                assert_eq!(payload, b"{}");
+            }
+            _ => panic!("expected publish"),
+        }
+    }
+
+    #[test]
+    fn parse_large_publish() {
+        let payload = vec![b'x'; 300];
+        let mut buffer = mqtt_publish_packet("a/b", &payload, false);
+        assert_eq!(&buffer[1..3], &[0xB1, 0x02]);
+        match mqtt_parse_packet(&mut buffer) {
+            Some(MqttPacket::Publish(_, value)) => assert_eq!(value, payload),
+            _ => panic!("expected publish"),
+        }
+    }
+
+    #[test]
+    fn parse_partial() {
+        let packet = mqtt_publish_packet("a/b", b"{}", false);
+        let mut buffer = Vec::new();
+        for byte in &packet[0..packet.len() - 1] {
+            buffer.push(*byte);
+            assert!(mqtt_parse_packet(&mut buffer).is_none());
+        }
+        assert_eq!(buffer.len(), packet.len() - 1);
+        buffer.push(packet[packet.len() - 1]);
+        assert!(matches!(mqtt_parse_packet(&mut buffer), Some(MqttPacket::Publish(_, _))));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        // remaining length longer than 4 bytes
+        let mut buffer = vec![MQTT_PUBLISH, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+        assert!(mqtt_parse_packet(&mut buffer).is_none());
+        assert!(buffer.is_empty());
+
+        // topic length past packet end
+        let mut buffer = vec![MQTT_PUBLISH, 3, 0, 9, b'a'];
+        assert!(matches!(mqtt_parse_packet(&mut buffer), Some(MqttPacket::Other(MQTT_PUBLISH))));
+
+        let mut buffer = vec![MQTT_PINGREQ, 0];
+        assert!(matches!(mqtt_parse_packet(&mut buffer), Some(MqttPacket::Other(MQTT_PINGREQ))));
+    }
+
+    #[test]
+    fn bridge_topics() {
+        let mut bridge = MqttBridge::new(config());
+        assert_eq!(bridge.get_topic("over-limit"), "tux-evse/alarm");
+        assert_eq!(bridge.get_topic("state"), "tux-evse/energy/state");
+        assert!(!bridge.is_connected());
+        assert!(bridge.publish("state", b"{}").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use std::time::{Duration, Instant};
+use typesv4::prelude::*;
+
+// minimal move of each state field to trigger an event, in state units (0 = any change)
+#[derive(Clone, Debug, Default)]
+pub struct StateDeltas {
+    pub session: i32,
+    pub current: i32,
+    pub tension: i32,
+    pub power: i32,
+    pub pv: i32,
+    pub injection: i32,
+}
+
+// going back to 0 (charge stopped) always matters whatever the delta
+fn moved(last: i32, value: i32, delta: i32) -> bool {
+    (value as i64 - last as i64).abs() > delta.max(0) as i64 || (delta > 0 && last != value && value == 0)
+}
+
+// change driven state event, heartbeat forces an event when nothing moved for too long.
+// sequence is incremented on every event so subscribers can detect missed ones.
+pub struct StateNotifier {
+    deltas: StateDeltas,
+    heartbeat: Duration,
+    last: Option<(EnergyState, Instant)>,
+    sequence: u64,
+}
+
+impl StateNotifier {
+    pub fn new(deltas: StateDeltas, heartbeat: Duration) -> Self {
+        StateNotifier {
+            deltas,
+            heartbeat,
+            last: None,
+            sequence: 0,
+        }
+    }
+
+    pub fn get_sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    // any cap change matters, charger has to follow it
+    fn changed(&self, last: &EnergyState, state: &EnergyState) -> bool {
+        last.imax != state.imax
+            || last.pmax != state.pmax
+            || moved(last.session, state.session, self.deltas.session)
+            || moved(last.current, state.current, self.deltas.current)
+            || moved(last.tension, state.tension, self.deltas.tension)
+            || moved(last.power, state.power, self.deltas.power)
+            || moved(last.pv, state.pv, self.deltas.pv)
+            || moved(last.injection, state.injection, self.deltas.injection)
+    }
+
+    // return the event sequence number when state should be pushed
+    pub fn check(&mut self, state: &EnergyState, now: Instant) -> Option<u64> {
+        let push = match &self.last {
+            None => true,
+            Some((last, time)) => {
+                self.changed(last, state) || (!self.heartbeat.is_zero() && now.duration_since(*time) >= self.heartbeat)
+            }
+        };
+        if !push {
+            return None;
+        }
+        self.sequence += 1;
+        self.last = Some((state.clone(), now));
+        Some(self.sequence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(power: i32) -> EnergyState {
+        EnergyState {
+            power,
+            ..EnergyState::default(32000, 22000, 245000)
+        }
+    }
+
+    #[test]
+    fn moved_delta() {
+        assert!(!moved(1000, 1400, 500));
+        assert!(!moved(1000, 500, 500));
+        assert!(moved(1000, 1600, 500));
+        assert!(moved(1000, 400, 500));
+        assert!(moved(i32::MIN, i32::MAX, 500));
+        // any change with 0 delta, negative delta is 0
+        assert!(!moved(1000, 1000, 0));
+        assert!(moved(1000, 1001, 0));
+        assert!(moved(1000, 1001, -10));
+        // back to 0 whatever the delta
+        assert!(moved(1000, 0, 5000));
+        assert!(!moved(0, 0, 5000));
+    }
+
+    #[test]
+    fn check_deltas() {
+        let deltas = StateDeltas {
+            power: 500,
+            ..StateDeltas::default()
+        };
+        let mut notifier = StateNotifier::new(deltas, Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(notifier.check(&state(1000), now), Some(1));
+        assert_eq!(notifier.check(&state(1000), now), None);
+        assert_eq!(notifier.check(&state(1400), now), None);
+        // compared to last pushed state, not to last checked one
+        assert_eq!(notifier.check(&state(1600), now), Some(2));
+        assert_eq!(notifier.check(&state(0), now), Some(3));
+        assert_eq!(notifier.get_sequence(), 3);
+        // zero heartbeat never pushes an unchanged state
+        assert_eq!(notifier.check(&state(0), now + Duration::from_secs(3600)), None);
+    }
+
+    #[test]
+    fn check_heartbeat() {
+        let mut notifier = StateNotifier::new(StateDeltas::default(), Duration::from_secs(60));
+        let now = Instant::now();
+        assert_eq!(notifier.check(&state(1000), now), Some(1));
+        assert_eq!(notifier.check(&state(1000), now + Duration::from_secs(59)), None);
+        assert_eq!(notifier.check(&state(1000), now + Duration::from_secs(60)), Some(2));
+        // heartbeat restarts from last event
+        assert_eq!(notifier.check(&state(1001), now + Duration::from_secs(90)), Some(3));
+        assert_eq!(notifier.check(&state(1001), now + Duration::from_secs(140)), None);
+        assert_eq!(notifier.check(&state(1001), now + Duration::from_secs(150)), Some(4));
+    }
+
+    #[test]
+    fn check_limits() {
+        let deltas = StateDeltas {
+            power: i32::MAX,
+            ..StateDeltas::default()
+        };
+        let mut notifier = StateNotifier::new(deltas, Duration::ZERO);
+        let now = Instant::now();
+        let mut capped = state(1000);
+        assert_eq!(notifier.check(&capped, now), Some(1));
+        capped.imax = 16000;
+        assert_eq!(notifier.check(&capped, now), Some(2));
+        capped.pmax = 3000;
+        assert_eq!(notifier.check(&capped, now), Some(3));
+        assert_eq!(notifier.check(&capped, now), None);
+    }
+}
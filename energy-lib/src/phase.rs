@@ -0,0 +1,190 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use std::time::{Duration, Instant};
+use typesv4::prelude::*;
+
+// power values are in Watt
+pub struct PhaseSwitchConfig {
+    pub min_3ph: i32,
+    pub hysteresis: i32,
+    pub debounce: Duration,
+    pub dwell: Duration,
+}
+
+// 6A on 3 phases (4.1kW), below it 1 phase (1.4kW min) is used
+impl Default for PhaseSwitchConfig {
+    fn default() -> Self {
+        PhaseSwitchConfig {
+            min_3ph: 4140,
+            hysteresis: 200,
+            debounce: Duration::from_secs(60),
+            dwell: Duration::from_secs(300),
+        }
+    }
+}
+
+// switch vehicle between 1 and 3 phases depending on available power.
+// A switch is only requested when the condition stays stable for 'debounce'
+// and never before 'dwell' time since previous switch (anti-oscillation).
+// Switching is disabled while installation only provides one phase.
+pub struct PhaseSwitch {
+    config: PhaseSwitchConfig,
+    installed: u32,
+    phases: u32,
+    power: i32,
+    pending: Option<(u32, Instant)>,
+    switched: Option<Instant>,
+}
+
+impl PhaseSwitch {
+    pub fn new(config: PhaseSwitchConfig, phases: u32) -> Self {
+        PhaseSwitch {
+            config,
+            installed: phases,
+            phases,
+            power: 0,
+            pending: None,
+            switched: None,
+        }
+    }
+
+    pub fn get_state(&self) -> PhaseSwitchSet {
+        PhaseSwitchSet {
+            phases: self.phases,
+            power: self.power,
+        }
+    }
+
+    // installation phase count changed by settings, forget pending request
+    pub fn set_phases(&mut self, phases: u32) {
+        self.installed = phases;
+        self.phases = phases;
+        self.pending = None;
+    }
+
+    fn target(&self, power: i32) -> u32 {
+        match self.phases {
+            1 if power >= self.config.min_3ph + self.config.hysteresis => 3,
+            3 if power < self.config.min_3ph - self.config.hysteresis => 1,
+            phases => phases,
+        }
+    }
+
+    // return a switch request when vehicle should move to a new phase count
+    pub fn check(&mut self, power: i32, now: Instant) -> Option<PhaseSwitchSet> {
+        self.power = power;
+        let target = self.target(power);
+        if self.installed < 3 || target == self.phases {
+            self.pending = None;
+            return None;
+        }
+
+        let since = match self.pending {
+            Some((phases, since)) if phases == target => since,
+            _ => {
+                self.pending = Some((target, now));
+                now
+            }
+        };
+
+        if now.duration_since(since) < self.config.debounce {
+            return None;
+        }
+
+        if let Some(switched) = self.switched {
+            if now.duration_since(switched) < self.config.dwell {
+                return None;
+            }
+        }
+
+        self.phases = target;
+        self.pending = None;
+        self.switched = Some(now);
+        Some(PhaseSwitchSet {
+            phases: target,
+            power,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    // switch once power stays for debounce, default min_3ph:4140 hysteresis:200
+    fn switch_after_debounce(switch: &mut PhaseSwitch, power: i32, start: Instant) -> Option<PhaseSwitchSet> {
+        assert!(switch.check(power, start).is_none());
+        assert!(switch.check(power, secs(start, 59)).is_none());
+        switch.check(power, secs(start, 60))
+    }
+
+    #[test]
+    fn hysteresis() {
+        let start = Instant::now();
+        let mut switch = PhaseSwitch::new(PhaseSwitchConfig::default(), 3);
+        assert!(switch_after_debounce(&mut switch, 3940, start).is_none());
+        let request = switch_after_debounce(&mut switch, 3939, secs(start, 100)).unwrap();
+        assert_eq!((request.phases, request.power), (1, 3939));
+
+        // back to 3 phases once dwell since previous switch at 160s is over
+        assert!(switch_after_debounce(&mut switch, 4339, secs(start, 500)).is_none());
+        let request = switch_after_debounce(&mut switch, 4340, secs(start, 600)).unwrap();
+        assert_eq!((request.phases, switch.get_state().phases), (3, 3));
+    }
+
+    #[test]
+    fn debounce_reset() {
+        let start = Instant::now();
+        let mut switch = PhaseSwitch::new(PhaseSwitchConfig::default(), 3);
+        assert!(switch.check(1000, start).is_none());
+        // power back above threshold before debounce forgets pending request
+        assert!(switch.check(5000, secs(start, 30)).is_none());
+        assert!(switch.check(1000, secs(start, 70)).is_none());
+        assert!(switch.check(1000, secs(start, 129)).is_none());
+        assert_eq!(switch.check(1000, secs(start, 130)).unwrap().phases, 1);
+    }
+
+    #[test]
+    fn dwell() {
+        let start = Instant::now();
+        let mut switch = PhaseSwitch::new(PhaseSwitchConfig::default(), 3);
+        assert_eq!(switch_after_debounce(&mut switch, 1000, start).unwrap().phases, 1);
+
+        // stable for debounce but still within dwell since previous switch at 60s
+        assert!(switch.check(5000, secs(start, 61)).is_none());
+        assert!(switch.check(5000, secs(start, 200)).is_none());
+        assert!(switch.check(5000, secs(start, 359)).is_none());
+        assert_eq!(switch.check(5000, secs(start, 360)).unwrap().phases, 3);
+    }
+
+    #[test]
+    fn set_phases() {
+        let start = Instant::now();
+        let mut switch = PhaseSwitch::new(PhaseSwitchConfig::default(), 3);
+        assert!(switch.check(1000, start).is_none());
+        switch.set_phases(3);
+        assert!(switch.check(1000, secs(start, 60)).is_none());
+        assert_eq!(switch.check(1000, secs(start, 120)).unwrap().phases, 1);
+
+        // single phase installation never switches
+        switch.set_phases(1);
+        assert!(switch_after_debounce(&mut switch, 10000, secs(start, 1000)).is_none());
+        assert_eq!(switch.get_state().phases, 1);
+        switch.set_phases(3);
+        assert_eq!(switch_after_debounce(&mut switch, 1000, secs(start, 2000)).unwrap().phases, 1);
+    }
+}
@@ -0,0 +1,484 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use afbv4::prelude::*;
+use typesv4::prelude::*;
+
+// nominal tension used to compare Amps and Watts profiles
+pub const PROFILE_VOLTS: i32 = 230;
+const DAY_SECS: i64 = 24 * 3600;
+
+// absent criteria matches any value
+fn match_criteria<T: PartialEq>(criteria: Option<T>, value: T) -> bool {
+    match criteria {
+        Some(expected) => expected == value,
+        None => true,
+    }
+}
+
+// OCPP smart charging profiles store, composite limit is computed in Watt
+pub struct ProfileStore {
+    profiles: Vec<ChargingProfile>,
+    session: Option<u64>,
+    phases: u32,
+}
+
+impl ProfileStore {
+    pub fn new(phases: u32) -> Self {
+        ProfileStore {
+            profiles: Vec::new(),
+            session: None,
+            phases,
+        }
+    }
+
+    pub fn set_phases(&mut self, phases: u32) {
+        self.phases = phases;
+    }
+
+    // start time of current charging session used by relative profiles
+    pub fn set_session(&mut self, start: Option<u64>) {
+        self.session = start;
+    }
+
+    pub fn get_session(&self) -> Option<u64> {
+        self.session
+    }
+
+    pub fn get_profiles(&self) -> &[ChargingProfile] {
+        &self.profiles
+    }
+
+    // return installed profile with its effective start schedule
+    pub fn install(&mut self, mut profile: ChargingProfile, now: u64) -> Result<ChargingProfile, AfbError> {
+        let periods = &profile.charging_schedule.charging_schedule_period;
+        match periods.first() {
+            Some(period) if period.start_period == 0 => {}
+            _ => {
+                return afb_error!(
+                    "energy-profile-install",
+                    "profile:{} first schedule period should start at 0",
+                    profile.charging_profile_id
+                )
+            }
+        }
+        if periods.windows(2).any(|pair| pair[0].start_period >= pair[1].start_period) {
+            return afb_error!(
+                "energy-profile-install",
+                "profile:{} schedule periods not in ascending order",
+                profile.charging_profile_id
+            );
+        }
+
+        match profile.charging_profile_kind {
+            ChargingProfileKind::Recurring => {
+                if profile.recurrency_kind.is_none() || profile.charging_schedule.start_schedule.is_none() {
+                    return afb_error!(
+                        "energy-profile-install",
+                        "profile:{} recurring requires recurrencyKind and startSchedule",
+                        profile.charging_profile_id
+                    );
+                }
+            }
+            ChargingProfileKind::Absolute => {
+                if profile.charging_schedule.start_schedule.is_none() {
+                    profile.charging_schedule.start_schedule = Some(now);
+                }
+            }
+            ChargingProfileKind::Relative => {}
+        }
+
+        // a new profile replaces the one with same id or same purpose/stack level
+        self.profiles.retain(|value| {
+            value.charging_profile_id != profile.charging_profile_id
+                && !(value.charging_profile_purpose == profile.charging_profile_purpose
+                    && value.stack_level == profile.stack_level)
+        });
+        self.profiles.push(profile.clone());
+        Ok(profile)
+    }
+
+    pub fn clear(&mut self, request: &ClearProfileRequest) -> usize {
+        let count = self.profiles.len();
+        self.profiles.retain(|profile| {
+            !(match_criteria(request.id, profile.charging_profile_id)
+                && match_criteria(request.charging_profile_purpose, profile.charging_profile_purpose)
+                && match_criteria(request.stack_level, profile.stack_level))
+        });
+        count - self.profiles.len()
+    }
+
+    fn get_start(&self, profile: &ChargingProfile, now: u64) -> Option<u64> {
+        let schedule = &profile.charging_schedule;
+        match profile.charging_profile_kind {
+            ChargingProfileKind::Absolute => schedule.start_schedule,
+            ChargingProfileKind::Relative => self.session,
+            ChargingProfileKind::Recurring => {
+                let period = match profile.recurrency_kind? {
+                    RecurrencyKind::Daily => DAY_SECS,
+                    RecurrencyKind::Weekly => 7 * DAY_SECS,
+                };
+                let start = schedule.start_schedule? as i64;
+                let occurrence = start + (now as i64 - start).div_euclid(period) * period;
+                Some(occurrence.max(0) as u64)
+            }
+        }
+    }
+
+    fn to_watts(&self, unit: ChargingRateUnit, period: &ChargingSchedulePeriod) -> i32 {
+        match unit {
+            ChargingRateUnit::W => period.limit.round() as i32,
+            ChargingRateUnit::A => {
+                let phases = period.number_phases.unwrap_or(self.phases) as f64;
+                (period.limit * PROFILE_VOLTS as f64 * phases).round() as i32
+            }
+        }
+    }
+
+    // profile limit in Watt at 'now' or None when not active
+    fn get_profile_limit(&self, profile: &ChargingProfile, now: u64) -> Option<i32> {
+        if profile.valid_from.is_some_and(|from| now < from)
+            || profile.valid_to.is_some_and(|to| now >= to)
+        {
+            return None;
+        }
+
+        let start = self.get_start(profile, now)?;
+        if now < start {
+            return None;
+        }
+        let elapsed = now - start;
+        let schedule = &profile.charging_schedule;
+        if schedule.duration.is_some_and(|duration| elapsed >= duration as u64) {
+            return None;
+        }
+
+        schedule
+            .charging_schedule_period
+            .iter()
+            .rev()
+            .find(|period| period.start_period as u64 <= elapsed)
+            .map(|period| self.to_watts(schedule.charging_rate_unit, period))
+    }
+
+    // highest stack level active profile wins within a purpose
+    fn get_purpose_limit(&self, purpose: ChargingProfilePurpose, now: u64) -> Option<i32> {
+        let mut profiles: Vec<&ChargingProfile> = self
+            .profiles
+            .iter()
+            .filter(|profile| profile.charging_profile_purpose == purpose)
+            .collect();
+        profiles.sort_by_key(|profile| std::cmp::Reverse(profile.stack_level));
+        profiles.iter().find_map(|profile| self.get_profile_limit(profile, now))
+    }
+
+    // composite limit in Watt: TxProfile overrides TxDefaultProfile, ChargePointMax caps both
+    pub fn get_limit(&self, now: u64) -> Option<i32> {
+        let cpmax = self.get_purpose_limit(ChargingProfilePurpose::ChargePointMaxProfile, now);
+        let tx = self
+            .get_purpose_limit(ChargingProfilePurpose::TxProfile, now)
+            .or_else(|| self.get_purpose_limit(ChargingProfilePurpose::TxDefaultProfile, now));
+
+        match (cpmax, tx) {
+            (Some(cpmax), Some(tx)) => Some(cpmax.min(tx)),
+            (cpmax, tx) => cpmax.or(tx),
+        }
+    }
+
+    // every instant within [from,to[ where one profile may change its limit
+    fn get_boundaries(&self, from: u64, to: u64) -> Vec<u64> {
+        let mut boundaries = vec![from];
+        for profile in &self.profiles {
+            let schedule = &profile.charging_schedule;
+            boundaries.extend(profile.valid_from);
+            boundaries.extend(profile.valid_to);
+
+            let mut starts = Vec::new();
+            if let Some(mut start) = self.get_start(profile, from) {
+                starts.push(start);
+                let recurrency = match profile.charging_profile_kind {
+                    ChargingProfileKind::Recurring => profile.recurrency_kind,
+                    _ => None,
+                };
+                if let Some(kind) = recurrency {
+                    let period = match kind {
+                        RecurrencyKind::Daily => DAY_SECS as u64,
+                        RecurrencyKind::Weekly => 7 * DAY_SECS as u64,
+                    };
+                    while start + period < to {
+                        start += period;
+                        starts.push(start);
+                    }
+                }
+            }
+
+            for start in starts {
+                boundaries.extend(
+                    schedule
+                        .charging_schedule_period
+                        .iter()
+                        .map(|period| start + period.start_period as u64),
+                );
+                boundaries.extend(schedule.duration.map(|duration| start + duration as u64));
+            }
+        }
+
+        boundaries.retain(|time| *time >= from && *time < to);
+        boundaries.sort_unstable();
+        boundaries.dedup();
+        boundaries
+    }
+
+    // composite schedule from now for request duration, 'pmax' is used when no profile applies
+    pub fn get_composite(&self, request: &CompositeRequest, now: u64, pmax: i32) -> CompositeSchedule {
+        let mut periods: Vec<ChargingSchedulePeriod> = Vec::new();
+        for time in self.get_boundaries(now, now + request.duration as u64) {
+            let watts = match self.get_limit(time) {
+                Some(value) => value.min(pmax),
+                None => pmax,
+            };
+            let limit = match request.charging_rate_unit {
+                ChargingRateUnit::W => watts as f64,
+                ChargingRateUnit::A => {
+                    let amps = watts as f64 / (PROFILE_VOLTS as f64 * self.phases as f64);
+                    (amps * 10.0).round() / 10.0
+                }
+            };
+
+            if periods.last().is_some_and(|period| period.limit == limit) {
+                continue;
+            }
+            periods.push(ChargingSchedulePeriod {
+                start_period: (time - now) as u32,
+                limit,
+                number_phases: None,
+            });
+        }
+
+        CompositeSchedule {
+            start_schedule: now,
+            duration: request.duration,
+            charging_rate_unit: request.charging_rate_unit,
+            charging_schedule_period: periods,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-03-01T06:00:00Z
+    const NOW: u64 = 1709272800;
+    const DAY: u64 = DAY_SECS as u64;
+
+    fn profile(id: i32, purpose: ChargingProfilePurpose, stack_level: u32, periods: &[(u32, f64)]) -> ChargingProfile {
+        ChargingProfile {
+            charging_profile_id: id,
+            stack_level,
+            charging_profile_purpose: purpose,
+            charging_schedule: ChargingSchedule {
+                start_schedule: Some(NOW),
+                charging_schedule_period: periods
+                    .iter()
+                    .map(|(start_period, limit)| ChargingSchedulePeriod {
+                        start_period: *start_period,
+                        limit: *limit,
+                        number_phases: None,
+                    })
+                    .collect(),
+                ..ChargingSchedule::default()
+            },
+            ..ChargingProfile::default()
+        }
+    }
+
+    fn new_store(profiles: Vec<ChargingProfile>) -> ProfileStore {
+        let mut store = ProfileStore::new(3);
+        for profile in profiles {
+            store.install(profile, NOW).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn stack_level() {
+        let mut top = profile(2, ChargingProfilePurpose::TxDefaultProfile, 1, &[(0, 2000.0)]);
+        top.charging_schedule.duration = Some(600);
+        let store = new_store(vec![
+            profile(1, ChargingProfilePurpose::TxDefaultProfile, 0, &[(0, 3000.0)]),
+            top,
+        ]);
+        assert_eq!(store.get_limit(NOW), Some(2000));
+        // lower stack level applies once higher one expired
+        assert_eq!(store.get_limit(NOW + 600), Some(3000));
+        assert_eq!(store.get_limit(NOW - 1), None);
+    }
+
+    #[test]
+    fn purpose() {
+        let mut store = new_store(vec![profile(1, ChargingProfilePurpose::TxDefaultProfile, 0, &[(0, 3000.0)])]);
+        assert_eq!(store.get_limit(NOW), Some(3000));
+
+        // TxProfile overrides TxDefaultProfile even when higher
+        store.install(profile(2, ChargingProfilePurpose::TxProfile, 0, &[(0, 5000.0)]), NOW).unwrap();
+        assert_eq!(store.get_limit(NOW), Some(5000));
+
+        // ChargePointMaxProfile caps whatever applies
+        store.install(profile(3, ChargingProfilePurpose::ChargePointMaxProfile, 0, &[(0, 4000.0)]), NOW).unwrap();
+        assert_eq!(store.get_limit(NOW), Some(4000));
+        store.install(profile(2, ChargingProfilePurpose::TxProfile, 0, &[(0, 2000.0)]), NOW).unwrap();
+        assert_eq!(store.get_limit(NOW), Some(2000));
+
+        let request = ClearProfileRequest {
+            charging_profile_purpose: Some(ChargingProfilePurpose::TxProfile),
+            ..ClearProfileRequest::default()
+        };
+        assert_eq!(store.clear(&request), 1);
+        assert_eq!(store.get_limit(NOW), Some(3000));
+        assert_eq!(store.clear(&ClearProfileRequest::default()), 2);
+        assert_eq!(store.get_limit(NOW), None);
+    }
+
+    #[test]
+    fn validity() {
+        let mut value = profile(1, ChargingProfilePurpose::TxDefaultProfile, 0, &[(0, 3000.0)]);
+        value.valid_from = Some(NOW + 100);
+        value.valid_to = Some(NOW + 200);
+        let store = new_store(vec![value]);
+        assert_eq!(store.get_limit(NOW + 99), None);
+        assert_eq!(store.get_limit(NOW + 100), Some(3000));
+        assert_eq!(store.get_limit(NOW + 199), Some(3000));
+        assert_eq!(store.get_limit(NOW + 200), None);
+    }
+
+    #[test]
+    fn recurring() {
+        let mut daily = profile(1, ChargingProfilePurpose::TxDefaultProfile, 0, &[(0, 1000.0), (3600, 2000.0)]);
+        daily.charging_profile_kind = ChargingProfileKind::Recurring;
+        daily.recurrency_kind = Some(RecurrencyKind::Daily);
+        daily.charging_schedule.start_schedule = Some(NOW - 10 * DAY);
+        daily.charging_schedule.duration = Some(7200);
+        let store = new_store(vec![daily]);
+        assert_eq!(store.get_limit(NOW + 1800), Some(1000));
+        assert_eq!(store.get_limit(NOW + 3700), Some(2000));
+        assert_eq!(store.get_limit(NOW + 7200), None);
+        assert_eq!(store.get_limit(NOW + 2 * DAY + 3600), Some(2000));
+        // before today occurrence, previous day one is over
+        assert_eq!(store.get_limit(NOW - 100), None);
+
+        let mut weekly = profile(1, ChargingProfilePurpose::TxDefaultProfile, 0, &[(0, 1000.0)]);
+        weekly.charging_profile_kind = ChargingProfileKind::Recurring;
+        weekly.recurrency_kind = Some(RecurrencyKind::Weekly);
+        weekly.charging_schedule.duration = Some(3600);
+        let store = new_store(vec![weekly]);
+        assert_eq!(store.get_limit(NOW + 7 * DAY + 10), Some(1000));
+        assert_eq!(store.get_limit(NOW + DAY + 10), None);
+        assert_eq!(store.get_limit(NOW - 7 * DAY), Some(1000));
+    }
+
+    #[test]
+    fn relative() {
+        let mut value = profile(1, ChargingProfilePurpose::TxProfile, 0, &[(0, 1000.0), (600, 2000.0)]);
+        value.charging_profile_kind = ChargingProfileKind::Relative;
+        value.charging_schedule.start_schedule = None;
+        let mut store = new_store(vec![value]);
+        assert_eq!(store.get_profiles()[0].charging_schedule.start_schedule, None);
+        assert_eq!(store.get_limit(NOW), None);
+
+        store.set_session(Some(NOW + 100));
+        assert_eq!(store.get_limit(NOW + 100), Some(1000));
+        assert_eq!(store.get_limit(NOW + 700), Some(2000));
+        store.set_session(None);
+        assert_eq!(store.get_limit(NOW + 700), None);
+    }
+
+    #[test]
+    fn amps() {
+        let mut value = profile(1, ChargingProfilePurpose::TxDefaultProfile, 0, &[(0, 16.0), (600, 16.0)]);
+        value.charging_schedule.charging_rate_unit = ChargingRateUnit::A;
+        value.charging_schedule.charging_schedule_period[1].number_phases = Some(1);
+        let mut store = new_store(vec![value]);
+        assert_eq!(store.get_limit(NOW), Some(11040));
+        assert_eq!(store.get_limit(NOW + 600), Some(3680));
+        store.set_phases(1);
+        assert_eq!(store.get_limit(NOW), Some(3680));
+    }
+
+    #[test]
+    fn install() {
+        let mut store = ProfileStore::new(3);
+        let mut value = profile(1, ChargingProfilePurpose::TxDefaultProfile, 0, &[(0, 1000.0)]);
+        value.charging_schedule.start_schedule = None;
+        let installed = store.install(value, NOW).unwrap();
+        assert_eq!(installed.charging_schedule.start_schedule, Some(NOW));
+
+        // same id replaces, same purpose and stack level replaces, other stack level is kept
+        store.install(profile(1, ChargingProfilePurpose::TxProfile, 0, &[(0, 2000.0)]), NOW).unwrap();
+        assert_eq!(store.get_profiles().len(), 1);
+        store.install(profile(2, ChargingProfilePurpose::TxProfile, 0, &[(0, 3000.0)]), NOW).unwrap();
+        assert_eq!(store.get_profiles().len(), 1);
+        assert_eq!(store.get_profiles()[0].charging_profile_id, 2);
+        store.install(profile(3, ChargingProfilePurpose::TxProfile, 1, &[(0, 4000.0)]), NOW).unwrap();
+        assert_eq!(store.get_profiles().len(), 2);
+
+        let mut recurring = profile(4, ChargingProfilePurpose::TxDefaultProfile, 0, &[(0, 1000.0)]);
+        recurring.charging_profile_kind = ChargingProfileKind::Recurring;
+        for invalid in [
+            profile(5, ChargingProfilePurpose::TxDefaultProfile, 0, &[]),
+            profile(5, ChargingProfilePurpose::TxDefaultProfile, 0, &[(60, 1000.0)]),
+            profile(5, ChargingProfilePurpose::TxDefaultProfile, 0, &[(0, 1000.0), (600, 2000.0), (600, 3000.0)]),
+            recurring,
+        ] {
+            assert!(store.install(invalid, NOW).is_err());
+        }
+        assert_eq!(store.get_profiles().len(), 2);
+    }
+
+    #[test]
+    fn composite() {
+        let mut value = profile(
+            1,
+            ChargingProfilePurpose::TxDefaultProfile,
+            0,
+            &[(0, 3000.0), (600, 3000.0), (1200, 5000.0)],
+        );
+        value.charging_schedule.duration = Some(1800);
+        let store = new_store(vec![value]);
+
+        // equal limits are merged, profile is capped by pmax and pmax applies once it ends
+        let request = CompositeRequest {
+            duration: 3600,
+            charging_rate_unit: ChargingRateUnit::W,
+        };
+        let composite = store.get_composite(&request, NOW, 4000);
+        let periods: Vec<(u32, f64)> = composite
+            .charging_schedule_period
+            .iter()
+            .map(|period| (period.start_period, period.limit))
+            .collect();
+        assert_eq!(periods, [(0, 3000.0), (1200, 4000.0)]);
+        assert_eq!((composite.start_schedule, composite.duration), (NOW, 3600));
+
+        let request = CompositeRequest {
+            duration: 3600,
+            charging_rate_unit: ChargingRateUnit::A,
+        };
+        let composite = store.get_composite(&request, NOW + 300, 22000);
+        let periods: Vec<(u32, f64)> = composite
+            .charging_schedule_period
+            .iter()
+            .map(|period| (period.start_period, period.limit))
+            .collect();
+        assert_eq!(periods, [(0, 4.3), (900, 7.2), (1500, 31.9)]);
+    }
+}
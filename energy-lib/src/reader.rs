@@ -0,0 +1,84 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+// answers of concurrent per label reads, a failed label answers None.
+// values are taken once, when every label answered or on timeout, later answers are dropped
+pub struct MeterRead {
+    values: Vec<Option<f64>>,
+    answered: Vec<bool>,
+    done: bool,
+}
+
+impl MeterRead {
+    pub fn new(count: usize) -> Self {
+        MeterRead {
+            values: vec![None; count],
+            answered: vec![false; count],
+            done: false,
+        }
+    }
+
+    // return true on last expected answer, a label answering twice keeps its first answer
+    pub fn set(&mut self, idx: usize, value: Option<f64>) -> bool {
+        if self.done || idx >= self.values.len() || self.answered[idx] {
+            return false;
+        }
+        self.values[idx] = value;
+        self.answered[idx] = true;
+        self.answered.iter().all(|answered| *answered)
+    }
+
+    pub fn take(&mut self) -> Option<Vec<Option<f64>>> {
+        if self.done {
+            return None;
+        }
+        self.done = true;
+        Some(std::mem::take(&mut self.values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_label() {
+        let mut read = MeterRead::new(3);
+        assert!(!read.set(2, Some(3.0)));
+        assert!(!read.set(0, None));
+        assert!(read.set(1, Some(2.0)));
+        assert_eq!(read.take(), Some(vec![None, Some(2.0), Some(3.0)]));
+        // taken once, late answers are dropped
+        assert_eq!(read.take(), None);
+        assert!(!read.set(0, Some(1.0)));
+    }
+
+    #[test]
+    fn duplicate_answer() {
+        let mut read = MeterRead::new(2);
+        assert!(!read.set(0, Some(1.0)));
+        assert!(!read.set(0, Some(9.0)));
+        assert!(!read.set(0, None));
+        assert!(read.set(1, Some(2.0)));
+        assert_eq!(read.take(), Some(vec![Some(1.0), Some(2.0)]));
+    }
+
+    #[test]
+    fn timeout() {
+        let mut read = MeterRead::new(4);
+        assert!(!read.set(1, Some(1.0)));
+        assert!(!read.set(4, Some(4.0)));
+        assert_eq!(read.take(), Some(vec![None, Some(1.0), None, None]));
+        assert!(!read.set(0, Some(0.0)));
+        assert_eq!(read.take(), None);
+    }
+}
@@ -0,0 +1,218 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use typesv4::prelude::*;
+
+const DAY_SECS: u64 = 24 * 3600;
+// both ISO-15118-2 and -20 accept at most 1024 schedule entries
+const SCHEDULE_ENTRIES: usize = 1024;
+
+// daily tariff window, start in seconds after midnight (UTC), pmax in W
+#[derive(Clone, Debug)]
+pub struct TariffWindow {
+    pub start: u32,
+    pub duration: u32,
+    pub pmax: i32,
+}
+
+impl TariffWindow {
+    fn is_active(&self, time: u64) -> bool {
+        let elapsed = (time % DAY_SECS + DAY_SECS - self.start as u64) % DAY_SECS;
+        elapsed < self.duration as u64
+    }
+}
+
+// scale watts to fit ISO i16 value with a decimal exponent
+fn get_exponent(watts: i32) -> (i8, i16) {
+    let mut exponent = 0;
+    let mut value = watts.max(0) as i64;
+    while value > i16::MAX as i64 {
+        value = (value + 5) / 10;
+        exponent += 1;
+    }
+    (exponent, value as i16)
+}
+
+fn get_composite_limit(composite: &CompositeSchedule, offset: u32) -> i32 {
+    composite
+        .charging_schedule_period
+        .iter()
+        .rev()
+        .find(|period| period.start_period <= offset)
+        .map_or(i32::MAX, |period| period.limit.round() as i32)
+}
+
+// merge composite profile (W) with tariff windows into ISO-15118 PMax schedules
+pub fn get_pmax_schedule(composite: &CompositeSchedule, tariffs: &[TariffWindow]) -> PMaxScheduleSet {
+    let now = composite.start_schedule;
+    let end = now + composite.duration as u64;
+
+    let mut boundaries: Vec<u64> = composite
+        .charging_schedule_period
+        .iter()
+        .map(|period| now + period.start_period as u64)
+        .collect();
+    let mut day = now - now % DAY_SECS;
+    while day < end {
+        for tariff in tariffs {
+            boundaries.push(day + tariff.start as u64);
+            boundaries.push(day + tariff.start as u64 + tariff.duration as u64);
+        }
+        day += DAY_SECS;
+    }
+    boundaries.push(now);
+    boundaries.retain(|time| *time >= now && *time < end);
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    // (start offset, watts) with consecutive identical values merged
+    let mut points: Vec<(u32, i32)> = Vec::new();
+    for time in boundaries {
+        let offset = (time - now) as u32;
+        let watts = tariffs
+            .iter()
+            .filter(|tariff| tariff.is_active(time))
+            .map(|tariff| tariff.pmax)
+            .fold(get_composite_limit(composite, offset), i32::min);
+
+        if points.last().is_some_and(|(_, value)| *value == watts) {
+            continue;
+        }
+        points.push((offset, watts));
+    }
+    points.truncate(SCHEDULE_ENTRIES);
+
+    let mut iso2 = SaScheduleTuple {
+        sa_schedule_tuple_id: 1,
+        pmax_schedule: Vec::new(),
+    };
+    let mut iso20 = PowerSchedule {
+        time_anchor: now,
+        power_schedule_entries: Vec::new(),
+    };
+
+    for (idx, (start, watts)) in points.iter().enumerate() {
+        let stop = points.get(idx + 1).map_or(composite.duration, |(next, _)| *next);
+        let (exponent, value) = get_exponent(*watts);
+
+        // ISO-2 only requires duration on last entry
+        iso2.pmax_schedule.push(PMaxScheduleEntry {
+            start: *start,
+            duration: if idx + 1 == points.len() { Some(stop - start) } else { None },
+            pmax: PhysicalValue {
+                multiplier: exponent,
+                unit: "W".to_string(),
+                value,
+            },
+        });
+        iso20.power_schedule_entries.push(PowerScheduleEntry {
+            duration: stop - start,
+            power: RationalNumber { exponent, value },
+        });
+    }
+
+    PMaxScheduleSet { iso2, iso20 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-03-01T06:00:00Z
+    const NOW: u64 = 1709272800;
+
+    fn composite(duration: u32, periods: &[(u32, f64)]) -> CompositeSchedule {
+        CompositeSchedule {
+            start_schedule: NOW,
+            duration,
+            charging_rate_unit: ChargingRateUnit::W,
+            charging_schedule_period: periods
+                .iter()
+                .map(|(start_period, limit)| ChargingSchedulePeriod {
+                    start_period: *start_period,
+                    limit: *limit,
+                    number_phases: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn iso20_sum(schedule: &PMaxScheduleSet) -> u32 {
+        schedule.iso20.power_schedule_entries.iter().map(|entry| entry.duration).sum()
+    }
+
+    #[test]
+    fn exponent() {
+        assert_eq!(get_exponent(11000), (0, 11000));
+        assert_eq!(get_exponent(32767), (0, 32767));
+        assert_eq!(get_exponent(32768), (1, 3277));
+        assert_eq!(get_exponent(400000), (2, 4000));
+        assert_eq!(get_exponent(i32::MAX), (5, 21475));
+        assert_eq!(get_exponent(-100), (0, 0));
+    }
+
+    #[test]
+    fn tariff_midnight() {
+        // off-peak 22:00 -> 06:00 is one window, not split at midnight
+        let tariffs = [TariffWindow {
+            start: 22 * 3600,
+            duration: 8 * 3600,
+            pmax: 3000,
+        }];
+        let schedule = get_pmax_schedule(&composite(2 * 86400, &[(0, 11000.0)]), &tariffs);
+
+        let iso2: Vec<(u32, Option<u32>, i16)> = schedule
+            .iso2
+            .pmax_schedule
+            .iter()
+            .map(|entry| (entry.start, entry.duration, entry.pmax.value))
+            .collect();
+        assert_eq!(
+            iso2,
+            [(0, None, 11000), (57600, None, 3000), (86400, None, 11000), (144000, Some(28800), 3000)]
+        );
+
+        let iso20: Vec<(u32, i16)> = schedule
+            .iso20
+            .power_schedule_entries
+            .iter()
+            .map(|entry| (entry.duration, entry.power.value))
+            .collect();
+        assert_eq!(iso20, [(57600, 11000), (28800, 3000), (57600, 11000), (28800, 3000)]);
+        assert_eq!(schedule.iso20.time_anchor, NOW);
+        assert_eq!(iso20_sum(&schedule), 2 * 86400);
+    }
+
+    #[test]
+    fn composite_scale() {
+        let schedule = get_pmax_schedule(&composite(3600, &[(0, 50000.0), (600, 50000.0), (1800, 7400.0)]), &[]);
+        let entries = &schedule.iso2.pmax_schedule;
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].pmax.multiplier, entries[0].pmax.value), (1, 5000));
+        assert_eq!((entries[1].start, entries[1].duration), (1800, Some(1800)));
+        assert_eq!(schedule.iso20.power_schedule_entries[0].power.exponent, 1);
+        assert_eq!(iso20_sum(&schedule), 3600);
+    }
+
+    #[test]
+    fn truncate() {
+        let periods: Vec<(u32, f64)> = (0..2000).map(|idx| (idx * 10, (1000 + 1000 * (idx % 2)) as f64)).collect();
+        let schedule = get_pmax_schedule(&composite(20000, &periods), &[]);
+        let entries = &schedule.iso2.pmax_schedule;
+        assert_eq!(entries.len(), SCHEDULE_ENTRIES);
+        assert_eq!(schedule.iso20.power_schedule_entries.len(), SCHEDULE_ENTRIES);
+        assert!(entries[..SCHEDULE_ENTRIES - 1].iter().all(|entry| entry.duration.is_none()));
+        // last entry covers the remaining requested duration
+        assert_eq!(entries[SCHEDULE_ENTRIES - 1].duration, Some(20000 - 10230));
+        assert_eq!(iso20_sum(&schedule), 20000);
+    }
+}
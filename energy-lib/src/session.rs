@@ -0,0 +1,124 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use afbv4::prelude::*;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use typesv4::prelude::*;
+
+// session offset file, written to a temporary file then renamed to survive power loss
+pub struct SessionStore {
+    path: String,
+}
+
+impl SessionStore {
+    pub fn new(path: &str) -> Self {
+        SessionStore {
+            path: path.to_string(),
+        }
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    // missing file is not an error, there is simply no session to restore
+    pub fn load(&self) -> Result<Option<SessionSet>, AfbError> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(value) => value,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => {
+                return afb_error!("energy-session-load", "fail to read path:{} error:{}", self.path, error)
+            }
+        };
+        match serde_json::from_str::<SessionSet>(&text) {
+            Ok(value) => Ok(Some(value)),
+            Err(error) => afb_error!("energy-session-load", "invalid session path:{} error:{}", self.path, error),
+        }
+    }
+
+    pub fn save(&self, session: &SessionSet) -> Result<(), AfbError> {
+        let text = match serde_json::to_string(session) {
+            Ok(value) => value,
+            Err(error) => return afb_error!("energy-session-save", "fail to serialize error:{}", error),
+        };
+
+        // packaged default lives in /var/lib, directory may not exist yet
+        let dir = Path::new(&self.path).parent().filter(|dir| !dir.as_os_str().is_empty());
+        if let Some(dir) = dir {
+            if let Err(error) = fs::create_dir_all(dir) {
+                return afb_error!("energy-session-save", "fail to create dir:{} error:{}", dir.display(), error);
+            }
+        }
+
+        let tmp_path = format!("{}.tmp", self.path);
+        let status = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(text.as_bytes()).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path));
+        if let Err(error) = status {
+            let _ = fs::remove_file(&tmp_path);
+            return afb_error!("energy-session-save", "fail to write path:{} error:{}", self.path, error);
+        }
+
+        // make rename durable
+        if let Ok(dir) = File::open(dir.unwrap_or(Path::new("."))) {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("energy-session-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn save_missing_dir() {
+        let dir = get_dir("save");
+        let path = dir.join("lib/session.json");
+        let store = SessionStore::new(path.to_str().unwrap());
+        assert!(store.load().unwrap().is_none());
+
+        let session = SessionSet {
+            start: 1200,
+            lifetime: 5400,
+            ..SessionSet::default()
+        };
+        store.save(&session).unwrap();
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!((loaded.start, loaded.lifetime), (1200, 5400));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn load_corrupted() {
+        let dir = get_dir("corrupted");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("session.json");
+        fs::write(&path, "{\"start\":").unwrap();
+
+        let store = SessionStore::new(path.to_str().unwrap());
+        assert!(store.load().is_err());
+
+        // next save replaces the corrupted file
+        store.save(&SessionSet::default()).unwrap();
+        assert!(store.load().unwrap().is_some());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
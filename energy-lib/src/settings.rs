@@ -0,0 +1,275 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use afbv4::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use typesv4::prelude::*;
+
+// accepted values shared by binding config and runtime settings
+pub const IMAX_RANGE: RangeInclusive<i32> = 1..=100;
+pub const PMAX_RANGE: RangeInclusive<i32> = 1..=1000;
+pub const UMAX_RANGE: RangeInclusive<i32> = 100..=500;
+pub const TIC_RANGE: RangeInclusive<u32> = 100..=3600000;
+pub const VARIATION_RANGE: RangeInclusive<i32> = 0..=100;
+
+pub fn check_range<T: PartialOrd + Display>(key: &str, value: T, range: &RangeInclusive<T>) -> Result<(), AfbError> {
+    if !range.contains(&value) {
+        return afb_error!(
+            "energy-config-check",
+            "invalid config key:{} value:{} should be {}..{}",
+            key,
+            value,
+            range.start(),
+            range.end()
+        );
+    }
+    Ok(())
+}
+
+pub fn check_phase(key: &str, phase: i32) -> Result<(), AfbError> {
+    if phase != 1 && phase != 3 {
+        return afb_error!("energy-config-check", "invalid config key:{} value:{} should be 1|3", key, phase);
+    }
+    Ok(())
+}
+
+// meter api/prefix and device name of binding labels, as in 'modbus/SDM630/Volt-Avg'
+pub fn check_meter_name(key: &str, value: &str) -> Result<(), AfbError> {
+    if value.is_empty() || value.contains('/') {
+        return afb_error!("energy-config-check", "invalid config key:{} value:'{}'", key, value);
+    }
+    Ok(())
+}
+
+// meter binding device, labels map binding label to device label when they differ
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeterBinding {
+    pub api: String,
+    pub prefix: String,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl MeterBinding {
+    pub fn new(api: &str, prefix: &str) -> Self {
+        MeterBinding {
+            api: api.to_string(),
+            prefix: prefix.to_string(),
+            labels: BTreeMap::new(),
+        }
+    }
+
+    pub fn check(&self) -> Result<(), AfbError> {
+        check_meter_name("meter_api", &self.api)?;
+        check_meter_name("meter_prefix", &self.prefix)?;
+        for (label, device) in &self.labels {
+            check_meter_name("meter_labels", label)?;
+            check_meter_name("meter_labels", device)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_label<'a>(&'a self, label: &'a str) -> &'a str {
+        match self.labels.get(label) {
+            Some(device) => device.as_str(),
+            None => label,
+        }
+    }
+
+    // '<prefix>/<device label>' verb within meter api
+    pub fn get_verb(&self, label: &str) -> String {
+        format!("{}/{}", self.prefix, self.get_label(label))
+    }
+
+    // index of binding label matching a device event label
+    pub fn find_label(&self, labels: &[&str], device: &str) -> Option<usize> {
+        labels.iter().position(|label| self.get_label(label) == device)
+    }
+
+    // new binding when request moves the meter, None when unchanged
+    pub fn update(&self, request: &SettingsRequest) -> Option<MeterBinding> {
+        let mut meter = self.clone();
+        if let Some(api) = &request.meter_api {
+            meter.api = api.clone();
+        }
+        if let Some(prefix) = &request.meter_prefix {
+            meter.prefix = prefix.clone();
+        }
+        if let Some(labels) = &request.meter_labels {
+            meter.labels = labels.clone();
+        }
+        if meter == *self {
+            None
+        } else {
+            Some(meter)
+        }
+    }
+}
+
+// settings that can change at runtime without restarting the binding.
+// tic in ms, variation in %, meter is shared as a snapshot with pending meter subcalls
+#[derive(Clone, Debug)]
+pub struct EnergySettings {
+    pub tic: u32,
+    pub variation: i32,
+    pub phase: i32,
+    pub meter: Arc<MeterBinding>,
+}
+
+impl EnergySettings {
+    pub fn new(phase: i32) -> Self {
+        EnergySettings {
+            tic: 30000,
+            variation: 1,
+            phase,
+            meter: Arc::new(MeterBinding::new("modbus", "SDM72D")),
+        }
+    }
+
+    // every key is checked before anything changes, a request is applied entirely or not at all
+    pub fn check(request: &SettingsRequest) -> Result<(), AfbError> {
+        if let Some(imax) = request.imax {
+            check_range("imax", imax, &IMAX_RANGE)?;
+        }
+        if let Some(pmax) = request.pmax {
+            check_range("pmax", pmax, &PMAX_RANGE)?;
+        }
+        if let Some(umax) = request.umax {
+            check_range("umax", umax, &UMAX_RANGE)?;
+        }
+        if let Some(phase) = request.phase {
+            check_phase("phase", phase)?;
+        }
+        if let Some(tic) = request.tic {
+            check_range("tic", tic, &TIC_RANGE)?;
+        }
+        if let Some(variation) = request.variation {
+            check_range("variation", variation, &VARIATION_RANGE)?;
+        }
+        if let Some(api) = &request.meter_api {
+            check_meter_name("meter_api", api)?;
+        }
+        if let Some(prefix) = &request.meter_prefix {
+            check_meter_name("meter_prefix", prefix)?;
+        }
+        if let Some(labels) = &request.meter_labels {
+            for (label, device) in labels {
+                check_meter_name("meter_labels", label)?;
+                check_meter_name("meter_labels", device)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&mut self, request: &SettingsRequest) {
+        if let Some(phase) = request.phase {
+            self.phase = phase;
+        }
+        if let Some(tic) = request.tic {
+            self.tic = tic;
+        }
+        if let Some(variation) = request.variation {
+            self.variation = variation;
+        }
+        if let Some(meter) = self.meter.update(request) {
+            self.meter = Arc::new(meter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sdm630() -> MeterBinding {
+        let mut meter = MeterBinding::new("modbus", "SDM630");
+        meter.labels.insert("Volt-Avr".to_string(), "Volt-Avg".to_string());
+        meter
+    }
+
+    #[test]
+    fn meter_labels() {
+        let meter = sdm630();
+        assert_eq!(meter.get_verb("Volt-Avr"), "SDM630/Volt-Avg");
+        assert_eq!(meter.get_verb("Volt-L1"), "SDM630/Volt-L1");
+        let labels = ["Volt-Avr", "Volt-L1", "Volt-L2", "Volt-L3"];
+        assert_eq!(meter.find_label(&labels, "Volt-Avg"), Some(0));
+        assert_eq!(meter.find_label(&labels, "Volt-L3"), Some(3));
+        assert_eq!(meter.find_label(&labels, "Volt-Avr"), None);
+        assert!(meter.check().is_ok());
+    }
+
+    #[test]
+    fn meter_update() {
+        let meter = MeterBinding::new("modbus", "SDM72D");
+        assert_eq!(meter.update(&SettingsRequest::default()), None);
+
+        let mut request = SettingsRequest {
+            meter_prefix: Some("SDM72D".to_string()),
+            ..SettingsRequest::default()
+        };
+        assert_eq!(meter.update(&request), None);
+
+        request.meter_prefix = Some("SDM630".to_string());
+        request.meter_labels = Some(sdm630().labels);
+        assert_eq!(meter.update(&request), Some(sdm630()));
+
+        request.meter_api = Some("mqtt-meter".to_string());
+        assert_eq!(meter.update(&request).unwrap().api, "mqtt-meter");
+    }
+
+    #[test]
+    fn check_request() {
+        let valid = SettingsRequest {
+            tic: Some(1000),
+            meter_api: Some("modbus".to_string()),
+            meter_labels: Some(sdm630().labels),
+            ..SettingsRequest::default()
+        };
+        assert!(EnergySettings::check(&valid).is_ok());
+
+        let invalid = [
+            SettingsRequest { tic: Some(10), ..valid.clone() },
+            SettingsRequest { phase: Some(2), ..valid.clone() },
+            SettingsRequest { meter_api: Some(String::new()), ..valid.clone() },
+            SettingsRequest { meter_prefix: Some("SDM/630".to_string()), ..valid.clone() },
+            SettingsRequest {
+                meter_labels: Some(BTreeMap::from([("Volt-Avr".to_string(), String::new())])),
+                ..valid.clone()
+            },
+        ];
+        for request in invalid {
+            assert!(EnergySettings::check(&request).is_err(), "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn apply_request() {
+        let mut settings = EnergySettings::new(3);
+        let meter = settings.meter.clone();
+        settings.apply(&SettingsRequest {
+            tic: Some(1000),
+            ..SettingsRequest::default()
+        });
+        assert_eq!(settings.tic, 1000);
+        assert!(Arc::ptr_eq(&meter, &settings.meter));
+
+        settings.apply(&SettingsRequest {
+            meter_prefix: Some("SDM630".to_string()),
+            ..SettingsRequest::default()
+        });
+        assert_eq!(settings.meter.get_verb("Watt-Total"), "SDM630/Watt-Total");
+        assert_eq!(meter.prefix, "SDM72D");
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use afbv4::prelude::*;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use typesv4::prelude::*;
+
+// data_set shared between verb/event contexts, callbacks may run on any binder thread
+pub type SharedDataSet = Arc<Mutex<MeterDataSet>>;
+
+pub fn new_shared_data_set(tag: MeterTagSet) -> SharedDataSet {
+    Arc::new(Mutex::new(MeterDataSet::default(tag)))
+}
+
+// event reference shared by every binder thread. AfbEvent wraps raw libafb handles and is
+// not Sync, but it is registered before being shared and libafb push/subscribe are thread safe
+#[derive(Clone, Copy)]
+pub struct SharedEvent(&'static AfbEvent);
+
+unsafe impl Send for SharedEvent {}
+unsafe impl Sync for SharedEvent {}
+
+impl SharedEvent {
+    pub fn new(event: &'static AfbEvent) -> Self {
+        SharedEvent(event)
+    }
+
+    pub fn get(&self) -> &'static AfbEvent {
+        self.0
+    }
+}
+
+impl Deref for SharedEvent {
+    type Target = AfbEvent;
+    fn deref(&self) -> &AfbEvent {
+        self.0
+    }
+}
+
+// a panic while holding the lock only poisons it, data is still usable by other callbacks
+pub fn lock_shared<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// same as lock_shared, also tells when another thread was holding the lock
+pub fn lock_contended<T>(mutex: &Mutex<T>) -> (MutexGuard<'_, T>, bool) {
+    match mutex.try_lock() {
+        Ok(guard) => (guard, false),
+        Err(TryLockError::Poisoned(error)) => (error.into_inner(), false),
+        Err(TryLockError::WouldBlock) => (lock_shared(mutex), true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn concurrent_lock() {
+        let data_set = new_shared_data_set(MeterTagSet::Power);
+        let workers: Vec<_> = (0..8)
+            .map(|worker| {
+                let data_set = data_set.clone();
+                thread::spawn(move || {
+                    let mut contended = 0;
+                    for _ in 0..1000 {
+                        let (mut guard, busy) = if worker % 2 == 0 {
+                            (lock_shared(&data_set), false)
+                        } else {
+                            lock_contended(&data_set)
+                        };
+                        guard.total += 1;
+                        guard.l1 = guard.total;
+                        contended += busy as i32;
+                    }
+                    contended
+                })
+            })
+            .collect();
+        for worker in workers {
+            assert!((0..=1000).contains(&worker.join().unwrap()));
+        }
+        let data_set = lock_shared(&data_set);
+        assert_eq!((data_set.total, data_set.l1), (8000, 8000));
+    }
+
+    #[test]
+    fn contended_lock() {
+        let data_set = new_shared_data_set(MeterTagSet::Power);
+        assert!(!lock_contended(&data_set).1);
+
+        let barrier = Arc::new(Barrier::new(2));
+        let guard = lock_shared(&data_set);
+        let worker = {
+            let (data_set, barrier) = (data_set.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                let (guard, contended) = lock_contended(&data_set);
+                (guard.total, contended)
+            })
+        };
+        barrier.wait();
+        // give worker time to find the lock busy
+        thread::sleep(std::time::Duration::from_millis(50));
+        drop(guard);
+        assert_eq!(worker.join().unwrap(), (0, true));
+    }
+
+    #[test]
+    fn poison_recovery() {
+        let data_set = new_shared_data_set(MeterTagSet::Power);
+        let status = {
+            let data_set = data_set.clone();
+            thread::spawn(move || {
+                let mut guard = lock_shared(&data_set);
+                guard.total = 42;
+                panic!("callback panic while holding data_set");
+            })
+            .join()
+        };
+        assert!(status.is_err());
+        assert!(data_set.is_poisoned());
+
+        // data is left as written before the panic, poison is not a contention
+        assert_eq!(lock_shared(&data_set).total, 42);
+        let (mut guard, contended) = lock_contended(&data_set);
+        assert!(!contended);
+        guard.total += 1;
+        drop(guard);
+        assert_eq!(lock_shared(&data_set).total, 43);
+    }
+}
@@ -0,0 +1,271 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use std::time::{Duration, Instant};
+use typesv4::prelude::*;
+
+// each window is split in fix number of buckets, oldest bucket is recycled when window slides
+const STATS_BUCKETS: usize = 60;
+const STATS_WINDOWS: [u64; 3] = [60, 15 * 60, 60 * 60];
+
+#[derive(Clone, Copy, Default)]
+struct StatsBucket {
+    slot: u64,
+    count: u32,
+    min: i32,
+    max: i32,
+    sum: i64,
+    last: i32,
+}
+
+struct StatsWindow {
+    slot_ms: u64,
+    buckets: [StatsBucket; STATS_BUCKETS],
+}
+
+impl StatsWindow {
+    fn new(period: Duration) -> Self {
+        StatsWindow {
+            slot_ms: period.as_millis() as u64 / STATS_BUCKETS as u64,
+            buckets: [StatsBucket::default(); STATS_BUCKETS],
+        }
+    }
+
+    fn push(&mut self, elapsed: Duration, value: i32) {
+        let slot = elapsed.as_millis() as u64 / self.slot_ms;
+        let bucket = &mut self.buckets[slot as usize % STATS_BUCKETS];
+        if bucket.slot != slot || bucket.count == 0 {
+            *bucket = StatsBucket {
+                slot,
+                count: 0,
+                min: value,
+                max: value,
+                sum: 0,
+                last: value,
+            };
+        }
+        bucket.count += 1;
+        bucket.sum += value as i64;
+        bucket.min = bucket.min.min(value);
+        bucket.max = bucket.max.max(value);
+        bucket.last = value;
+    }
+
+    fn get(&self, elapsed: Duration) -> StatsValue {
+        let slot = elapsed.as_millis() as u64 / self.slot_ms;
+        let mut stats = StatsValue::default();
+        let mut newest = 0;
+        let mut sum: i64 = 0;
+
+        for bucket in self.buckets.iter() {
+            // ignore empty buckets and buckets out of current window
+            if bucket.count == 0 || bucket.slot + (STATS_BUCKETS as u64) <= slot {
+                continue;
+            }
+            if stats.count == 0 {
+                stats.min = bucket.min;
+                stats.max = bucket.max;
+            } else {
+                stats.min = stats.min.min(bucket.min);
+                stats.max = stats.max.max(bucket.max);
+            }
+            if bucket.slot >= newest {
+                newest = bucket.slot;
+                stats.last = bucket.last;
+            }
+            stats.count += bucket.count;
+            sum += bucket.sum;
+        }
+
+        if stats.count > 0 {
+            stats.mean = (sum / stats.count as i64) as i32;
+        }
+        stats
+    }
+}
+
+// one window per phase: total, l1, l2, l3
+struct MeterStats {
+    tag: MeterTagSet,
+    windows: Vec<(u64, [StatsWindow; 4])>,
+}
+
+impl MeterStats {
+    fn new(tag: MeterTagSet) -> Self {
+        let windows = STATS_WINDOWS
+            .iter()
+            .map(|secs| {
+                let period = Duration::from_secs(*secs);
+                (
+                    *secs,
+                    [
+                        StatsWindow::new(period),
+                        StatsWindow::new(period),
+                        StatsWindow::new(period),
+                        StatsWindow::new(period),
+                    ],
+                )
+            })
+            .collect();
+        MeterStats { tag, windows }
+    }
+}
+
+// rolling min/max/mean/last for each meter data_set
+pub struct EnergyStats {
+    start: Instant,
+    meters: Vec<MeterStats>,
+}
+
+impl EnergyStats {
+    pub fn new() -> Self {
+        EnergyStats {
+            start: Instant::now(),
+            meters: Vec::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.meters.clear();
+    }
+
+    pub fn push(&mut self, data: &MeterDataSet) {
+        let elapsed = self.start.elapsed();
+        let meter = match self.meters.iter_mut().position(|meter| meter.tag == data.tag) {
+            Some(idx) => &mut self.meters[idx],
+            None => {
+                self.meters.push(MeterStats::new(data.tag.clone()));
+                self.meters.last_mut().unwrap()
+            }
+        };
+
+        let values = [data.total, data.l1, data.l2, data.l3];
+        for (_, windows) in meter.windows.iter_mut() {
+            for (window, value) in windows.iter_mut().zip(values) {
+                window.push(elapsed, value);
+            }
+        }
+    }
+
+    pub fn get(&self) -> EnergyStatsSet {
+        let elapsed = self.start.elapsed();
+        let meters = self
+            .meters
+            .iter()
+            .map(|meter| MeterStatsSet {
+                tag: meter.tag.clone(),
+                windows: meter
+                    .windows
+                    .iter()
+                    .map(|(secs, windows)| StatsWindowSet {
+                        window: *secs as u32,
+                        total: windows[0].get(elapsed),
+                        l1: windows[1].get(elapsed),
+                        l2: windows[2].get(elapsed),
+                        l3: windows[3].get(elapsed),
+                    })
+                    .collect(),
+            })
+            .collect();
+        EnergyStatsSet { meters }
+    }
+}
+
+impl Default for EnergyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(value: u64) -> Duration {
+        Duration::from_millis(value)
+    }
+
+    // (count, min, max, mean, last)
+    fn values(stats: StatsValue) -> (u32, i32, i32, i32, i32) {
+        (stats.count, stats.min, stats.max, stats.mean, stats.last)
+    }
+
+    // 60s window, one bucket per second
+    fn window() -> StatsWindow {
+        StatsWindow::new(Duration::from_secs(60))
+    }
+
+    #[test]
+    fn aggregate() {
+        let mut window = window();
+        assert_eq!(values(window.get(ms(0))), (0, 0, 0, 0, 0));
+        for (elapsed, value) in [(0, 10), (500, 20), (1500, -5), (2500, 30), (2600, 4)] {
+            window.push(ms(elapsed), value);
+        }
+        assert_eq!(values(window.get(ms(3000))), (5, -5, 30, 11, 4));
+    }
+
+    #[test]
+    fn slide() {
+        let mut window = window();
+        for second in 0..10 {
+            window.push(ms(second * 1000), second as i32 * 10);
+        }
+        assert_eq!(values(window.get(ms(59_999))), (10, 0, 90, 45, 90));
+        // seconds 0..5 left the window
+        assert_eq!(values(window.get(ms(65_000))), (4, 60, 90, 75, 90));
+        assert_eq!(values(window.get(ms(69_000))), (0, 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn recycle() {
+        let mut window = window();
+        window.push(ms(1000), 100);
+        window.push(ms(1200), 50);
+        window.push(ms(59_000), 7);
+
+        // second 60 bucket sits first in the ring, last still follows it
+        window.push(ms(60_500), 9);
+        assert_eq!(values(window.get(ms(60_500))), (4, 7, 100, 41, 9));
+
+        // second 61 reuses second 1 bucket, old values are dropped
+        window.push(ms(61_000), 5);
+        assert_eq!(values(window.get(ms(61_000))), (3, 5, 9, 7, 5));
+    }
+
+    #[test]
+    fn meters() {
+        let mut stats = EnergyStats::new();
+        for total in [1000, 3000] {
+            stats.push(&MeterDataSet {
+                total,
+                l1: total / 2,
+                ..MeterDataSet::default(MeterTagSet::Power)
+            });
+        }
+        stats.push(&MeterDataSet::default(MeterTagSet::Current));
+
+        let set = stats.get();
+        assert_eq!(set.meters.len(), 2);
+        let power = &set.meters[0];
+        assert_eq!(power.tag, MeterTagSet::Power);
+        let windows: Vec<u32> = power.windows.iter().map(|window| window.window).collect();
+        assert_eq!(windows, [60, 900, 3600]);
+        for window in &power.windows {
+            assert_eq!(values(window.total.clone()), (2, 1000, 3000, 2000, 3000));
+            assert_eq!(values(window.l1.clone()), (2, 500, 1500, 1000, 1500));
+        }
+
+        stats.reset();
+        assert!(stats.get().meters.is_empty());
+    }
+}
@@ -0,0 +1,381 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use crate::prelude::*;
+use afbv4::prelude::*;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// 'SunS' marker is searched at usual base addresses
+const SUNSPEC_BASES: [u16; 3] = [40000, 0, 50000];
+const SUNSPEC_MARKER: [u16; 2] = [0x5375, 0x6e53];
+const SUNSPEC_END: u16 = 0xFFFF;
+const SUNSPEC_MAX_MODELS: usize = 32;
+// registers needed from inverter model to reach energy counter
+const SUNSPEC_INVERTER_LEN: u16 = 32;
+
+pub struct SunSpecConfig {
+    pub uri: String,
+    pub unit: u8,
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct SunSpecModel {
+    pub id: u16,
+    pub addr: u16,
+    pub len: u16,
+}
+
+// power in W, energy in Wh, phase currents in mA
+#[derive(Clone, Debug, Default)]
+pub struct SunSpecReading {
+    pub model: u16,
+    pub power: i32,
+    pub energy: u64,
+    pub current: [i32; 3],
+}
+
+fn get_scale(sf: u16) -> Option<f64> {
+    match sf as i16 {
+        -10..=10 => Some(10f64.powi(sf as i16 as i32)),
+        _ => None,
+    }
+}
+
+fn get_uint16(value: u16, scale: Option<f64>) -> f64 {
+    match (value, scale) {
+        (0xFFFF, _) | (_, None) => 0.0,
+        (value, Some(scale)) => value as f64 * scale,
+    }
+}
+
+fn get_int16(value: u16, scale: Option<f64>) -> f64 {
+    match (value, scale) {
+        (0x8000, _) | (_, None) => 0.0,
+        (value, Some(scale)) => value as i16 as f64 * scale,
+    }
+}
+
+fn get_float32(registers: &[u16], offset: usize) -> f64 {
+    let value = f32::from_bits((registers[offset] as u32) << 16 | registers[offset + 1] as u32);
+    if value.is_nan() {
+        0.0
+    } else {
+        value as f64
+    }
+}
+
+// decode inverter model data (registers following id/len header)
+pub fn sunspec_decode(model: u16, registers: &[u16]) -> Option<SunSpecReading> {
+    if registers.len() < SUNSPEC_INVERTER_LEN as usize {
+        return None;
+    }
+
+    let (power, energy, current) = match model {
+        // integer + scale factor models
+        101 | 103 => {
+            let current_sf = get_scale(registers[4]);
+            let energy = (registers[22] as u32) << 16 | registers[23] as u32;
+            let energy = match get_scale(registers[24]) {
+                Some(scale) => energy as f64 * scale,
+                None => 0.0,
+            };
+            (
+                get_int16(registers[12], get_scale(registers[13])),
+                energy,
+                [
+                    get_uint16(registers[1], current_sf),
+                    get_uint16(registers[2], current_sf),
+                    get_uint16(registers[3], current_sf),
+                ],
+            )
+        }
+        // float models
+        111 | 113 => (
+            get_float32(registers, 20),
+            get_float32(registers, 30),
+            [
+                get_float32(registers, 2),
+                get_float32(registers, 4),
+                get_float32(registers, 6),
+            ],
+        ),
+        _ => return None,
+    };
+
+    Some(SunSpecReading {
+        model,
+        power: power.round() as i32,
+        energy: energy.max(0.0).round() as u64,
+        current: current.map(|amps| (amps * 1000.0).round() as i32),
+    })
+}
+
+// SunSpec inverter over Modbus-TCP, connection is reopened on failure
+pub struct SunSpecReader {
+    config: SunSpecConfig,
+    stream: Option<TcpStream>,
+    transaction: u16,
+    inverter: Option<SunSpecModel>,
+}
+
+impl SunSpecReader {
+    pub fn new(config: SunSpecConfig) -> Self {
+        SunSpecReader {
+            config,
+            stream: None,
+            transaction: 0,
+            inverter: None,
+        }
+    }
+
+    pub fn get_uri(&self) -> &str {
+        &self.config.uri
+    }
+
+    pub fn get_inverter(&self) -> Option<&SunSpecModel> {
+        self.inverter.as_ref()
+    }
+
+    fn connect(&mut self) -> Result<&mut TcpStream, AfbError> {
+        if self.stream.is_none() {
+            let addr = match self.config.uri.to_socket_addrs().map(|mut addrs| addrs.next()) {
+                Ok(Some(value)) => value,
+                _ => return afb_error!("energy-sunspec-connect", "invalid uri:{}", self.config.uri),
+            };
+            // an unreachable inverter should not stall the binder thread past timeout
+            let stream = match TcpStream::connect_timeout(&addr, self.config.timeout) {
+                Ok(value) => value,
+                Err(error) => {
+                    return afb_error!(
+                        "energy-sunspec-connect",
+                        "fail to connect uri:{} error:{}",
+                        self.config.uri,
+                        error
+                    )
+                }
+            };
+            let status = stream
+                .set_read_timeout(Some(self.config.timeout))
+                .and_then(|_| stream.set_write_timeout(Some(self.config.timeout)));
+            if let Err(error) = status {
+                return afb_error!("energy-sunspec-connect", "fail to set timeout error:{}", error);
+            }
+            self.stream = Some(stream);
+        }
+        match self.stream.as_mut() {
+            Some(value) => Ok(value),
+            None => afb_error!("energy-sunspec-connect", "no connection uri:{}", self.config.uri),
+        }
+    }
+
+    fn exchange(&mut self, frame: &[u8]) -> Result<ModbusFrame, std::io::Error> {
+        let stream = match self.stream.as_mut() {
+            Some(value) => value,
+            None => return Err(std::io::ErrorKind::NotConnected.into()),
+        };
+        stream.write_all(frame)?;
+
+        let mut buffer = Vec::new();
+        let mut data = [0u8; 512];
+        loop {
+            let count = stream.read(&mut data)?;
+            if count == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            buffer.extend_from_slice(&data[0..count]);
+            if let Some(frame) = modbus_parse_frame(&mut buffer) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    fn read_registers(&mut self, start: u16, count: u16) -> Result<Vec<u16>, AfbError> {
+        self.connect()?;
+        self.transaction = self.transaction.wrapping_add(1);
+        let pdu = modbus_read_request(MODBUS_READ_HOLDING, start, count);
+        let frame = modbus_build_frame(self.transaction, self.config.unit, &pdu);
+
+        let response = match self.exchange(&frame) {
+            Ok(value) if value.transaction == self.transaction => value,
+            Ok(_) => {
+                self.stream = None;
+                return afb_error!("energy-sunspec-read", "unexpected transaction id uri:{}", self.config.uri);
+            }
+            Err(error) => {
+                self.stream = None;
+                return afb_error!("energy-sunspec-read", "uri:{} error:{}", self.config.uri, error);
+            }
+        };
+
+        match modbus_read_response(MODBUS_READ_HOLDING, &response.pdu) {
+            Ok(registers) if registers.len() == count as usize => Ok(registers),
+            Ok(_) => afb_error!("energy-sunspec-read", "short response addr:{} count:{}", start, count),
+            Err(code) => afb_error!("energy-sunspec-read", "modbus exception:{} addr:{}", code, start),
+        }
+    }
+
+    // walk model chain from 'SunS' marker and select the first inverter model
+    pub fn discover(&mut self) -> Result<Vec<SunSpecModel>, AfbError> {
+        let mut base = None;
+        for addr in SUNSPEC_BASES {
+            if let Ok(marker) = self.read_registers(addr, 2) {
+                if marker == SUNSPEC_MARKER {
+                    base = Some(addr);
+                    break;
+                }
+            }
+        }
+        let mut addr = match base {
+            Some(value) => value + 2,
+            None => return afb_error!("energy-sunspec-discover", "no SunSpec marker uri:{}", self.config.uri),
+        };
+
+        let mut models = Vec::new();
+        while models.len() < SUNSPEC_MAX_MODELS {
+            let header = self.read_registers(addr, 2)?;
+            if header[0] == SUNSPEC_END {
+                break;
+            }
+            models.push(SunSpecModel {
+                id: header[0],
+                addr: addr + 2,
+                len: header[1],
+            });
+            addr = match addr.checked_add(2 + header[1]) {
+                Some(value) => value,
+                None => break,
+            };
+        }
+
+        self.inverter = models
+            .iter()
+            .find(|model| matches!(model.id, 101 | 103 | 111 | 113) && model.len >= SUNSPEC_INVERTER_LEN)
+            .cloned();
+        if self.inverter.is_none() {
+            return afb_error!("energy-sunspec-discover", "no inverter model uri:{}", self.config.uri);
+        }
+        Ok(models)
+    }
+
+    pub fn read(&mut self) -> Result<SunSpecReading, AfbError> {
+        if self.inverter.is_none() {
+            self.discover()?;
+        }
+        let inverter = match self.inverter.clone() {
+            Some(value) => value,
+            None => return afb_error!("energy-sunspec-read", "no inverter model uri:{}", self.config.uri),
+        };
+
+        let registers = self.read_registers(inverter.addr, SUNSPEC_INVERTER_LEN)?;
+        match sunspec_decode(inverter.id, &registers) {
+            Some(value) => Ok(value),
+            None => afb_error!("energy-sunspec-read", "fail to decode model:{}", inverter.id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn float(registers: &mut [u16], offset: usize, value: f32) {
+        registers[offset] = (value.to_bits() >> 16) as u16;
+        registers[offset + 1] = value.to_bits() as u16;
+    }
+
+    // three phase inverter model 103 block (after id/len), 5.5kW 8A per phase
+    fn model_103() -> Vec<u16> {
+        let mut registers = vec![
+            2410, 803, 804, 803, 0xFFFE, 4000, 4001, 3999, 2310, 2308, 2312, 0xFFFF, 5520, 0, 5001, 0xFFFE, 5540, 0, 120,
+            0, 0xFFFF, 0xFFFC,
+        ];
+        registers.extend_from_slice(&[0x0001, 0x2345, 1, 0, 0xFFFF, 0, 0, 0, 0, 0]);
+        registers
+    }
+
+    fn model_113() -> Vec<u16> {
+        let mut registers = vec![0u16; SUNSPEC_INVERTER_LEN as usize];
+        for (offset, value) in [(0, 24.1), (2, 8.03), (4, 8.04), (6, 8.03), (20, 5520.4), (30, 745650.0)] {
+            float(&mut registers, offset, value);
+        }
+        registers
+    }
+
+    #[test]
+    fn decode_103() {
+        let reading = sunspec_decode(103, &model_103()).unwrap();
+        assert_eq!(reading.model, 103);
+        assert_eq!(reading.power, 5520);
+        assert_eq!(reading.energy, 745650);
+        assert_eq!(reading.current, [8030, 8040, 8030]);
+
+        // negative power (night consumption) and scale factors
+        let mut registers = model_103();
+        registers[12] = (-15i16) as u16;
+        registers[13] = 1;
+        registers[4] = (-1i16) as u16;
+        let reading = sunspec_decode(101, &registers).unwrap();
+        assert_eq!((reading.power, reading.current[0]), (-150, 80300));
+    }
+
+    #[test]
+    fn decode_sentinel() {
+        // not implemented values read as 0, other fields are kept
+        let mut registers = model_103();
+        registers[2] = 0xFFFF;
+        registers[12] = 0x8000;
+        let reading = sunspec_decode(103, &registers).unwrap();
+        assert_eq!((reading.power, reading.energy), (0, 745650));
+        assert_eq!(reading.current, [8030, 0, 8030]);
+
+        // scale factor not implemented or out of -10..10
+        let mut registers = model_103();
+        registers[4] = 11;
+        registers[13] = 0x8000;
+        registers[24] = (-11i16) as u16;
+        let reading = sunspec_decode(103, &registers).unwrap();
+        assert_eq!((reading.power, reading.energy, reading.current), (0, 0, [0, 0, 0]));
+
+        // -10..10 edges are still accepted
+        let mut registers = model_103();
+        registers[4] = (-10i16) as u16;
+        registers[24] = 10;
+        let reading = sunspec_decode(103, &registers).unwrap();
+        assert_eq!((reading.energy, reading.current[0]), (0x12345 * 10_000_000_000, 0));
+    }
+
+    #[test]
+    fn decode_113() {
+        let reading = sunspec_decode(113, &model_113()).unwrap();
+        assert_eq!(reading.model, 113);
+        assert_eq!(reading.power, 5520);
+        assert_eq!(reading.energy, 745650);
+        assert_eq!(reading.current, [8030, 8040, 8030]);
+
+        // NaN is the float not implemented value, negative energy is clamped
+        let mut registers = model_113();
+        float(&mut registers, 20, f32::NAN);
+        float(&mut registers, 30, -1.0);
+        let reading = sunspec_decode(111, &registers).unwrap();
+        assert_eq!((reading.power, reading.energy), (0, 0));
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert!(sunspec_decode(103, &model_103()[0..31]).is_none());
+        assert!(sunspec_decode(113, &model_113()[0..31]).is_none());
+        assert!(sunspec_decode(102, &model_103()).is_none());
+    }
+}
@@ -0,0 +1,208 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use typesv4::prelude::*;
+
+// tempo day starts at 06:00 CET (05:00 UTC), red/white days only happen in winter
+const TEMPO_ROLLOVER: u64 = 5 * 3600;
+
+fn get_day(now: u64) -> u64 {
+    now.saturating_sub(TEMPO_ROLLOVER) / (24 * 3600)
+}
+
+// pmax in W allowed during peak hours per day color, None means no cap, 0 pauses charging
+#[derive(Clone, Debug, Default)]
+pub struct TempoPolicy {
+    pub blue: Option<i32>,
+    pub white: Option<i32>,
+    pub red: Option<i32>,
+    pub mobile_peak: Option<i32>,
+}
+
+fn get_color(bits: u32) -> TempoColor {
+    match bits & 0x3 {
+        1 => TempoColor::Blue,
+        2 => TempoColor::White,
+        3 => TempoColor::Red,
+        _ => TempoColor::Unknown,
+    }
+}
+
+// Tempo/EJP peak days from linky STGE status word and LTARF tariff label.
+// STGE already carries tomorrow color, PJOURF+1 profile is not needed for it.
+pub struct TempoTracker {
+    policy: TempoPolicy,
+    today: TempoColor,
+    tomorrow: TempoColor,
+    tariff: String,
+    mobile_peak: bool,
+    // tempo day when override was set
+    overridden: Option<u64>,
+}
+
+impl TempoTracker {
+    pub fn new(policy: TempoPolicy) -> Self {
+        TempoTracker {
+            policy,
+            today: TempoColor::Unknown,
+            tomorrow: TempoColor::Unknown,
+            tariff: String::new(),
+            mobile_peak: false,
+            overridden: None,
+        }
+    }
+
+    // bits 24-25 today color, 26-27 tomorrow color, 30-31 mobile peak in progress
+    pub fn update_status(&mut self, stge: u32) -> bool {
+        let today = get_color(stge >> 24);
+        let tomorrow = get_color(stge >> 26);
+        let mobile_peak = (stge >> 30) & 0x3 != 0;
+
+        let changed = today != self.today || tomorrow != self.tomorrow || mobile_peak != self.mobile_peak;
+        // override only lasts until the next day color
+        if today != self.today {
+            self.overridden = None;
+        }
+        self.today = today;
+        self.tomorrow = tomorrow;
+        self.mobile_peak = mobile_peak;
+        changed
+    }
+
+    pub fn update_tariff(&mut self, ltarf: &str) -> bool {
+        let tariff = ltarf.trim().to_uppercase();
+        if tariff == self.tariff {
+            return false;
+        }
+        self.tariff = tariff;
+        true
+    }
+
+    // same color may last several days, override also ends on day rollover
+    pub fn check_day(&mut self, now: u64) -> bool {
+        match self.overridden {
+            Some(day) if day != get_day(now) => {
+                self.overridden = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn set_override(&mut self, active: bool, now: u64) {
+        self.overridden = match active {
+            true => Some(get_day(now)),
+            false => None,
+        };
+    }
+
+    // tempo peak hours are HP from LTARF, EJP peak is signaled by STGE
+    pub fn is_peak(&self) -> bool {
+        self.tariff.starts_with("HP") || self.tariff.contains("POINTE")
+    }
+
+    pub fn get_limit(&self) -> Option<i32> {
+        if self.overridden.is_some() {
+            return None;
+        }
+
+        let color = match self.today {
+            _ if !self.is_peak() => None,
+            TempoColor::Blue => self.policy.blue,
+            TempoColor::White => self.policy.white,
+            TempoColor::Red => self.policy.red,
+            TempoColor::Unknown => None,
+        };
+        let mobile = match self.mobile_peak {
+            true => self.policy.mobile_peak,
+            false => None,
+        };
+
+        match (color, mobile) {
+            (Some(color), Some(mobile)) => Some(color.min(mobile)),
+            (color, mobile) => color.or(mobile),
+        }
+    }
+
+    pub fn get_state(&self) -> TempoSet {
+        TempoSet {
+            today: self.today,
+            tomorrow: self.tomorrow,
+            tariff: self.tariff.clone(),
+            peak: self.is_peak(),
+            mobile_peak: self.mobile_peak,
+            overridden: self.overridden.is_some(),
+            pmax: self.get_limit(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-15T12:00:00Z
+    const NOON: u64 = 1705320000;
+    const HOUR: u64 = 3600;
+
+    // today/tomorrow color in STGE bits 24-27
+    fn stge(today: u32, tomorrow: u32) -> u32 {
+        (today << 24) | (tomorrow << 26)
+    }
+
+    fn tracker() -> TempoTracker {
+        let mut tracker = TempoTracker::new(TempoPolicy {
+            red: Some(0),
+            ..TempoPolicy::default()
+        });
+        tracker.update_status(stge(3, 3));
+        tracker.update_tariff("HP  JR");
+        tracker
+    }
+
+    #[test]
+    fn red_peak_limit() {
+        let mut tracker = tracker();
+        assert_eq!(tracker.get_limit(), Some(0));
+        tracker.update_tariff("HC  JR");
+        assert_eq!(tracker.get_limit(), None);
+    }
+
+    #[test]
+    fn override_ends_on_color_change() {
+        let mut tracker = tracker();
+        tracker.set_override(true, NOON);
+        assert_eq!(tracker.get_limit(), None);
+        assert!(!tracker.update_status(stge(3, 3)));
+        assert_eq!(tracker.get_limit(), None);
+
+        tracker.update_status(stge(2, 3));
+        tracker.update_status(stge(3, 3));
+        assert_eq!(tracker.get_limit(), Some(0));
+    }
+
+    #[test]
+    fn override_ends_on_day_rollover() {
+        let mut tracker = tracker();
+        tracker.set_override(true, NOON);
+
+        // same tempo day until next morning 05:00 UTC
+        assert!(!tracker.check_day(NOON + 16 * HOUR));
+        assert_eq!(tracker.get_limit(), None);
+
+        // two red days in a row, STGE does not change
+        assert!(tracker.check_day(NOON + 17 * HOUR));
+        tracker.update_status(stge(3, 3));
+        assert_eq!(tracker.get_limit(), Some(0));
+        assert!(!tracker.get_state().overridden);
+    }
+}
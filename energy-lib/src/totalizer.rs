@@ -0,0 +1,246 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use typesv4::prelude::*;
+
+// slack in Wh added to max power when checking for implausible jumps
+const JUMP_SLACK: i64 = 100;
+
+#[derive(Clone, Debug)]
+pub enum EnergyDiscontinuity {
+    Rollover { last: i64, index: i64 },
+    Reset { last: i64, index: i64 },
+    Jump { last: i64, index: i64, elapsed: u64 },
+    Replaced { previous: String, serial: String },
+}
+
+impl EnergyDiscontinuity {
+    // energy in Wh reported by meter but not added to lifetime
+    pub fn get_discarded(&self) -> i64 {
+        match self {
+            EnergyDiscontinuity::Jump { last, index, .. } => index - last,
+            _ => 0,
+        }
+    }
+}
+
+// keep lifetime and session energy monotonic whatever the meter index does.
+// all values in Wh, rollover 0 means meter index never wraps, max_power in W (0 disables jump check)
+pub struct EnergyTotalizer {
+    rollover: i64,
+    max_power: i64,
+    index: Option<(i64, u64)>,
+    lifetime: i64,
+    session: i64,
+    serial: Option<String>,
+}
+
+impl EnergyTotalizer {
+    pub fn new(rollover: i64, max_power: i64) -> Self {
+        EnergyTotalizer {
+            rollover,
+            max_power,
+            index: None,
+            lifetime: 0,
+            session: 0,
+            serial: None,
+        }
+    }
+
+    pub fn restore(&mut self, session: &SessionSet) {
+        self.lifetime = session.lifetime;
+        self.session = session.start;
+        self.index = Some((session.index, session.saved));
+        self.serial = session.serial.clone();
+    }
+
+    pub fn save(&self, timestamp: u64, now: u64) -> SessionSet {
+        let (index, saved) = self.index.unwrap_or((0, now));
+        SessionSet {
+            start: self.session,
+            timestamp,
+            lifetime: self.lifetime,
+            index,
+            saved,
+            serial: self.serial.clone(),
+        }
+    }
+
+    pub fn get_lifetime(&self) -> i64 {
+        self.lifetime
+    }
+
+    pub fn get_session(&self) -> i64 {
+        self.lifetime - self.session
+    }
+
+    pub fn reset_session(&mut self) {
+        self.session = self.lifetime;
+    }
+
+    // a new serial number means the meter was swapped, next index becomes the new baseline
+    pub fn set_serial(&mut self, serial: &str) -> Option<EnergyDiscontinuity> {
+        let previous = self.serial.replace(serial.to_string());
+        match previous {
+            Some(previous) if previous != serial => {
+                self.index = None;
+                Some(EnergyDiscontinuity::Replaced {
+                    previous,
+                    serial: serial.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // meter index in Wh at unix time 'now'
+    pub fn update(&mut self, index: i64, now: u64) -> Option<EnergyDiscontinuity> {
+        let (last, time) = match self.index {
+            Some(value) => value,
+            None => {
+                self.index = Some((index, now));
+                return None;
+            }
+        };
+        let delta = index - last;
+
+        if delta < 0 {
+            // float32 index only has 24 bits of mantissa, ignore jitter
+            if -delta <= (index.abs() >> 21).max(1) {
+                return None;
+            }
+            self.index = Some((index, now));
+            if self.rollover > 0 && last > self.rollover * 9 / 10 && index < self.rollover / 10 {
+                self.lifetime += index + self.rollover - last;
+                return Some(EnergyDiscontinuity::Rollover { last, index });
+            }
+            return Some(EnergyDiscontinuity::Reset { last, index });
+        }
+
+        let elapsed = now.saturating_sub(time);
+        if self.max_power > 0 && delta > self.max_power * 2 * elapsed as i64 / 3600 + JUMP_SLACK {
+            self.index = Some((index, now));
+            return Some(EnergyDiscontinuity::Jump { last, index, elapsed });
+        }
+
+        self.lifetime += delta;
+        self.index = Some((index, now));
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 99999.999 kWh index wraps to 0
+    const ROLLOVER: i64 = 100_000_000;
+
+    fn totalizer() -> EnergyTotalizer {
+        let mut totalizer = EnergyTotalizer::new(ROLLOVER, 7400);
+        assert!(totalizer.update(1_000_000, 0).is_none());
+        totalizer
+    }
+
+    #[test]
+    fn monotonic() {
+        let mut totalizer = totalizer();
+        assert!(totalizer.update(1_000_500, 3600).is_none());
+        assert_eq!(totalizer.get_lifetime(), 500);
+
+        // float32 index jitter is ignored
+        assert!(totalizer.update(1_000_499, 3601).is_none());
+        assert_eq!(totalizer.get_lifetime(), 500);
+    }
+
+    #[test]
+    fn rollover() {
+        let mut totalizer = EnergyTotalizer::new(ROLLOVER, 0);
+        totalizer.update(ROLLOVER - 200, 0);
+        match totalizer.update(300, 60) {
+            Some(EnergyDiscontinuity::Rollover { last, index }) => assert_eq!((last, index), (ROLLOVER - 200, 300)),
+            other => panic!("unexpected:{:?}", other),
+        }
+        assert_eq!(totalizer.get_lifetime(), 500);
+        assert!(totalizer.update(400, 120).is_none());
+        assert_eq!(totalizer.get_lifetime(), 600);
+    }
+
+    #[test]
+    fn reset() {
+        let mut totalizer = totalizer();
+        totalizer.update(1_000_200, 600);
+        let discontinuity = totalizer.update(50, 660);
+        match &discontinuity {
+            Some(EnergyDiscontinuity::Reset { last, index }) => assert_eq!((*last, *index), (1_000_200, 50)),
+            other => panic!("unexpected:{:?}", other),
+        }
+        assert_eq!(discontinuity.unwrap().get_discarded(), 0);
+
+        // counting restarts from new index
+        assert!(totalizer.update(150, 720).is_none());
+        assert_eq!(totalizer.get_lifetime(), 300);
+    }
+
+    #[test]
+    fn jump() {
+        let mut totalizer = totalizer();
+        // 7.4kW during 60s is about 123Wh, x2 + slack still far below 5kWh
+        let discontinuity = totalizer.update(1_005_000, 60);
+        match &discontinuity {
+            Some(EnergyDiscontinuity::Jump { last, index, elapsed }) => {
+                assert_eq!((*last, *index, *elapsed), (1_000_000, 1_005_000, 60))
+            }
+            other => panic!("unexpected:{:?}", other),
+        }
+        assert_eq!(discontinuity.unwrap().get_discarded(), 5000);
+        assert_eq!(totalizer.get_lifetime(), 0);
+
+        // plausible delta is counted from new baseline
+        assert!(totalizer.update(1_005_100, 120).is_none());
+        assert_eq!(totalizer.get_lifetime(), 100);
+    }
+
+    #[test]
+    fn replaced() {
+        let mut totalizer = totalizer();
+        assert!(totalizer.set_serial("A1").is_none());
+        totalizer.update(1_000_400, 600);
+        assert!(totalizer.set_serial("A1").is_none());
+
+        match totalizer.set_serial("B2") {
+            Some(EnergyDiscontinuity::Replaced { previous, serial }) => {
+                assert_eq!((previous.as_str(), serial.as_str()), ("A1", "B2"))
+            }
+            other => panic!("unexpected:{:?}", other),
+        }
+        // new meter index becomes the baseline, lifetime is kept
+        assert!(totalizer.update(20, 660).is_none());
+        assert!(totalizer.update(120, 720).is_none());
+        assert_eq!(totalizer.get_lifetime(), 500);
+    }
+
+    #[test]
+    fn session_restore() {
+        let mut totalizer = totalizer();
+        totalizer.update(1_000_300, 600);
+        totalizer.reset_session();
+        totalizer.update(1_000_500, 1200);
+        assert_eq!(totalizer.get_session(), 200);
+
+        let saved = totalizer.save(600, 1200);
+        let mut restored = EnergyTotalizer::new(ROLLOVER, 7400);
+        restored.restore(&saved);
+        assert!(restored.update(1_000_600, 1800).is_none());
+        assert_eq!((restored.get_lifetime(), restored.get_session()), (600, 300));
+    }
+}
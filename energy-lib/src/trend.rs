@@ -0,0 +1,199 @@
+/*
+ * Copyright (C) 2015-2022 IoT.bzh Company
+ * Author: Fulup Ar Foll <fulup@iot.bzh>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ */
+
+use std::collections::VecDeque;
+use typesv4::prelude::*;
+
+// max samples kept per data_set (one day at one sample per second)
+pub const TREND_MAX: u32 = 86400;
+
+// bounded history of timestamped samples for each meter data_set, grows up to 'size'
+pub struct TrendBuffer {
+    size: usize,
+    meters: Vec<(MeterTagSet, VecDeque<TrendSample>)>,
+}
+
+impl TrendBuffer {
+    pub fn new(size: usize) -> Self {
+        TrendBuffer {
+            size,
+            meters: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, data: &MeterDataSet, timestamp: u64) {
+        if self.size == 0 {
+            return;
+        }
+
+        let samples = match self.meters.iter_mut().position(|(tag, _)| *tag == data.tag) {
+            Some(idx) => &mut self.meters[idx].1,
+            None => {
+                self.meters.push((data.tag.clone(), VecDeque::new()));
+                &mut self.meters.last_mut().unwrap().1
+            }
+        };
+
+        if samples.len() == self.size {
+            samples.pop_front();
+        }
+        samples.push_back(TrendSample {
+            timestamp,
+            total: data.total,
+            l1: data.l1,
+            l2: data.l2,
+            l3: data.l3,
+        });
+    }
+
+    // return samples within [start,stop] averaged on 'resolution' ms buckets (0=raw samples)
+    pub fn get(&self, request: &TrendRequest) -> TrendSet {
+        let mut response = TrendSet {
+            tag: request.tag.clone(),
+            resolution: request.resolution,
+            samples: Vec::new(),
+        };
+
+        let samples = match self.meters.iter().find(|(tag, _)| *tag == request.tag) {
+            Some((_, samples)) => samples,
+            None => return response,
+        };
+
+        let stop = if request.stop == 0 { u64::MAX } else { request.stop };
+        let mut selected = samples
+            .iter()
+            .filter(|sample| sample.timestamp >= request.start && sample.timestamp <= stop);
+
+        if request.resolution == 0 {
+            response.samples = selected.cloned().collect();
+            return response;
+        }
+
+        let mut bucket: Option<(u64, [i64; 4], i64)> = None;
+        loop {
+            let sample = selected.next();
+            let slot = sample.map(|sample| sample.timestamp - sample.timestamp % request.resolution);
+
+            // flush current bucket when moving to next slot or at end of samples
+            if let Some((timestamp, sums, count)) = bucket {
+                if slot != Some(timestamp) {
+                    response.samples.push(TrendSample {
+                        timestamp,
+                        total: (sums[0] / count) as i32,
+                        l1: (sums[1] / count) as i32,
+                        l2: (sums[2] / count) as i32,
+                        l3: (sums[3] / count) as i32,
+                    });
+                    bucket = None;
+                }
+            }
+
+            let (sample, slot) = match (sample, slot) {
+                (Some(sample), Some(slot)) => (sample, slot),
+                _ => break,
+            };
+            let (_, sums, count) = bucket.get_or_insert((slot, [0; 4], 0));
+            sums[0] += sample.total as i64;
+            sums[1] += sample.l1 as i64;
+            sums[2] += sample.l2 as i64;
+            sums[3] += sample.l3 as i64;
+            *count += 1;
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn power(total: i32) -> MeterDataSet {
+        MeterDataSet {
+            total,
+            l1: total / 10,
+            ..MeterDataSet::default(MeterTagSet::Power)
+        }
+    }
+
+    fn request(start: u64, stop: u64, resolution: u64) -> TrendRequest {
+        TrendRequest {
+            tag: MeterTagSet::Power,
+            start,
+            stop,
+            resolution,
+        }
+    }
+
+    // (timestamp, total)
+    fn samples(trend: &TrendBuffer, request: &TrendRequest) -> Vec<(u64, i32)> {
+        trend
+            .get(request)
+            .samples
+            .iter()
+            .map(|sample| (sample.timestamp, sample.total))
+            .collect()
+    }
+
+    #[test]
+    fn raw() {
+        let mut trend = TrendBuffer::new(10);
+        for (timestamp, total) in [(1000, 10), (2000, 20), (3000, 30)] {
+            trend.push(&power(total), timestamp);
+        }
+        assert_eq!(samples(&trend, &request(0, 0, 0)), [(1000, 10), (2000, 20), (3000, 30)]);
+        assert_eq!(samples(&trend, &request(2000, 0, 0)), [(2000, 20), (3000, 30)]);
+        assert_eq!(samples(&trend, &request(1000, 2000, 0)), [(1000, 10), (2000, 20)]);
+        assert!(samples(&trend, &request(3001, 0, 0)).is_empty());
+
+        let set = trend.get(&TrendRequest {
+            tag: MeterTagSet::Current,
+            ..request(0, 0, 0)
+        });
+        assert!(set.samples.is_empty());
+    }
+
+    #[test]
+    fn evict() {
+        let mut trend = TrendBuffer::new(3);
+        for idx in 0..5 {
+            trend.push(&power(idx * 10), idx as u64 * 1000);
+        }
+        assert_eq!(samples(&trend, &request(0, 0, 0)), [(2000, 20), (3000, 30), (4000, 40)]);
+
+        // one buffer per data_set, size 0 disables history
+        trend.push(&MeterDataSet::default(MeterTagSet::Current), 5000);
+        assert_eq!(samples(&trend, &request(0, 0, 0)).len(), 3);
+        let mut trend = TrendBuffer::new(0);
+        trend.push(&power(10), 1000);
+        assert!(samples(&trend, &request(0, 0, 0)).is_empty());
+    }
+
+    #[test]
+    fn resolution() {
+        let mut trend = TrendBuffer::new(100);
+        // 3 samples in [0,10s[, gap, 1 sample in [30s,40s[, 2 in [40s,50s[ flushed at end
+        for (timestamp, total) in [(0, 10), (4000, 20), (9999, 60), (30000, 100), (40000, 7), (49000, 8)] {
+            trend.push(&power(total), timestamp);
+        }
+        let set = trend.get(&request(0, 0, 10000));
+        assert_eq!(set.resolution, 10000);
+        let averaged: Vec<(u64, i32, i32)> = set
+            .samples
+            .iter()
+            .map(|sample| (sample.timestamp, sample.total, sample.l1))
+            .collect();
+        assert_eq!(averaged, [(0, 30, 3), (30000, 100, 10), (40000, 7, 0)]);
+
+        // bucket timestamp is aligned on resolution whatever the first sample
+        assert_eq!(samples(&trend, &request(4000, 45000, 10000)), [(0, 40), (30000, 100), (40000, 7)]);
+    }
+}